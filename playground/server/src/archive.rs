@@ -0,0 +1,217 @@
+use crate::{AppState, CompileResponse, Language};
+use axum::{
+    extract::{Path as UrlPath, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Keeps a copy of every run on disk so it can be looked at after the fact, e.g. by an
+// instructor reviewing what students submitted. Each run is written to its own
+// `<timestamp>-<id>.json` file; once the files add up to more than `max_bytes`, the
+// oldest are deleted.
+pub struct Archive {
+    dir: PathBuf,
+    max_bytes: u64,
+    // Bearer token required by GET /archive/:id; without one, runs can't be read back
+    admin_token: Option<String>,
+    // Held while a run is written and the archive rotated, so two writers never
+    // rotate at once and delete more than needed
+    write_lock: Mutex<()>,
+}
+
+// What ends up in a run's archive file
+#[derive(Serialize)]
+struct ArchivedRun<'a> {
+    id: &'a str,
+    // When the run finished, in milliseconds since the Unix epoch
+    timestamp_ms: u64,
+    language: Language,
+    code: &'a str,
+    args: &'a [String],
+    dependencies: &'a BTreeMap<String, String>,
+    // The response as it was sent, with the output, diagnostics and timings
+    response: &'a CompileResponse,
+}
+
+impl Archive {
+    pub fn new(dir: PathBuf, max_bytes: u64, admin_token: Option<String>) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Archive {
+            dir,
+            max_bytes,
+            admin_token,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    // Give the run an id, put it in the response and write the run to disk in the
+    // background, so archiving never holds up the response
+    pub fn record(
+        self: &Arc<Self>,
+        language: Language,
+        code: &str,
+        args: &[String],
+        dependencies: &BTreeMap<String, String>,
+        response: &mut CompileResponse,
+    ) {
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        response.archive_id = Some(id.clone());
+
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as u64)
+            .unwrap_or_default();
+        let run = ArchivedRun {
+            id: &id,
+            timestamp_ms,
+            language,
+            code,
+            args,
+            dependencies,
+            response,
+        };
+        let contents = match serde_json::to_vec_pretty(&run) {
+            Ok(contents) => contents,
+            Err(err) => {
+                println!("Failed to serialize run {} for the archive: {}", id, err);
+                return;
+            }
+        };
+
+        println!("Archiving run {}", id);
+        let path = self.dir.join(format!("{:013}-{}.json", timestamp_ms, id));
+        let archive = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let _write = archive.write_lock.lock().unwrap();
+            if let Err(err) = fs::write(&path, contents) {
+                println!("Failed to archive run {}: {}", id, err);
+                return;
+            }
+            if let Err(err) = archive.rotate() {
+                println!("Failed to rotate the archive: {}", err);
+            }
+        });
+    }
+
+    // Delete the oldest runs until the archive fits in `max_bytes` again
+    fn rotate(&self) -> io::Result<()> {
+        let mut files = Vec::new();
+        let mut total = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+            {
+                let size = entry.metadata()?.len();
+                total += size;
+                files.push((path, size));
+            }
+        }
+
+        // File names start with the zero-padded timestamp, so they sort oldest first
+        files.sort();
+        for (path, size) in files {
+            if total <= self.max_bytes {
+                break;
+            }
+            fs::remove_file(&path)?;
+            total -= size;
+        }
+        Ok(())
+    }
+
+    // The stored file for a run, if it is still in the archive
+    fn find(&self, id: &str) -> io::Result<Option<PathBuf>> {
+        let suffix = format!("-{}.json", id);
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if file_name(&path).is_some_and(|name| name.ends_with(&suffix)) {
+                return Ok(Some(path));
+            }
+        }
+        Ok(None)
+    }
+
+    fn authorized(&self, headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+        let Some(admin_token) = &self.admin_token else {
+            return Err((
+                StatusCode::FORBIDDEN,
+                "Reading the archive requires ARCHIVE_ADMIN_TOKEN to be configured".to_string(),
+            ));
+        };
+        let token = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        // Compare digests rather than the tokens themselves, so the time taken doesn't
+        // reveal how much of a guess was right
+        let matches = token.is_some_and(|token| {
+            Sha256::digest(token.as_bytes()) == Sha256::digest(admin_token.as_bytes())
+        });
+        if !matches {
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                "Missing or invalid admin token".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn file_name(path: &Path) -> Option<&str> {
+    path.file_name().and_then(|name| name.to_str())
+}
+
+/// Retrieve an archived run
+///
+/// Returns the stored JSON record for the run with the given id, as reported in the
+/// `archive_id` of its response. Requires the admin token as a bearer token.
+pub async fn archive_handler(
+    State(app_state): State<AppState>,
+    UrlPath(id): UrlPath<String>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let Some(archive) = app_state.archive.clone() else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "Archiving is not enabled on this server".to_string(),
+        ));
+    };
+    archive.authorized(&headers)?;
+
+    // Ids are simple UUIDs; anything else can't name an archived run
+    if id.len() != 32 || !id.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err((StatusCode::BAD_REQUEST, format!("Invalid run id: {}", id)));
+    }
+
+    let lookup_id = id.clone();
+    let lookup =
+        tokio::task::spawn_blocking(move || archive.find(&lookup_id)?.map(fs::read).transpose())
+            .await;
+    match lookup {
+        Ok(Ok(Some(contents))) => Ok(([(header::CONTENT_TYPE, "application/json")], contents)),
+        Ok(Ok(None)) => Err((
+            StatusCode::NOT_FOUND,
+            format!("No archived run with id {}", id),
+        )),
+        Ok(Err(err)) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to read the archive: {}", err),
+        )),
+        Err(err) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Task panic: {}", err),
+        )),
+    }
+}
@@ -0,0 +1,75 @@
+use crate::{
+    deadline::RequestDeadline,
+    extract::JobJson,
+    guardrails::{self, ClientId},
+    rejected, rust_job, typescript_job, AppState, CompileError, CompileRequest, CompileResponse,
+    JobReply, Language, OutputEncoding,
+};
+use axum::{extract::State, Json};
+use futures::{stream, StreamExt};
+use serde::Deserialize;
+use std::time::Instant;
+
+// How many jobs of one batch are started at once. They still wait for the server-wide
+// job slots, so this only keeps a single batch from queueing up all of its jobs.
+const BATCH_PARALLELISM: usize = 4;
+
+#[derive(Deserialize)]
+pub struct BatchRequest {
+    jobs: Vec<BatchJob>,
+}
+
+// A compile request plus the language it is written in
+#[derive(Deserialize)]
+struct BatchJob {
+    language: Language,
+    #[serde(flatten)]
+    request: CompileRequest,
+}
+
+/// Compile and run several snippets in one request
+///
+/// Each job is handled like a request to /rust or /typescript, in its own copy of the
+/// template, and gets its own timeout. Results come back in the order the jobs were
+/// given; a failing job doesn't affect the others.
+pub async fn batch_handler(
+    State(app_state): State<AppState>,
+    client: ClientId,
+    RequestDeadline(deadline): RequestDeadline,
+    JobJson(batch): JobJson<BatchRequest>,
+) -> Result<Json<Vec<CompileResponse>>, JobReply> {
+    println!("Received batch of {} jobs", batch.jobs.len());
+    let started = Instant::now();
+    if batch.jobs.len() > app_state.max_batch_size {
+        let message = format!(
+            "A batch may contain at most {} jobs, got {}",
+            app_state.max_batch_size,
+            batch.jobs.len()
+        );
+        guardrails::record_rejection(&app_state.metrics, &client, "batch_size", &message);
+        let error = CompileError::TooLarge(message);
+        return Err(rejected("batch_size", error, OutputEncoding::Utf8, started));
+    }
+
+    let responses = stream::iter(batch.jobs)
+        .map(|mut job| {
+            let app_state = app_state.clone();
+            // The deadline covers the whole batch, so later jobs get what is left of it
+            job.request.deadline = deadline;
+            async move {
+                let (_, Json(response)) = match job.language {
+                    Language::Rust => rust_job(app_state, job.request, true).await,
+                    Language::TypeScript => typescript_job(app_state, job.request, true).await,
+                };
+                response
+            }
+        })
+        .buffered(BATCH_PARALLELISM)
+        .collect::<Vec<_>>()
+        .await;
+    for response in &responses {
+        guardrails::record_response(&app_state.metrics, &client, response);
+    }
+
+    Ok(Json(responses))
+}
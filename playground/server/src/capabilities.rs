@@ -0,0 +1,77 @@
+use crate::{
+    process::MAX_OUTPUT_BYTES, sandbox, AppState, CargoProfile, Language, EXECUTION_TIMEOUT,
+    MAX_REQUEST_BODY_BYTES,
+};
+use axum::{extract::State, Json};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+// Everything here is read from the same constants and startup state the handlers use,
+// so it can't drift from what the server actually does
+#[derive(Serialize)]
+pub struct Capabilities {
+    // Languages this server offers, whether or not their template is usable right now
+    languages: Vec<Language>,
+    // How long a job may run before it is killed
+    max_timeout_secs: u64,
+    // Largest request body, measured after decompression
+    max_body_bytes: usize,
+    // Most output kept per stream (stdout, stderr) of a program
+    max_output_bytes: usize,
+    // Most jobs in a single /batch request
+    max_batch_size: usize,
+    // cargo profiles Rust requests may choose between
+    profiles: Vec<CargoProfile>,
+    // Output can be streamed while the program runs, over /ws
+    streaming: bool,
+    // Programs can use the Anchor CLI
+    anchor: bool,
+    // On-chain programs can be built with /rust/build-sbf
+    sbf: bool,
+    // Programs are confined to a sandbox
+    sandbox: bool,
+    // The tools found at startup (tool -> version)
+    toolchains: BTreeMap<&'static str, String>,
+}
+
+/// Describe what this server offers, so clients can adapt their UI to it
+///
+/// Lists the enabled languages, the limits requests run under and which optional
+/// features are available, so e.g. a release toggle or SBF builds can be hidden
+/// instead of failing when used.
+pub async fn capabilities_handler(State(app_state): State<AppState>) -> Json<Capabilities> {
+    println!("Received request to /capabilities");
+    let languages = [
+        (Language::Rust, app_state.rust_enabled),
+        (Language::TypeScript, app_state.typescript_enabled),
+    ]
+    .into_iter()
+    .filter_map(|(language, enabled)| enabled.then_some(language))
+    .collect();
+    let versions = &app_state.versions;
+    let toolchains = [
+        ("rustc", &versions.rustc),
+        ("cargo", &versions.cargo),
+        ("solana", &versions.solana),
+        ("anchor", &versions.anchor),
+        ("node", &versions.node),
+        ("pnpm", &versions.pnpm),
+    ]
+    .into_iter()
+    .filter_map(|(tool, version)| Some((tool, version.clone()?)))
+    .collect();
+
+    Json(Capabilities {
+        languages,
+        max_timeout_secs: EXECUTION_TIMEOUT.as_secs(),
+        max_body_bytes: MAX_REQUEST_BODY_BYTES,
+        max_output_bytes: MAX_OUTPUT_BYTES,
+        max_batch_size: app_state.max_batch_size,
+        profiles: vec![CargoProfile::Dev, CargoProfile::Release],
+        streaming: true,
+        anchor: versions.anchor.is_some(),
+        sbf: app_state.rust_enabled && app_state.sbf_unavailable.is_none(),
+        sandbox: sandbox::is_installed(),
+        toolchains,
+    })
+}
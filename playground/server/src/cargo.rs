@@ -0,0 +1,279 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    path::Path,
+    process::Command,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+// A single line of cargo's `--message-format=json` output. Only the message
+// kinds the playground cares about are modelled; everything else is `Other`.
+#[derive(Deserialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+enum CargoMessage {
+    CompilerMessage {
+        message: RustcDiagnostic,
+    },
+    // A unit (crate, build script, ...) was compiled or found to be up to date
+    CompilerArtifact {
+        executable: Option<String>,
+    },
+    #[serde(other)]
+    Other,
+}
+
+// The subset of a rustc JSON diagnostic needed to report it back to the user
+#[derive(Deserialize)]
+struct RustcDiagnostic {
+    level: String,
+    message: String,
+    code: Option<RustcCode>,
+    spans: Vec<RustcSpan>,
+    rendered: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RustcCode {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct RustcSpan {
+    line_start: u32,
+    column_start: u32,
+    is_primary: bool,
+}
+
+// A compiler warning, as returned to the user
+#[derive(Serialize, Clone)]
+pub struct Diagnostic {
+    pub message: String,
+    // Lint or error code, e.g. `unused_variables`
+    pub code: Option<String>,
+    // Where in `src/main.rs` the warning points, when it points anywhere
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+    pub rendered: String,
+}
+
+// What a `cargo build --message-format=json` run reported on stdout, or a plain
+// `rustc --error-format=json` run on stderr
+pub struct BuildReport {
+    // Whether stdout contained any JSON messages at all
+    pub json_available: bool,
+    // Whether rustc reported at least one `error` level diagnostic
+    pub has_errors: bool,
+    // Every diagnostic (errors and warnings) rendered the way rustc prints it
+    pub rendered: String,
+    // The warnings on their own
+    pub warnings: Vec<Diagnostic>,
+    // And the errors
+    pub errors: Vec<Diagnostic>,
+    // How many units the build consisted of, up to date ones included
+    pub units: u64,
+    // The binary the build produced, if it produced one; cargo reports it last
+    pub executable: Option<String>,
+}
+
+impl BuildReport {
+    pub fn parse(stdout: &str) -> Self {
+        let mut report = BuildReport::empty();
+        for line in stdout.lines() {
+            let Ok(message) = serde_json::from_str::<CargoMessage>(line) else {
+                continue;
+            };
+            report.json_available = true;
+
+            match message {
+                CargoMessage::CompilerMessage { message } => report.add(message),
+                CargoMessage::CompilerArtifact { executable } => {
+                    report.units += 1;
+                    report.executable = executable.or(report.executable.take());
+                }
+                CargoMessage::Other => {}
+            }
+        }
+        report
+    }
+
+    // rustc invoked directly prints one diagnostic per line, without cargo's wrapper
+    pub fn parse_rustc(stderr: &str) -> Self {
+        let mut report = BuildReport::empty();
+        for line in stderr.lines() {
+            let Ok(message) = serde_json::from_str::<RustcDiagnostic>(line) else {
+                continue;
+            };
+            report.json_available = true;
+            report.add(message);
+        }
+        report
+    }
+
+    fn empty() -> Self {
+        BuildReport {
+            json_available: false,
+            has_errors: false,
+            rendered: String::new(),
+            warnings: Vec::new(),
+            errors: Vec::new(),
+            units: 0,
+            executable: None,
+        }
+    }
+
+    fn add(&mut self, message: RustcDiagnostic) {
+        // Internal compiler errors use the level "error: internal compiler error"
+        let is_error = message.level.starts_with("error");
+        if is_error {
+            self.has_errors = true;
+        }
+        if let Some(rendered) = &message.rendered {
+            self.rendered.push_str(rendered);
+        }
+        if is_summary(&message) {
+            return;
+        }
+        if is_error {
+            self.errors.push(Diagnostic::from(message));
+        } else if message.level == "warning" {
+            self.warnings.push(Diagnostic::from(message));
+        }
+    }
+}
+
+impl From<RustcDiagnostic> for Diagnostic {
+    fn from(diagnostic: RustcDiagnostic) -> Self {
+        let primary = diagnostic.spans.iter().find(|span| span.is_primary);
+        Diagnostic {
+            message: diagnostic.message,
+            code: diagnostic.code.map(|code| code.code),
+            line: primary.map(|span| span.line_start),
+            column: primary.map(|span| span.column_start),
+            rendered: diagnostic.rendered.unwrap_or_default(),
+        }
+    }
+}
+
+// rustc's closing "N warnings emitted" or "aborting due to N previous errors" line,
+// which is reported as a diagnostic itself
+fn is_summary(diagnostic: &RustcDiagnostic) -> bool {
+    diagnostic.spans.is_empty()
+        && diagnostic.code.is_none()
+        && (diagnostic.message.ends_with(" emitted")
+            || diagnostic.message.starts_with("aborting due to"))
+}
+
+// Fallback for when cargo's JSON output is unavailable: recognise compile errors by
+// the text cargo and rustc print for them.
+pub fn looks_like_compile_error(stderr: &str) -> bool {
+    stderr.contains("error[E")
+        || stderr.contains("could not compile")
+        || stderr.contains("error: aborting due to")
+}
+
+// Follows a build's progress from cargo's JSON output as it streams in, counting the
+// units that are done. Cargo reports every unit, including the ones that were already
+// up to date, so a build of the same template always ends at the same count.
+#[derive(Default)]
+pub struct BuildProgress {
+    // The start of a line whose end hasn't arrived yet
+    partial: Vec<u8>,
+    pub compiled: u64,
+}
+
+impl BuildProgress {
+    // Take the next chunk of stdout; returns whether any unit finished in it
+    pub fn feed(&mut self, bytes: &[u8]) -> bool {
+        let before = self.compiled;
+        self.partial.extend_from_slice(bytes);
+        while let Some(end) = self.partial.iter().position(|&byte| byte == b'\n') {
+            if self.partial.starts_with(br#"{"reason":"compiler-artifact""#) {
+                self.compiled += 1;
+            }
+            self.partial.drain(..=end);
+        }
+        self.compiled != before
+    }
+}
+
+// How many units a build of the Rust template consists of, the total that build
+// progress is measured against. Estimated from the dependency graph at startup and
+// replaced by the exact count once a build has finished; zero while unknown.
+pub struct BuildTotal(AtomicU64);
+
+impl BuildTotal {
+    pub fn new(units: Option<u64>) -> Self {
+        BuildTotal(AtomicU64::new(units.unwrap_or(0)))
+    }
+
+    pub fn get(&self) -> Option<u64> {
+        Some(self.0.load(Ordering::Relaxed)).filter(|&units| units > 0)
+    }
+
+    pub fn set(&self, units: u64) {
+        self.0.store(units, Ordering::Relaxed);
+    }
+}
+
+// The parts of `cargo metadata` needed to count a build's units
+#[derive(Deserialize)]
+struct Metadata {
+    packages: Vec<MetadataPackage>,
+    resolve: Option<MetadataResolve>,
+}
+
+#[derive(Deserialize)]
+struct MetadataPackage {
+    id: String,
+    targets: Vec<MetadataTarget>,
+}
+
+#[derive(Deserialize)]
+struct MetadataTarget {
+    kind: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct MetadataResolve {
+    nodes: Vec<MetadataNode>,
+}
+
+#[derive(Deserialize)]
+struct MetadataNode {
+    id: String,
+}
+
+// Estimate how many units building the template takes from its dependency graph: one
+// per package built for this machine, plus one per build script. Returns None when the
+// graph can't be resolved offline, or not without changing the template's Cargo.lock.
+pub fn estimate_build_units(template_dir: &str) -> Option<u64> {
+    let output = Command::new("rustc").arg("-vV").output().ok()?;
+    let version = String::from_utf8_lossy(&output.stdout).to_string();
+    let host = version.lines().find_map(|line| line.strip_prefix("host: "))?;
+
+    let output = Command::new("cargo")
+        .current_dir(Path::new(template_dir))
+        .args(["metadata", "--format-version", "1", "--offline", "--locked"])
+        .args(["--filter-platform", host])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let metadata: Metadata = serde_json::from_slice(&output.stdout).ok()?;
+
+    let resolve = metadata.resolve?;
+    let resolved: HashSet<&str> = resolve.nodes.iter().map(|node| node.id.as_str()).collect();
+    let build_scripts = metadata
+        .packages
+        .iter()
+        .filter(|package| resolved.contains(package.id.as_str()))
+        .filter(|package| {
+            package
+                .targets
+                .iter()
+                .any(|target| target.kind.iter().any(|kind| kind == "custom-build"))
+        })
+        .count();
+    Some((resolved.len() + build_scripts) as u64).filter(|&units| units > 0)
+}
@@ -0,0 +1,405 @@
+use std::{
+    ffi::CString,
+    fs, io,
+    os::unix::{ffi::OsStrExt, process::CommandExt},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::OnceLock,
+    thread,
+    time::Duration,
+};
+
+// Where job cgroups are created, and the limits each one gets. Set once at startup;
+// until then (or without MAX_MEMORY_MB and MAX_PROCESSES) jobs don't get a cgroup of
+// their own.
+static PARENTS: OnceLock<Vec<Parent>> = OnceLock::new();
+
+// With cgroup v2 every controller is in the one hierarchy under this directory
+const UNIFIED_ROOT: &str = "/sys/fs/cgroup";
+
+// A cgroup the server may create job cgroups in: its own cgroup, in the memory or pids
+// hierarchy with cgroup v1 or in the single hierarchy with v2
+struct Parent {
+    dir: PathBuf,
+    // Files written in every job cgroup, in order; those this kernel doesn't have,
+    // e.g. the swap limit without swap accounting, are skipped
+    limits: Vec<(&'static str, String)>,
+    // The file in which the kernel counts a job cgroup's OOM kills
+    oom_events: &'static str,
+}
+
+impl Parent {
+    // Find the server's own cgroups, and prepare them for job cgroups that may use at
+    // most `memory_bytes` and have at most `max_processes` processes and threads
+    fn detect(memory_bytes: Option<u64>, max_processes: Option<u64>) -> io::Result<Vec<Self>> {
+        let own = fs::read_to_string("/proc/self/cgroup")?;
+        if Path::new(UNIFIED_ROOT).join("cgroup.controllers").exists() {
+            // cgroup v2, listed as "0::/path"
+            let path = own
+                .lines()
+                .find_map(|line| line.strip_prefix("0::"))
+                .ok_or_else(|| io::Error::other("The server isn't in a cgroup v2 cgroup"))?;
+            let dir = Path::new(UNIFIED_ROOT).join(path.trim_start_matches('/'));
+            let mut controllers = Vec::new();
+            let mut limits = Vec::new();
+            if let Some(bytes) = memory_bytes {
+                controllers.push("+memory");
+                // Swapping out would only slow the program down instead of stopping it
+                limits.push(("memory.max", bytes.to_string()));
+                limits.push(("memory.swap.max", "0".to_string()));
+            }
+            if let Some(max) = max_processes {
+                controllers.push("+pids");
+                limits.push(("pids.max", max.to_string()));
+            }
+            delegate(&dir, &controllers.join(" "))?;
+            Ok(vec![Parent {
+                dir,
+                limits,
+                oom_events: "memory.events",
+            }])
+        } else {
+            // cgroup v1, with a hierarchy per controller
+            let mut parents = Vec::new();
+            if let Some(bytes) = memory_bytes {
+                let limit = bytes.to_string();
+                parents.push(Parent {
+                    dir: v1_dir(&own, "memory")?,
+                    // The memory+swap limit can't be set below the memory limit, so it's
+                    // written second
+                    limits: vec![
+                        ("memory.limit_in_bytes", limit.clone()),
+                        ("memory.memsw.limit_in_bytes", limit),
+                    ],
+                    oom_events: "memory.oom_control",
+                });
+            }
+            if let Some(max) = max_processes {
+                parents.push(Parent {
+                    dir: v1_dir(&own, "pids")?,
+                    limits: vec![("pids.max", max.to_string())],
+                    // Never read, since this hierarchy doesn't limit memory
+                    oom_events: "memory.oom_control",
+                });
+            }
+            Ok(parents)
+        }
+    }
+}
+
+// The server's cgroup in the cgroup v1 hierarchy of `controller`, listed in `own` as
+// e.g. "4:memory:/path"
+fn v1_dir(own: &str, controller: &str) -> io::Result<PathBuf> {
+    let path = own
+        .lines()
+        .find_map(|line| {
+            let mut fields = line.splitn(3, ':');
+            let controllers = fields.nth(1)?;
+            let path = fields.next()?;
+            controllers
+                .split(',')
+                .any(|name| name == controller)
+                .then_some(path)
+        })
+        .ok_or_else(|| io::Error::other(format!("No cgroup {} controller", controller)))?;
+    Ok(Path::new(UNIFIED_ROOT)
+        .join(controller)
+        .join(path.trim_start_matches('/')))
+}
+
+// cgroup v2 only hands `controllers` down to the children of a cgroup without
+// processes of its own, so move the server into a child cgroup first if it has to be
+fn delegate(dir: &Path, controllers: &str) -> io::Result<()> {
+    let subtree = dir.join("cgroup.subtree_control");
+    if fs::write(&subtree, controllers).is_ok() {
+        return Ok(());
+    }
+    let server = dir.join("server");
+    fs::create_dir_all(&server)?;
+    fs::write(server.join("cgroup.procs"), std::process::id().to_string())?;
+    fs::write(&subtree, controllers)
+}
+
+// Give every later job a cgroup that holds it to `memory_bytes` and `max_processes`,
+// where set. Fails when the server can't create cgroups, e.g. because /sys/fs/cgroup is
+// mounted read-only.
+pub fn install(memory_bytes: Option<u64>, max_processes: Option<u64>) -> io::Result<()> {
+    let parents = Parent::detect(memory_bytes, max_processes)?;
+    // Find out now rather than with the first job whether job cgroups can be made
+    JobCgroup::create(&parents, |_| true)?;
+    let _ = PARENTS.set(parents);
+    Ok(())
+}
+
+// The cgroup a job's program runs in, removed again when dropped
+pub struct JobCgroup {
+    dirs: Vec<PathBuf>,
+    // The cgroup.procs file of each directory, ready to be written after forking
+    procs: Vec<CString>,
+    oom_events: Option<PathBuf>,
+    // The file in which the kernel counts the forks refused over the process limit
+    pids_events: Option<PathBuf>,
+}
+
+impl JobCgroup {
+    // A cgroup for a new job, or an empty one that leaves commands as they are when no
+    // limits are installed
+    pub fn new() -> io::Result<Self> {
+        Self::create(installed(), |_| true)
+    }
+
+    // A cgroup holding a command only to the process limit, for commands such as
+    // dependency installs that aren't held to the memory limit
+    pub fn processes_only() -> io::Result<Self> {
+        Self::create(installed(), |file| file.starts_with("pids."))
+    }
+
+    // Create a job cgroup under each parent with the limits whose file `keep` accepts,
+    // leaving out the parents without any
+    fn create(parents: &[Parent], keep: impl Fn(&str) -> bool) -> io::Result<Self> {
+        let name = format!("job-{}", uuid::Uuid::new_v4().simple());
+        let mut job = JobCgroup {
+            dirs: Vec::new(),
+            procs: Vec::new(),
+            oom_events: None,
+            pids_events: None,
+        };
+        for parent in parents {
+            let limits: Vec<_> = parent
+                .limits
+                .iter()
+                .filter(|(file, _)| keep(file))
+                .collect();
+            if limits.is_empty() {
+                continue;
+            }
+            let dir = parent.dir.join(&name);
+            fs::create_dir(&dir)?;
+            job.dirs.push(dir.clone());
+            for (file, value) in &limits {
+                match fs::write(dir.join(file), value) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+            let procs = dir.join("cgroup.procs");
+            job.procs
+                .push(CString::new(procs.as_os_str().as_bytes()).map_err(io::Error::other)?);
+            if limits.iter().any(|(file, _)| file.starts_with("memory.")) {
+                job.oom_events = Some(dir.join(parent.oom_events));
+            }
+            if limits.iter().any(|(file, _)| file.starts_with("pids.")) {
+                job.pids_events = Some(dir.join("pids.events"));
+            }
+        }
+        Ok(job)
+    }
+
+    // Start `command` in this cgroup, so it and everything it starts is held to its
+    // limits.
+    //
+    // Apply this before the sandbox: the sandbox drops the command's privileges, after
+    // which it could no longer join. The sandbox process waiting for the command joins
+    // along with it.
+    pub fn enter<'a>(&self, command: &'a mut Command) -> &'a mut Command {
+        if self.procs.is_empty() {
+            return command;
+        }
+        let procs = self.procs.clone();
+        // SAFETY: open, write and close are async-signal-safe, and the paths were
+        // prepared before forking
+        unsafe {
+            command.pre_exec(move || {
+                for path in &procs {
+                    let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                    if fd == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    // "0" stands for the writing process itself
+                    let written = libc::write(fd, c"0".as_ptr().cast(), 1);
+                    libc::close(fd);
+                    if written != 1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        command
+    }
+
+    // Whether the kernel killed a process of this job for going over its memory limit.
+    // The OOM killer's SIGKILL is counted here even when a wrapper such as pnpm hides
+    // how its child ended; a job without a limit never runs out this way.
+    pub fn out_of_memory(&self) -> bool {
+        let Some(events) = self.oom_events.as_ref() else {
+            return false;
+        };
+        counted(events, "oom_kill ")
+    }
+
+    // Whether a process of this job was refused a new process or thread for going over
+    // its process limit. The fork or pthread_create then fails with EAGAIN, which the
+    // program may or may not report.
+    pub fn hit_process_limit(&self) -> bool {
+        self.pids_events
+            .as_ref()
+            .is_some_and(|events| counted(events, "max "))
+    }
+}
+
+// Whether the kernel counted anything on the line of `events` starting with `prefix`
+fn counted(events: &Path, prefix: &str) -> bool {
+    fs::read_to_string(events).is_ok_and(|events| {
+        events
+            .lines()
+            .filter_map(|line| line.strip_prefix(prefix))
+            .any(|count| count.trim() != "0")
+    })
+}
+
+// The parents installed at startup, if any
+fn installed() -> &'static [Parent] {
+    PARENTS.get().map(Vec::as_slice).unwrap_or_default()
+}
+
+impl Drop for JobCgroup {
+    fn drop(&mut self) {
+        for dir in &self.dirs {
+            remove(dir);
+        }
+    }
+}
+
+// A cgroup can only be removed once it's empty. The job's processes are normally gone
+// by now, but one that left the process group could still be running.
+fn remove(dir: &Path) {
+    for _ in 0..20 {
+        if fs::remove_dir(dir).is_ok() {
+            return;
+        }
+        let procs = fs::read_to_string(dir.join("cgroup.procs")).unwrap_or_default();
+        for pid in procs
+            .lines()
+            .filter_map(|pid| pid.parse::<libc::pid_t>().ok())
+        {
+            // SAFETY: kill has no memory safety preconditions
+            unsafe { libc::kill(pid, libc::SIGKILL) };
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    println!("WARNING: couldn't remove job cgroup {}", dir.display());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    // A job cgroup with the given limits, or None when this machine doesn't let the
+    // tests create cgroups
+    fn job_cgroup(memory_bytes: Option<u64>, max_processes: Option<u64>) -> Option<JobCgroup> {
+        match Parent::detect(memory_bytes, max_processes)
+            .and_then(|parents| JobCgroup::create(&parents, |_| true))
+        {
+            Ok(job) => Some(job),
+            Err(err) => {
+                println!("Skipping: can't create a cgroup here: {}", err);
+                None
+            }
+        }
+    }
+
+    // Runs `script` with Python in a job cgroup limited to 16 processes and threads,
+    // returning what it printed and whether it succeeded
+    fn run_bomb(script: &str) -> Option<(String, bool, bool)> {
+        let job = job_cgroup(None, Some(16))?;
+        let output = job
+            .enter(Command::new("python3").args(["-c", script]))
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        Some((stdout, output.status.success(), job.hit_process_limit()))
+    }
+
+    #[test]
+    fn going_over_the_memory_limit_is_out_of_memory() {
+        let Some(job) = job_cgroup(Some(64 << 20), None) else {
+            return;
+        };
+        // Touches 256 MiB, four times the limit
+        let script = "import mmap\nm = mmap.mmap(-1, 256 << 20)\nfor i in range(0, len(m), 4096):\n    m[i] = 1\n";
+        let status = job
+            .enter(Command::new("python3").args(["-c", script]))
+            .status()
+            .unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+        assert!(job.out_of_memory());
+    }
+
+    #[test]
+    fn killing_itself_is_not_out_of_memory() {
+        let Some(job) = job_cgroup(Some(64 << 20), None) else {
+            return;
+        };
+        let status = job
+            .enter(Command::new("bash").args(["-c", "kill -9 $$"]))
+            .status()
+            .unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+        assert!(!job.out_of_memory());
+    }
+
+    #[test]
+    fn fork_bombs_hit_the_process_limit() {
+        // Forks sleeping children until forking fails, then kills them again
+        let script = "import os, signal, time\nchildren = []\ntry:\n    for _ in range(1000):\n        pid = os.fork()\n        if pid == 0:\n            time.sleep(30)\n            os._exit(0)\n        children.append(pid)\nexcept BlockingIOError:\n    print(len(children))\nfor pid in children:\n    os.kill(pid, signal.SIGKILL)\n";
+        let Some((stdout, _, hit_limit)) = run_bomb(script) else {
+            return;
+        };
+        // The limit holds even though the tests may well run as root
+        let children: usize = stdout.trim().parse().unwrap();
+        assert!(children < 16);
+        assert!(hit_limit);
+    }
+
+    #[test]
+    fn thread_bombs_hit_the_process_limit() {
+        // Starts sleeping threads until that fails
+        let script = "import os, threading, time\nthreads = 0\ntry:\n    for _ in range(1000):\n        threading.Thread(target=time.sleep, args=(30,), daemon=True).start()\n        threads += 1\nexcept RuntimeError:\n    print(threads, flush=True)\nos._exit(0)\n";
+        let Some((stdout, _, hit_limit)) = run_bomb(script) else {
+            return;
+        };
+        let threads: usize = stdout.trim().parse().unwrap();
+        assert!(threads < 16);
+        assert!(hit_limit);
+    }
+
+    #[test]
+    fn staying_under_the_process_limit_is_fine() {
+        let Some((stdout, success, hit_limit)) =
+            run_bomb("import os\nos.system('true')\nprint('ok')\n")
+        else {
+            return;
+        };
+        assert_eq!(stdout, "ok\n");
+        assert!(success);
+        assert!(!hit_limit);
+    }
+
+    #[test]
+    fn job_cgroups_are_removed() {
+        let Some(job) = job_cgroup(Some(64 << 20), None) else {
+            return;
+        };
+        let dirs = job.dirs.clone();
+        // A process that outlives the command keeps the cgroup busy until it's killed
+        let status = job
+            .enter(Command::new("bash").args(["-c", "sleep 30 >/dev/null 2>&1 &"]))
+            .status()
+            .unwrap();
+        assert!(status.success());
+        drop(job);
+        assert!(dirs.iter().all(|dir| !dir.exists()));
+    }
+}
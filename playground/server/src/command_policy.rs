@@ -0,0 +1,97 @@
+use std::{
+    collections::HashSet,
+    env, fs, io,
+    os::unix::fs::{symlink, PermissionsExt},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::OnceLock,
+};
+
+// The PATH programs run with when command restrictions are on. Set once at startup;
+// until then (or when restrictions are off) `restrict` leaves commands as they are.
+static SHIM_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+// Allowed when ALLOWED_COMMANDS isn't set
+pub const DEFAULT_ALLOWED_COMMANDS: &str = "cargo,rustc,solana,node";
+
+// Commands the server itself starts programs with, which must always be found
+const REQUIRED_COMMANDS: [&str; 2] = ["cargo", "pnpm"];
+
+// Every denied command is linked to this script, which names the command it was run as
+const DENIED_SCRIPT: &str = "#!/bin/sh\necho \"${0##*/}: command not allowed\" >&2\nexit 126\n";
+
+// Directories of command shims that stand in for the server's PATH:
+// - `bin` holds a link to the real binary for every allowed command, and a link to
+//   the denied script for every other command found on the server's PATH,
+// - `command-not-allowed` is the denied script itself.
+pub struct CommandShims {
+    root: PathBuf,
+    // Allowed commands that weren't found on the server's PATH
+    pub missing: Vec<String>,
+}
+
+impl CommandShims {
+    // (Re)create the shims in `root` for the `allowed` commands
+    pub fn create(root: PathBuf, allowed: &[String]) -> io::Result<Self> {
+        if root.exists() {
+            fs::remove_dir_all(&root)?;
+        }
+        let bin = root.join("bin");
+        fs::create_dir_all(&bin)?;
+        let denied = root.join("command-not-allowed");
+        fs::write(&denied, DENIED_SCRIPT)?;
+        fs::set_permissions(&denied, fs::Permissions::from_mode(0o755))?;
+
+        let allowed: HashSet<&str> = allowed
+            .iter()
+            .map(String::as_str)
+            .chain(REQUIRED_COMMANDS)
+            .collect();
+        let mut missing: Vec<String> = allowed.iter().map(|name| name.to_string()).collect();
+
+        // Like a PATH lookup, the first directory that has a command wins
+        let path = env::var_os("PATH").unwrap_or_default();
+        for dir in env::split_paths(&path) {
+            let Ok(entries) = fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name();
+                let shim = bin.join(&name);
+                if shim.symlink_metadata().is_ok() || !is_executable(&entry.path()) {
+                    continue;
+                }
+                let is_allowed = name.to_str().is_some_and(|name| allowed.contains(name));
+                let target = if is_allowed { entry.path() } else { denied.clone() };
+                symlink(target, shim)?;
+                missing.retain(|missing| *missing != name.to_string_lossy());
+            }
+        }
+        missing.sort();
+
+        Ok(CommandShims { root, missing })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Make these the shims for every later `restrict`
+    pub fn install(self) {
+        let _ = SHIM_PATH.set(self.root.join("bin"));
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+// Run `command` with the shims as its only PATH, if command restrictions are on, so
+// the program can only find the allowed commands by name. Commands run by absolute
+// path are not affected.
+pub fn restrict(command: &mut Command) -> &mut Command {
+    if let Some(path) = SHIM_PATH.get() {
+        command.env("PATH", path);
+    }
+    command
+}
@@ -0,0 +1,84 @@
+use crate::{
+    job_queue::{JobQueue, JobSlot},
+    job_response, AppState, CompileError, JobReply, JobReport, OutputEncoding, EXECUTION_TIMEOUT,
+};
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// Header a client caps the whole request with, queueing included
+const DEADLINE_HEADER: &str = "x-request-deadline";
+
+// When the client stops waiting for the response, from X-Request-Deadline: either a
+// Unix timestamp in milliseconds or `timeout-ms=<n>`, counted from when the request
+// arrived. None without the header.
+pub struct RequestDeadline(pub Option<Instant>);
+
+#[async_trait]
+impl FromRequestParts<AppState> for RequestDeadline {
+    type Rejection = JobReply;
+
+    async fn from_request_parts(parts: &mut Parts, _: &AppState) -> Result<Self, Self::Rejection> {
+        let started = Instant::now();
+        let Some(value) = parts.headers.get(DEADLINE_HEADER) else {
+            return Ok(RequestDeadline(None));
+        };
+        let deadline = value
+            .to_str()
+            .ok()
+            .and_then(|value| parse(value.trim(), started));
+        match deadline {
+            Some(deadline) => Ok(RequestDeadline(Some(deadline))),
+            None => {
+                let error = CompileError::InvalidRequest(format!(
+                    "Invalid X-Request-Deadline {:?}; expected a Unix timestamp in milliseconds or timeout-ms=<milliseconds>",
+                    value
+                ));
+                Err(job_response(
+                    Err(error),
+                    JobReport::default(),
+                    OutputEncoding::Utf8,
+                    started,
+                ))
+            }
+        }
+    }
+}
+
+fn parse(value: &str, now: Instant) -> Option<Instant> {
+    if let Some(timeout_ms) = value.strip_prefix("timeout-ms=") {
+        return now.checked_add(Duration::from_millis(timeout_ms.parse().ok()?));
+    }
+    let deadline = UNIX_EPOCH + Duration::from_millis(value.parse().ok()?);
+    // A deadline that already passed is still a valid one; the job is then refused
+    match deadline.duration_since(SystemTime::now()) {
+        Ok(remaining) => now.checked_add(remaining),
+        Err(_) => Some(now),
+    }
+}
+
+// Wait for a job slot, but no longer than the client is willing to, and work out how
+// long the job may then run: the execution timeout, or what is left until the
+// deadline if that is less
+pub async fn acquire_slot(
+    job_queue: &Arc<JobQueue>,
+    deadline: Option<Instant>,
+) -> Result<(JobSlot, Duration), CompileError> {
+    let Some(deadline) = deadline else {
+        return Ok((job_queue.acquire().await, EXECUTION_TIMEOUT));
+    };
+    let expired = || {
+        CompileError::DeadlineExceeded(
+            "The request's deadline passed before the job could start".to_string(),
+        )
+    };
+    let slot = tokio::time::timeout_at(deadline.into(), job_queue.acquire())
+        .await
+        .map_err(|_| expired())?;
+    match deadline.checked_duration_since(Instant::now()) {
+        Some(remaining) if !remaining.is_zero() => Ok((slot, remaining.min(EXECUTION_TIMEOUT))),
+        _ => Err(expired()),
+    }
+}
@@ -0,0 +1,57 @@
+use std::{collections::BTreeMap, env, path::PathBuf};
+
+// The moment deterministic runs start at, as a Unix timestamp and in the form
+// libfaketime reads
+const PINNED_EPOCH: &str = "1700000000";
+const PINNED_TIME: &str = "2023-11-14 22:13:20";
+
+// Seed deterministic runs are given for their random number generators
+const PINNED_SEED: &str = "1";
+
+// Where distributions install libfaketime, tried in order after LIBFAKETIME
+const LIBFAKETIME_PATHS: [&str; 4] = [
+    "/usr/lib/x86_64-linux-gnu/faketime/libfaketime.so.1",
+    "/usr/lib/aarch64-linux-gnu/faketime/libfaketime.so.1",
+    "/usr/lib/faketime/libfaketime.so.1",
+    "/usr/local/lib/faketime/libfaketime.so.1",
+];
+
+// Pins what a program sees of the clock and its seeds, for runs that asked to be
+// deterministic. The clock can only be pinned when libfaketime is installed.
+pub struct Determinism {
+    libfaketime: Option<PathBuf>,
+}
+
+impl Determinism {
+    pub fn detect() -> Self {
+        let libfaketime = env::var_os("LIBFAKETIME")
+            .map(PathBuf::from)
+            .into_iter()
+            .chain(LIBFAKETIME_PATHS.iter().map(PathBuf::from))
+            .find(|path| path.is_file());
+        Determinism { libfaketime }
+    }
+
+    pub fn libfaketime(&self) -> Option<&PathBuf> {
+        self.libfaketime.as_ref()
+    }
+
+    // Add the pinning variables to a program's environment, over any the request set.
+    // Returns whether everything the server can control was pinned, which needs the
+    // clock; what the program reads from the network or the validator never is.
+    pub fn pin(&self, env: &mut BTreeMap<String, String>) -> bool {
+        env.insert("SOURCE_DATE_EPOCH".to_string(), PINNED_EPOCH.to_string());
+        env.insert("RUST_TEST_SEED".to_string(), PINNED_SEED.to_string());
+        let Some(libfaketime) = &self.libfaketime else {
+            return false;
+        };
+        // The clock starts at the pinned moment and then advances as usual, so sleeps
+        // and timers still work; monotonic clocks are left alone for the same reason
+        env.insert("LD_PRELOAD".to_string(), libfaketime.to_string_lossy().to_string());
+        env.insert("FAKETIME".to_string(), format!("@{}", PINNED_TIME));
+        env.insert("FAKETIME_DONT_FAKE_MONOTONIC".to_string(), "1".to_string());
+        env.insert("TZ".to_string(), "UTC".to_string());
+        true
+    }
+}
+
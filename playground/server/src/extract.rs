@@ -0,0 +1,85 @@
+use crate::{
+    guardrails::{self, ClientId},
+    job_response, AppState, CompileError, JobReply, JobReport, OutputEncoding,
+};
+use axum::{
+    async_trait,
+    extract::{rejection::JsonRejection, FromRequest, Request},
+    http::StatusCode,
+    Json,
+};
+use regex::Regex;
+use serde::de::DeserializeOwned;
+use std::{error::Error, sync::LazyLock, time::Instant};
+
+// serde's wording for a missing field, e.g. "missing field `code` at line 1 column 2",
+// prefixed with the path to the enclosing object when it is nested
+static MISSING_FIELD: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(?:(\S+): )?missing field `([^`]+)`").unwrap());
+
+// `Json`, except that a body that can't be parsed is rejected with a 422 response in
+// the same shape as every other compile response, rather than axum's plain text
+pub(crate) struct JobJson<T>(pub T);
+
+#[async_trait]
+impl<T> FromRequest<AppState> for JobJson<T>
+where
+    T: DeserializeOwned,
+{
+    type Rejection = JobReply;
+
+    async fn from_request(request: Request, state: &AppState) -> Result<Self, Self::Rejection> {
+        let started = Instant::now();
+        let client = ClientId::from_extensions(request.extensions());
+        match Json::<T>::from_request(request, state).await {
+            Ok(Json(value)) => Ok(JobJson(value)),
+            Err(rejection) => Err(job_response(
+                Err(reject(rejection, state, &client)),
+                JobReport::default(),
+                OutputEncoding::Utf8,
+                started,
+            )),
+        }
+    }
+}
+
+// Body size limits have their own error, and count as a guardrail rejection; everything
+// else makes the request malformed
+fn reject(rejection: JsonRejection, state: &AppState, client: &ClientId) -> CompileError {
+    if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        let reason = rejection.body_text();
+        guardrails::record_rejection(&state.metrics, client, "body_size", &reason);
+        return CompileError::TooLarge(reason);
+    }
+    CompileError::MalformedRequest(describe(&rejection))
+}
+
+// A message that says what is wrong with the body, without axum's generic preamble
+fn describe(rejection: &JsonRejection) -> String {
+    let detail = rejection
+        .source()
+        .map(|source| source.to_string())
+        .unwrap_or_else(|| rejection.body_text());
+
+    match rejection {
+        JsonRejection::JsonDataError(_) => match MISSING_FIELD.captures(&detail) {
+            Some(field) => match field.get(1) {
+                Some(parent) => {
+                    format!("missing required field: {}.{}", parent.as_str(), &field[2])
+                }
+                None => format!("missing required field: {}", &field[2]),
+            },
+            None => format!("invalid field: {}", detail),
+        },
+        JsonRejection::JsonSyntaxError(_)
+            if detail.starts_with("EOF while parsing a value at line 1 column 0") =>
+        {
+            "request body is empty".to_string()
+        }
+        JsonRejection::JsonSyntaxError(_) => format!("invalid JSON: {}", detail),
+        JsonRejection::MissingJsonContentType(_) => {
+            "expected a JSON body with `Content-Type: application/json`".to_string()
+        }
+        _ => rejection.body_text(),
+    }
+}
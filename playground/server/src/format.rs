@@ -0,0 +1,223 @@
+use crate::{
+    elapsed_ms, extract::JobJson, process, process::RunLimits, sandbox, AppState, ErrorKind,
+    EXECUTION_TIMEOUT,
+};
+use axum::{extract::State, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io,
+    process::{Command, Stdio},
+    time::Instant,
+};
+
+#[derive(Deserialize)]
+pub struct FormatCheckRequest {
+    code: String,
+}
+
+#[derive(Serialize)]
+pub struct FormatCheckResponse {
+    // The code is exactly what rustfmt would produce
+    formatted: bool,
+    // Unified diff from the code to rustfmt's output, when they differ
+    diff: Option<String>,
+    // Set when the check couldn't be done, e.g. because the code doesn't parse
+    error: Option<String>,
+    error_kind: Option<ErrorKind>,
+    duration_ms: u64,
+}
+
+type FormatCheckReply = (StatusCode, Json<FormatCheckResponse>);
+
+// Why a format check couldn't be done
+enum CheckError {
+    // rustfmt couldn't parse the code; carries its error output
+    Parse(String),
+    // rustfmt isn't installed
+    Missing,
+    Io(io::Error),
+}
+
+impl From<io::Error> for CheckError {
+    fn from(err: io::Error) -> Self {
+        CheckError::Io(err)
+    }
+}
+
+/// Check whether Rust code is formatted
+///
+/// Runs rustfmt over a copy of the code and reports whether anything changed, with
+/// the diff if it did. Unformatted code is not an error of the request, so it is
+/// returned with status 200 and `formatted: false`.
+pub async fn format_check_handler(
+    State(app_state): State<AppState>,
+    JobJson(request): JobJson<FormatCheckRequest>,
+) -> FormatCheckReply {
+    println!("Received Rust format check request");
+    let started = Instant::now();
+
+    let _slot = app_state.job_queue.acquire().await;
+    let check = tokio::task::spawn_blocking(move || check_format(&request.code));
+    match check.await {
+        Ok(Ok(diff)) => {
+            let response = FormatCheckResponse {
+                formatted: diff.is_none(),
+                diff,
+                error: None,
+                error_kind: None,
+                duration_ms: elapsed_ms(started),
+            };
+            (StatusCode::OK, Json(response))
+        }
+        Ok(Err(CheckError::Parse(message))) => {
+            failure(StatusCode::BAD_REQUEST, ErrorKind::Compile, message, started)
+        }
+        Ok(Err(CheckError::Missing)) => failure(
+            StatusCode::NOT_IMPLEMENTED,
+            ErrorKind::Internal,
+            "rustfmt is not installed on this server".to_string(),
+            started,
+        ),
+        Ok(Err(CheckError::Io(err))) => failure(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorKind::Internal,
+            err.to_string(),
+            started,
+        ),
+        Err(err) => failure(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorKind::Internal,
+            format!("Task panic: {}", err),
+            started,
+        ),
+    }
+}
+
+// Format the code with rustfmt and diff it against the original. Returns None when
+// rustfmt left the code as it was.
+//
+// rustfmt only ever sees the code on stdin: given a file, it would also format (and
+// report errors from) every out-of-line module the code declares, including ones that
+// `#[path]` points anywhere on the server.
+fn check_format(code: &str) -> Result<Option<String>, CheckError> {
+    let dir = tempfile::Builder::new().prefix("playground-fmt-").tempdir()?;
+    fs::write(dir.path().join("original.rs"), code)?;
+
+    let limits = RunLimits {
+        deadline: Some(Instant::now() + EXECUTION_TIMEOUT),
+        idle_timeout: None,
+        cancel: None,
+    };
+    let output = process::run_with_stdin(
+        sandbox::confine(
+            Command::new("rustfmt")
+                .args(["--emit", "stdout", "--edition", "2021", "--color", "never"]),
+            &[],
+            &[],
+        ),
+        Stdio::from(File::open(dir.path().join("original.rs"))?),
+        limits.clone(),
+        &mut |_, _| {},
+    )
+    .map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => CheckError::Missing,
+        _ => CheckError::Io(err),
+    })?;
+    if output.timed_out {
+        return Err(CheckError::Io(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("rustfmt did not finish within {} seconds", EXECUTION_TIMEOUT.as_secs()),
+        )));
+    }
+    // rustup's proxy fails like this when the rustfmt component isn't installed
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.contains("is not installed for the toolchain") {
+        return Err(CheckError::Missing);
+    }
+    if !output.status.success() {
+        // Point the messages at the file the user knows, as the other endpoints do
+        return Err(CheckError::Parse(stderr.replace("<stdin>", "src/main.rs")));
+    }
+
+    if output.stdout == code.as_bytes() {
+        return Ok(None);
+    }
+    fs::write(dir.path().join("formatted.rs"), &output.stdout)?;
+    // diff exits with 1 when the files differ, which is the expected outcome here
+    let output = process::run(
+        Command::new("diff")
+            .args(["-u", "--label", "a/src/main.rs", "--label", "b/src/main.rs"])
+            .args(["original.rs", "formatted.rs"])
+            .current_dir(dir.path()),
+        limits,
+        &mut |_, _| {},
+    )?;
+    Ok(Some(String::from_utf8_lossy(&output.stdout).to_string()))
+}
+
+fn failure(
+    status: StatusCode,
+    kind: ErrorKind,
+    message: String,
+    started: Instant,
+) -> FormatCheckReply {
+    let response = FormatCheckResponse {
+        formatted: false,
+        diff: None,
+        error: Some(message),
+        error_kind: Some(kind),
+        duration_ms: elapsed_ms(started),
+    };
+    (status, Json(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A module that `#[path]` points outside the check must be neither rewritten nor
+    // read, even when it is unformatted or doesn't parse
+    #[test]
+    fn path_modules_are_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let victim = dir.path().join("victim.rs");
+        for contents in ["fn  secret() {}\n", "fn secret( {\n"] {
+            fs::write(&victim, contents).unwrap();
+            let code = format!("#[path = {:?}]\nmod v;\nfn main(){{let x=1;}}\n", victim);
+
+            let result = check_format(&code);
+            if matches!(result, Err(CheckError::Missing)) {
+                return;
+            }
+            assert_eq!(fs::read_to_string(&victim).unwrap(), contents);
+            match result {
+                Ok(Some(diff)) => assert!(!diff.contains("secret"), "{}", diff),
+                Ok(None) => panic!("the code is unformatted"),
+                Err(CheckError::Parse(message)) => panic!("unexpected parse error: {}", message),
+                Err(_) => panic!("check failed"),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_errors_point_at_main_rs() {
+        match check_format("fn main( {\n") {
+            Err(CheckError::Parse(message)) => {
+                assert!(message.contains("src/main.rs"), "{}", message);
+                assert!(!message.contains("<stdin>"), "{}", message);
+            }
+            Err(CheckError::Missing) => {}
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn formatted_code_has_no_diff() {
+        match check_format("fn main() {\n    let x = 1;\n}\n") {
+            Ok(diff) => assert_eq!(diff, None),
+            Err(CheckError::Missing) => {}
+            Err(_) => panic!("check failed"),
+        }
+    }
+}
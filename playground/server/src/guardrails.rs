@@ -0,0 +1,129 @@
+use crate::{metrics::Metrics, AppState, CompileResponse};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, Extensions},
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+// Mixed into every client hash, and different on every start, so that the hashes in
+// the logs can't be reversed by hashing every IPv4 address
+static CLIENT_SALT: LazyLock<String> = LazyLock::new(|| uuid::Uuid::new_v4().to_string());
+
+// Identifies the client a request came from without logging its address: the first
+// 12 hex digits of a salted hash of its IP
+pub struct ClientId(String);
+
+impl ClientId {
+    pub fn from_extensions(extensions: &Extensions) -> Self {
+        let ip = extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string())
+            .unwrap_or_default();
+        let mut hasher = Sha256::new();
+        hasher.update(CLIENT_SALT.as_bytes());
+        hasher.update(ip.as_bytes());
+        let hash: String = hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        ClientId(hash[..12].to_string())
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientId {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &AppState) -> Result<Self, Self::Rejection> {
+        Ok(ClientId::from_extensions(&parts.extensions))
+    }
+}
+
+// Count a request a guardrail turned away, e.g. for arguments over the size limit, and
+// log it as a JSON event, so operators can see what is being rejected and for whom
+pub fn record_rejection(metrics: &Metrics, client: &ClientId, rule: &'static str, reason: &str) {
+    metrics.count_rejection(rule);
+    let event = serde_json::json!({
+        "event": "compile_rejected",
+        "client": client.0,
+        "rule": rule,
+        "reason": reason,
+    });
+    println!("{}", event);
+}
+
+// Record the rejection behind a compile response, if it was one
+pub fn record_response(metrics: &Metrics, client: &ClientId, response: &CompileResponse) {
+    if let Some(rule) = response.rejected_by {
+        let reason = response.error.as_deref().unwrap_or_default();
+        record_rejection(metrics, client, rule, reason);
+    }
+}
+
+// Allows each client at most `max` of something per `window`, e.g. payer sessions
+pub struct RateLimit {
+    max: u32,
+    window: Duration,
+    // When each client's current window started, and how much it used of it
+    clients: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl RateLimit {
+    pub fn new(max: u32, window: Duration) -> Self {
+        RateLimit {
+            max,
+            window,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Count one use by `client`, or return how long until it may try again
+    pub fn check(&self, client: &ClientId) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        // Clients whose window is over start afresh anyway, so stop tracking them
+        clients.retain(|_, (started, _)| now.duration_since(*started) < self.window);
+        let (started, used) = clients.entry(client.0.clone()).or_insert((now, 0));
+        if *used >= self.max {
+            return Err(self.window.saturating_sub(now.duration_since(*started)));
+        }
+        *used += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_client_has_its_own_allowance() {
+        let limit = RateLimit::new(2, Duration::from_secs(3600));
+        let first = ClientId("a".to_string());
+        let second = ClientId("b".to_string());
+        assert!(limit.check(&first).is_ok());
+        assert!(limit.check(&first).is_ok());
+        let retry_after = limit.check(&first).unwrap_err();
+        assert!(retry_after > Duration::from_secs(3590));
+        assert!(limit.check(&second).is_ok());
+    }
+
+    #[test]
+    fn allowance_comes_back_after_the_window() {
+        let limit = RateLimit::new(1, Duration::from_millis(20));
+        let client = ClientId("a".to_string());
+        assert!(limit.check(&client).is_ok());
+        assert!(limit.check(&client).is_err());
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(limit.check(&client).is_ok());
+    }
+}
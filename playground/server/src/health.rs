@@ -0,0 +1,72 @@
+use crate::AppState;
+use axum::{extract::State, http::StatusCode};
+use std::{
+    process::Command,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Remembers when the tools last checked out healthy, so that frequent probes don't
+// start two processes each
+pub struct HealthCache {
+    ttl: Duration,
+    last_healthy: Mutex<Option<Instant>>,
+}
+
+impl HealthCache {
+    pub fn new(ttl: Duration) -> Self {
+        HealthCache {
+            ttl,
+            last_healthy: Mutex::new(None),
+        }
+    }
+
+    // Failures are never cached, so a broken tool is re-checked on every probe
+    fn is_fresh(&self) -> bool {
+        self.last_healthy
+            .lock()
+            .unwrap()
+            .is_some_and(|checked| checked.elapsed() < self.ttl)
+    }
+
+    fn mark_healthy(&self) {
+        *self.last_healthy.lock().unwrap() = Some(Instant::now());
+    }
+}
+
+// Whether `<tool> --version` runs and succeeds
+async fn tool_works(tool: &'static str) -> bool {
+    tokio::task::spawn_blocking(move || {
+        Command::new(tool)
+            .arg("--version")
+            .output()
+            .map(|output| output.status.success())
+            .unwrap_or(false)
+    })
+    .await
+    .unwrap_or(false)
+}
+
+// Health check endpoint for Docker integration
+pub async fn health_check(State(app_state): State<AppState>) -> StatusCode {
+    println!("Health check request received");
+    if app_state.health.is_fresh() {
+        return StatusCode::OK;
+    }
+
+    // Verify critical components are working, for the languages this server offers
+    let (rust_working, ts_working) = tokio::join!(
+        async { !app_state.rust_enabled || tool_works("cargo").await },
+        async { !app_state.typescript_enabled || tool_works("pnpm").await },
+    );
+
+    if rust_working && ts_working {
+        println!("Health check succeeded - tools of the enabled languages available");
+        app_state.health.mark_healthy();
+        StatusCode::OK
+    } else {
+        println!("Health check failed - Rust available: {}, TypeScript tools available: {}", 
+                 rust_working, ts_working);
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
@@ -0,0 +1,74 @@
+use crate::{
+    metrics::Metrics,
+    workspace::{ActiveWorkspaces, RUST_PREFIX, TYPESCRIPT_PREFIX},
+};
+use std::{
+    env, fs, io,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, SystemTime},
+};
+
+// Periodically remove private workspace directories that were left behind, e.g. by a
+// crash or a killed server, and are older than `max_age`. Workspaces that are still
+// in use are never touched, however old they are.
+pub fn spawn(
+    active: ActiveWorkspaces,
+    metrics: Arc<Metrics>,
+    interval: Duration,
+    max_age: Duration,
+) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let active = active.clone();
+            let reclaimed = tokio::task::spawn_blocking(move || sweep(&active, max_age)).await;
+            match reclaimed {
+                Ok(Ok(0)) => {}
+                Ok(Ok(reclaimed)) => {
+                    println!("Janitor removed {} leaked workspaces", reclaimed);
+                    metrics
+                        .workspace_leaks_reclaimed_total
+                        .fetch_add(reclaimed, Ordering::Relaxed);
+                }
+                Ok(Err(err)) => println!("Janitor failed to scan the temp directory: {}", err),
+                Err(err) => println!("Janitor task failed: {}", err),
+            }
+        }
+    });
+}
+
+// Remove stale workspace directories from the temp root, returning how many went
+fn sweep(active: &ActiveWorkspaces, max_age: Duration) -> io::Result<u64> {
+    let now = SystemTime::now();
+    let mut reclaimed = 0;
+
+    for entry in fs::read_dir(env::temp_dir())? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !name.starts_with(RUST_PREFIX) && !name.starts_with(TYPESCRIPT_PREFIX) {
+            continue;
+        }
+
+        let path = entry.path();
+        if active.contains(&path) {
+            continue;
+        }
+        let age = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| now.duration_since(modified).ok());
+        if age.is_none_or(|age| age < max_age) {
+            continue;
+        }
+
+        match fs::remove_dir_all(&path) {
+            Ok(()) => reclaimed += 1,
+            Err(err) => println!("Janitor could not remove {}: {}", path.display(), err),
+        }
+    }
+
+    Ok(reclaimed)
+}
@@ -0,0 +1,123 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// How many of the most recent jobs the average duration is taken over
+const RECENT_JOBS: usize = 20;
+
+// The slots jobs build and run in. Jobs that find every slot taken wait in line, in
+// the order they arrived, and are told where they stood and how long that should take.
+pub struct JobQueue {
+    slots: Arc<Semaphore>,
+    capacity: usize,
+    // Jobs waiting for a slot right now
+    waiting: AtomicUsize,
+    // How long the most recent jobs held their slot
+    recent: Mutex<VecDeque<Duration>>,
+}
+
+// Where a job stood when it had to wait for a slot
+#[derive(Clone, Copy)]
+pub struct QueueWait {
+    // 1 for the first job in line
+    pub position: usize,
+    // Expected wait from the recent jobs' average duration; None until a job finished
+    pub estimated_wait_secs: Option<u64>,
+}
+
+// A job's slot, given back to the queue when dropped
+pub struct JobSlot {
+    queue: Arc<JobQueue>,
+    started: Instant,
+    _permit: OwnedSemaphorePermit,
+    // Set when the job had to wait for the slot
+    pub wait: Option<QueueWait>,
+}
+
+impl JobQueue {
+    pub fn new(capacity: usize) -> Self {
+        JobQueue {
+            slots: Arc::new(Semaphore::new(capacity)),
+            capacity,
+            waiting: AtomicUsize::new(0),
+            recent: Mutex::new(VecDeque::with_capacity(RECENT_JOBS)),
+        }
+    }
+
+    // Most jobs that may run at the same time
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // Wait for a free slot. The semaphore hands out slots first come, first served, so
+    // a job's position is one more than the jobs already waiting when it arrived.
+    pub async fn acquire(self: &Arc<Self>) -> JobSlot {
+        if let Ok(permit) = self.slots.clone().try_acquire_owned() {
+            return self.slot(permit, None);
+        }
+
+        let position = self.waiting.fetch_add(1, Ordering::Relaxed) + 1;
+        let wait = QueueWait {
+            position,
+            estimated_wait_secs: self.estimate_wait(position),
+        };
+        // Leaves the line even if the request is dropped while waiting
+        let _in_line = InLine(&self.waiting);
+        let permit = self
+            .slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the job slots are never closed");
+        self.slot(permit, Some(wait))
+    }
+
+    fn slot(self: &Arc<Self>, permit: OwnedSemaphorePermit, wait: Option<QueueWait>) -> JobSlot {
+        JobSlot {
+            queue: self.clone(),
+            started: Instant::now(),
+            _permit: permit,
+            wait,
+        }
+    }
+
+    // Every slot has to free up once for each round of jobs ahead of this one
+    fn estimate_wait(&self, position: usize) -> Option<u64> {
+        let recent = self.recent.lock().unwrap();
+        if recent.is_empty() {
+            return None;
+        }
+        let average = recent.iter().sum::<Duration>() / recent.len() as u32;
+        let rounds = position.div_ceil(self.capacity) as u32;
+        Some((average * rounds).as_secs_f64().ceil() as u64)
+    }
+
+    fn record(&self, duration: Duration) {
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT_JOBS {
+            recent.pop_front();
+        }
+        recent.push_back(duration);
+    }
+}
+
+impl Drop for JobSlot {
+    fn drop(&mut self) {
+        self.queue.record(self.started.elapsed());
+    }
+}
+
+// Counts a job as waiting for as long as it is alive
+struct InLine<'a>(&'a AtomicUsize);
+
+impl Drop for InLine<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
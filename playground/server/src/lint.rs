@@ -0,0 +1,189 @@
+use crate::{
+    elapsed_ms, extract::JobJson, process, process::RunLimits, workspace::Workspace, AppState,
+    EXECUTION_TIMEOUT,
+};
+use axum::{extract::State, http::StatusCode, Json};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{fs, io, path::Path, process::Command, sync::LazyLock, time::Instant};
+
+// One line of `tsc --pretty false` output, e.g.
+// "src/index.ts(3,7): error TS2322: Type 'string' is not assignable to type 'number'."
+// Errors that aren't about a particular file have no location.
+static TSC_DIAGNOSTIC: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(?:(.+)\((\d+),(\d+)\): )?(error|warning|message) (TS\d+): (.*)$").unwrap()
+});
+
+// Compiler options used when the template has no tsconfig.json, matching what esrun
+// accepts: modern syntax, ES modules and top-level await
+const DEFAULT_TSC_OPTIONS: [&str; 7] = [
+    "--target",
+    "es2022",
+    "--module",
+    "nodenext",
+    "--moduleResolution",
+    "nodenext",
+    "--skipLibCheck",
+];
+
+#[derive(Deserialize)]
+pub struct LintRequest {
+    code: String,
+}
+
+#[derive(Serialize)]
+pub struct LintResponse {
+    // No diagnostic is an error; warnings alone don't fail the check
+    success: bool,
+    diagnostics: Vec<LintDiagnostic>,
+    // Set when the linter itself couldn't be run
+    error: Option<String>,
+    duration_ms: u64,
+}
+
+#[derive(Serialize)]
+struct LintDiagnostic {
+    // File the diagnostic points at, relative to the workspace
+    file: Option<String>,
+    line: Option<u32>,
+    column: Option<u32>,
+    // "error", "warning" or "message", as reported by tsc
+    severity: String,
+    // The TypeScript error code, e.g. "TS2322"
+    code: String,
+    message: String,
+}
+
+type LintReply = (StatusCode, Json<LintResponse>);
+
+/// Type check TypeScript code
+///
+/// Runs the template's TypeScript compiler with `--noEmit` over the code and returns
+/// its diagnostics. Findings in the code are not an error of the request, so they are
+/// returned with status 200 and `success: false`.
+pub async fn lint_handler(
+    State(app_state): State<AppState>,
+    JobJson(request): JobJson<LintRequest>,
+) -> LintReply {
+    println!("Received TypeScript lint request");
+    let started = Instant::now();
+    if let Some(reason) = app_state.typescript_unavailable {
+        let message = reason.to_string();
+        return failure(StatusCode::SERVICE_UNAVAILABLE, message, started);
+    }
+
+    // Only the compiler the template installed is used, so the checks match its packages
+    let tsc = Path::new(&app_state.template_ts).join("node_modules/.bin/tsc");
+    if !tsc.is_file() {
+        let message =
+            "The TypeScript template has no linter installed (node_modules/.bin/tsc)".to_string();
+        return failure(StatusCode::NOT_IMPLEMENTED, message, started);
+    }
+
+    let _slot = app_state.job_queue.acquire().await;
+    let template_ts = app_state.template_ts.clone();
+    let active_workspaces = app_state.active_workspaces.clone();
+    let lint = tokio::task::spawn_blocking(move || {
+        let workspace = Workspace::typescript_copy(&template_ts, &active_workspaces)?;
+        run_tsc(&workspace, &tsc, &request.code)
+    });
+    match lint.await {
+        Ok(Ok(diagnostics)) => {
+            let response = LintResponse {
+                success: !diagnostics
+                    .iter()
+                    .any(|diagnostic| diagnostic.severity == "error"),
+                diagnostics,
+                error: None,
+                duration_ms: elapsed_ms(started),
+            };
+            (StatusCode::OK, Json(response))
+        }
+        Ok(Err(err)) => failure(StatusCode::INTERNAL_SERVER_ERROR, err.to_string(), started),
+        Err(err) => failure(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Task panic: {}", err),
+            started,
+        ),
+    }
+}
+
+fn run_tsc(workspace: &Workspace, tsc: &Path, code: &str) -> io::Result<Vec<LintDiagnostic>> {
+    fs::write(workspace.dir.join("src/index.ts"), code)?;
+
+    let mut command = Command::new(tsc);
+    command
+        .current_dir(&workspace.dir)
+        .args(["--noEmit", "--pretty", "false"]);
+    if workspace.dir.join("tsconfig.json").is_file() {
+        command.args(["--project", "."]);
+    } else {
+        command.args(DEFAULT_TSC_OPTIONS).arg("src/index.ts");
+    }
+
+    let output = process::run(
+        workspace.confine(&mut command),
+        RunLimits {
+            deadline: Some(Instant::now() + EXECUTION_TIMEOUT),
+            idle_timeout: None,
+            cancel: None,
+        },
+        &mut |_, _| {},
+    )?;
+    if output.timed_out {
+        return Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "tsc did not finish within {} seconds",
+                EXECUTION_TIMEOUT.as_secs()
+            ),
+        ));
+    }
+
+    // tsc reports diagnostics on stdout and exits with a failure status when there are
+    // any, so only a failure without diagnostics means tsc itself didn't work
+    let diagnostics = parse_tsc(&String::from_utf8_lossy(&output.stdout));
+    if diagnostics.is_empty() && !output.status.success() {
+        return Err(io::Error::other(format!(
+            "tsc failed:\n{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    Ok(diagnostics)
+}
+
+fn parse_tsc(output: &str) -> Vec<LintDiagnostic> {
+    let mut diagnostics: Vec<LintDiagnostic> = Vec::new();
+    for line in output.lines() {
+        if let Some(captures) = TSC_DIAGNOSTIC.captures(line) {
+            let number = |index| captures.get(index).and_then(|n| n.as_str().parse().ok());
+            diagnostics.push(LintDiagnostic {
+                file: captures.get(1).map(|file| file.as_str().to_string()),
+                line: number(2),
+                column: number(3),
+                severity: captures[4].to_string(),
+                code: captures[5].to_string(),
+                message: captures[6].to_string(),
+            });
+        } else if let Some(diagnostic) = diagnostics.last_mut() {
+            // Indented lines continue the previous message, e.g. the chain of reasons
+            // two types are incompatible
+            if line.starts_with(' ') {
+                diagnostic.message.push('\n');
+                diagnostic.message.push_str(line.trim());
+            }
+        }
+    }
+    diagnostics
+}
+
+fn failure(status: StatusCode, message: String, started: Instant) -> LintReply {
+    let response = LintResponse {
+        success: false,
+        diagnostics: Vec::new(),
+        error: Some(message),
+        duration_ms: elapsed_ms(started),
+    };
+    (status, Json(response))
+}
@@ -1,25 +1,160 @@
 use axum::{
-    extract::State,
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{DefaultBodyLimit, Path, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::Response,
     routing::{get, post},
     Json, Router,
 };
+use archive::Archive;
+use retry::RetryPolicy;
+use relay::ValidatorRelay;
+use sandbox::{Sandbox, SandboxMode, SandboxNetwork};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use command_policy::CommandShims;
+use cargo::{looks_like_compile_error, BuildProgress, BuildReport, BuildTotal, Diagnostic};
+use cgroup::JobCgroup;
+use process::{CancelOnDrop, Cancellation, ProcessOutput, RunLimits, Stream};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::{env, fs::File, io::Write, process::{Command, Stdio}, time::Duration};
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{self, File},
+    io::{self, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    process::Command,
+    sync::{Arc, LazyLock},
+    time::{Duration, Instant},
+};
 use thiserror::Error;
-use tower_http::cors::{Any, CorsLayer};
-use tokio::time::timeout;
+use single_flight::SingleFlight;
+use solana::Validator;
+use versions::ToolVersions;
+use extract::JobJson;
+use guardrails::ClientId;
+use deadline::RequestDeadline;
+use determinism::Determinism;
+use health::HealthCache;
+use payer::PayerSessions;
+use job_queue::{JobQueue, QueueWait};
+use metrics::Metrics;
+use negotiate::ResponseFormat;
+use workspace::{ActiveWorkspaces, Workspace};
+use tower_http::{
+    compression::CompressionLayer,
+    cors::{Any, CorsLayer},
+    decompression::RequestDecompressionLayer,
+};
+use tokio::{
+    task::JoinError,
+    time::{error::Elapsed, timeout},
+};
+
+mod batch;
+mod archive;
+mod capabilities;
+mod cargo;
+mod cgroup;
+mod command_policy;
+mod deadline;
+mod determinism;
+mod extract;
+mod format;
+mod guardrails;
+mod health;
+mod janitor;
+mod job_queue;
+mod lint;
+mod matrix;
+mod metrics;
+mod negotiate;
+mod npm;
+mod payer;
+mod process;
+mod process_limit;
+mod relay;
+mod retry;
+mod sandbox;
+mod sbf;
+mod session;
+mod single_flight;
+mod solana;
+mod versions;
+mod workspace;
 
 // App state containing both templates' directories
 #[derive(Clone)]
 struct AppState {
     template_rs: String,
     template_ts: String,
+    // Where TypeScript workspaces with extra dependencies are installed and cached
+    ts_deps_cache: String,
+    ts_deps_cache_max: usize,
+    // Starter code read from the templates before any request overwrites it
+    starter_rs: Arc<Option<TemplateFiles>>,
+    starter_ts: Arc<Option<TemplateFiles>>,
+    // Tool and dependency versions detected at startup
+    versions: Arc<ToolVersions>,
+    // Identical compile requests that opted into coalescing and are still running
+    single_flight: Arc<SingleFlight<JobReply>>,
+    // Limits how many jobs build and run at the same time; the rest wait their turn
+    job_queue: Arc<JobQueue>,
+    // Most jobs a single /batch request may contain
+    max_batch_size: usize,
+    // Private workspaces currently in use, which the janitor must leave alone
+    active_workspaces: ActiveWorkspaces,
+    metrics: Arc<Metrics>,
+    // Programs that print nothing for this long are considered done and killed
+    idle_timeout: Option<Duration>,
+    // RPC client for the helper endpoints that talk to the validator directly
+    validator: Arc<Validator>,
+    // Languages turned off with ENABLE_RUST/ENABLE_TYPESCRIPT, whose requests are
+    // answered with a 403 and whose own routes aren't registered
+    rust_enabled: bool,
+    typescript_enabled: bool,
+    // Why each language's requests are answered with a 503, when its template or
+    // tools failed the startup checks
+    rust_unavailable: Option<&'static str>,
+    typescript_unavailable: Option<&'static str>,
+    // Template for on-chain programs built by /rust/build-sbf, which is optional
+    template_sbf: String,
+    sbf_unavailable: Option<&'static str>,
+    // Held for the duration of an SBF build, since builds share the template
+    sbf_build_lock: Arc<tokio::sync::Mutex<()>>,
+    // The per-version target directories of /rust/matrix builds
+    matrix_targets: Arc<matrix::MatrixTargets>,
+    // Where finished runs are recorded, when ARCHIVE_DIR is set
+    archive: Option<Arc<Archive>>,
+    // How runs that opted in are retried after transient validator errors
+    retry_policy: Arc<RetryPolicy>,
+    // Units in a build of the Rust template, for the progress of session builds
+    build_total: Arc<BuildTotal>,
+    // The last successful /health check, reused for a while
+    health: Arc<HealthCache>,
+    // Output frames a WebSocket run may have queued before the program is held up
+    stream_buffer: usize,
+    // Pins the clock and seeds of programs that ask for deterministic runs
+    determinism: Arc<Determinism>,
+    // Funded fee payers created with POST /session
+    payers: Arc<PayerSessions>,
+}
+
+fn main() {
+    let archive_admin_token = env::var("ARCHIVE_ADMIN_TOKEN").ok().filter(|token| !token.is_empty());
+    // Every build and program inherits the server's environment, and with the token any
+    // of them could read the whole archive. Removing it is only sound while no other
+    // thread could be reading the environment, so it happens before the runtime starts.
+    env::remove_var("ARCHIVE_ADMIN_TOKEN");
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(serve(archive_admin_token));
 }
 
-#[tokio::main]
-async fn main() {
+async fn serve(archive_admin_token: Option<String>) {
     // Get configuration from environment variables
     let host = env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
     let port = env::var("PORT").unwrap_or_else(|_| "3000".to_string());
@@ -27,51 +162,501 @@ async fn main() {
         env::var("TEMPLATE_RS").unwrap_or_else(|_| "/app/template-rs".to_string());
     let template_ts =
         env::var("TEMPLATE_TS").unwrap_or_else(|_| "/app/template-ts".to_string());
+    let template_sbf =
+        env::var("TEMPLATE_SBF").unwrap_or_else(|_| "/app/template-sbf".to_string());
+    // Off unless set: a program can be quiet for good reason, e.g. while it waits for a
+    // transaction to be confirmed. 0 disables it as well.
+    let idle_timeout_secs: u64 = env::var("IDLE_TIMEOUT_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(0);
+    let solana_url =
+        env::var("SOLANA_URL").unwrap_or_else(|_| "http://solana-validator:8899".to_string());
+    // 2 SOL, the same cap public devnet/testnet faucets use
+    let max_airdrop_lamports: u64 = env::var("MAX_AIRDROP_LAMPORTS")
+        .ok()
+        .and_then(|lamports| lamports.parse().ok())
+        .unwrap_or(2_000_000_000);
+    let max_concurrent_jobs: usize = env::var("MAX_CONCURRENT_JOBS")
+        .ok()
+        .and_then(|jobs| jobs.parse().ok())
+        .filter(|&jobs| jobs > 0)
+        .unwrap_or(4);
+    let max_batch_size: usize = env::var("MAX_BATCH_SIZE")
+        .ok()
+        .and_then(|jobs| jobs.parse().ok())
+        .unwrap_or(16);
+    let janitor_interval_secs: u64 = env::var("JANITOR_INTERVAL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .filter(|&secs| secs > 0)
+        .unwrap_or(300);
+    let workspace_max_age_secs: u64 = env::var("WORKSPACE_MAX_AGE_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(3600);
+    let stream_buffer: usize = env::var("STREAM_BUFFER_LINES")
+        .ok()
+        .and_then(|frames| frames.parse().ok())
+        .filter(|&frames| frames > 0)
+        .unwrap_or(256);
+    let health_cache_secs: u64 = env::var("HEALTH_CACHE_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(10);
+    // 1 SOL
+    let payer_session_lamports: u64 = env::var("PAYER_SESSION_LAMPORTS")
+        .ok()
+        .and_then(|lamports| lamports.parse().ok())
+        .unwrap_or(1_000_000_000);
+    let payer_session_ttl_secs: u64 = env::var("PAYER_SESSION_TTL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .filter(|&secs| secs > 0)
+        .unwrap_or(1800);
+    // Unset means 10 per hour; 0 leaves session creation unlimited
+    let payer_sessions_per_hour: Option<u32> = env::var("PAYER_SESSIONS_PER_HOUR")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .or(Some(10))
+        .filter(|&max| max > 0);
+    // Each version's build directory is about the size of a full SDK build
+    let matrix_cached_versions: usize = env::var("MATRIX_CACHED_VERSIONS")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(4);
+    let archive_dir = env::var("ARCHIVE_DIR").ok();
+    // 100 MiB
+    let archive_max_bytes: u64 = env::var("ARCHIVE_MAX_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse().ok())
+        .unwrap_or(100 * 1024 * 1024);
+    let validator_retry_max: u32 = env::var("VALIDATOR_RETRY_MAX")
+        .ok()
+        .and_then(|retries| retries.parse().ok())
+        .unwrap_or(3);
+    let validator_retry_base_ms: u64 = env::var("VALIDATOR_RETRY_BASE_MS")
+        .ok()
+        .and_then(|ms| ms.parse().ok())
+        .unwrap_or(500);
+    let transient_patterns = env::var("VALIDATOR_TRANSIENT_PATTERNS")
+        .unwrap_or_else(|_| retry::DEFAULT_TRANSIENT_PATTERNS.to_string());
+    // Deployments that only offer one language can turn the other one off
+    let rust_enabled = !env::var("ENABLE_RUST").is_ok_and(|enable| enable == "false");
+    let typescript_enabled = !env::var("ENABLE_TYPESCRIPT").is_ok_and(|enable| enable == "false");
+    let sandbox_mode = env::var("SANDBOX")
+        .ok()
+        .and_then(|mode| SandboxMode::parse(&mode))
+        .unwrap_or(SandboxMode::Auto);
+    // "shared" (the default), "none" or "validator"
+    let sandbox_network = env::var("SANDBOX_NETWORK").unwrap_or_else(|_| "shared".to_string());
+    // Unset (or 0) leaves the number of processes unlimited
+    let max_processes: Option<u64> = env::var("MAX_PROCESSES")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .filter(|&max| max > 0);
+    // Unset (or 0) leaves the memory of programs unlimited
+    let max_memory_mb: Option<u64> = env::var("MAX_MEMORY_MB")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .filter(|&max| max > 0);
+    let restrict_commands = env::var("RESTRICT_COMMANDS").is_ok_and(|restrict| restrict == "true");
+    let allowed_commands: Vec<String> = env::var("ALLOWED_COMMANDS")
+        .unwrap_or_else(|_| command_policy::DEFAULT_ALLOWED_COMMANDS.to_string())
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect();
+    let ts_deps_cache = env::var("TS_DEPS_CACHE_DIR").unwrap_or_else(|_| {
+        env::temp_dir()
+            .join("playground-ts-deps")
+            .to_string_lossy()
+            .to_string()
+    });
+    // Most dependency sets kept installed; the least recently used ones go first
+    let ts_deps_cache_max: usize = env::var("TS_DEPS_CACHE_MAX")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(32);
     
     println!("Starting Solana Playground service");
     println!("Configuration:");
     println!("  Host: {}", host);
     println!("  Port: {}", port);
+    println!(
+        "  Languages: Rust {}, TypeScript {}",
+        if rust_enabled { "enabled" } else { "disabled" },
+        if typescript_enabled { "enabled" } else { "disabled" }
+    );
     println!("  Template RS path: {}", template_rs);
     println!("  Template TS path: {}", template_ts);
-    println!("  Solana URL: {}", env::var("SOLANA_URL").unwrap_or_else(|_| "http://solana-validator:8899".to_string()));
+    println!("  Template SBF path: {}", template_sbf);
+    println!("  TypeScript dependency cache: {} (up to {} sets)", ts_deps_cache, ts_deps_cache_max);
+    match idle_timeout_secs {
+        0 => println!("  Idle timeout: disabled"),
+        secs => println!("  Idle timeout: {}s", secs),
+    }
+    println!("  Max concurrent jobs: {}", max_concurrent_jobs);
+    println!("  Max batch size: {}", max_batch_size);
+    println!("  Health check cache: {}s", health_cache_secs);
+    println!("  WebSocket output buffer: {} frames", stream_buffer);
+    println!(
+        "  Workspace janitor: every {}s, removes workspaces older than {}s",
+        janitor_interval_secs, workspace_max_age_secs
+    );
+    match &archive_dir {
+        Some(dir) => println!(
+            "  Archive: {} (up to {} bytes, admin token {})",
+            dir,
+            archive_max_bytes,
+            if archive_admin_token.is_some() { "set" } else { "not set" }
+        ),
+        None => println!("  Archive: disabled"),
+    }
+    println!(
+        "  Validator retries: up to {}, starting after {}ms",
+        validator_retry_max, validator_retry_base_ms
+    );
+    println!("  Max airdrop: {} lamports", max_airdrop_lamports);
+    println!(
+        "  Payer sessions: {} lamports each, expire after {}s unused, {} per client per hour",
+        payer_session_lamports,
+        payer_session_ttl_secs,
+        payer_sessions_per_hour.map_or("unlimited".to_string(), |max| max.to_string())
+    );
+    println!("  Solana URL: {}", solana_url);
     println!("  Solana WS URL: {}", env::var("SOLANA_WS_URL").unwrap_or_else(|_| "ws://solana-validator:8900".to_string()));
 
-    // Verify template directories exist
-    if !std::path::Path::new(&template_rs).exists() {
-        println!("WARNING: Rust template directory does not exist: {}", template_rs);
+    // Verify the templates are complete. In strict mode a broken template stops the
+    // server; otherwise its language is disabled and its requests get a 503. Languages
+    // that are turned off aren't checked, since they are never used.
+    let strict_templates = env::var("STRICT_TEMPLATES").is_ok_and(|strict| strict == "true");
+    let strict_tools = env::var("STRICT_TOOLS").is_ok_and(|strict| strict == "true");
+    let template_errors = [
+        ("Rust", rust_enabled, check_template(&template_rs, &["Cargo.toml", "src/main.rs"])),
+        ("TypeScript", typescript_enabled, check_template(&template_ts, &["package.json", "src/index.ts"])),
+    ];
+    for (language, enabled, error) in &template_errors {
+        let Err(error) = error else { continue };
+        if !enabled {
+            continue;
+        }
+        if strict_templates {
+            println!("ERROR: {} template is not usable: {}", language, error);
+            std::process::exit(1);
+        }
+        println!("WARNING: {} template is not usable, disabling {}: {}", language, language, error);
     }
-    if !std::path::Path::new(&template_ts).exists() {
-        println!("WARNING: TypeScript template directory does not exist: {}", template_ts);
+    let [(_, _, rust_template), (_, _, typescript_template)] = template_errors;
+    // Builds are locked to the template's Cargo.lock; without one each build resolves
+    // the dependencies itself, and may pick up newer versions from one run to the next
+    if rust_enabled && rust_template.is_ok() && !PathBuf::from(&template_rs).join("Cargo.lock").is_file() {
+        println!("NOTE: Rust template has no Cargo.lock, dependency versions are not frozen");
+    }
+    // Program builds are an optional extra, so a missing SBF template never stops startup
+    let sbf_template = check_template(&template_sbf, &["Cargo.toml"]);
+    if let (true, Err(error)) = (rust_enabled, &sbf_template) {
+        println!("NOTE: SBF template is not usable, disabling /rust/build-sbf: {}", error);
     }
 
-    // Create a CORS middleware
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any);
+    // Gather tool versions once; they can't change while the server is running. A tool
+    // without a version couldn't be run, which also makes this the check that the tools
+    // each language needs are installed, with the same strict and lenient modes.
+    let versions = ToolVersions::detect(&template_rs);
+    println!("  rustc: {}", versions.rustc.as_deref().unwrap_or("not found"));
+    println!("  node: {}", versions.node.as_deref().unwrap_or("not found"));
+    let tool_checks = [
+        ("Rust", rust_enabled, [("cargo", &versions.cargo)].as_slice()),
+        ("TypeScript", typescript_enabled, [("node", &versions.node), ("pnpm", &versions.pnpm)].as_slice()),
+    ]
+    .map(|(language, enabled, tools)| {
+        let missing: Vec<&str> = tools
+            .iter()
+            .filter(|(_, version)| version.is_none())
+            .map(|(tool, _)| *tool)
+            .collect();
+        if enabled && !missing.is_empty() {
+            let missing = missing.join(", ");
+            if strict_tools {
+                println!("ERROR: {} toolchain is not usable, not found on PATH: {}", language, missing);
+                std::process::exit(1);
+            }
+            println!("WARNING: {} toolchain is not usable, disabling {}: not found on PATH: {}", language, language, missing);
+        }
+        missing.is_empty()
+    });
+    let [rust_tools, typescript_tools] = tool_checks;
+    // Only needed by some programs, so they are never required
+    for (tool, version) in [("solana", &versions.solana), ("anchor", &versions.anchor)] {
+        if version.is_none() {
+            println!("NOTE: {} not found on PATH", tool);
+        }
+    }
+    let rust_unavailable = if rust_template.is_err() {
+        Some("Rust template not configured")
+    } else if !rust_tools {
+        Some("Rust toolchain not available on this server.")
+    } else {
+        None
+    };
+    let typescript_unavailable = if typescript_template.is_err() {
+        Some("TypeScript template not configured")
+    } else if !typescript_tools {
+        Some("TypeScript toolchain not available on this server.")
+    } else {
+        None
+    };
+    // SBF builds run through cargo too
+    let sbf_unavailable = if sbf_template.is_err() {
+        Some("SBF template not configured")
+    } else if !rust_tools {
+        Some("Rust toolchain not available on this server.")
+    } else {
+        None
+    };
+
+    // Give programs a PATH of shims, so they can only run the allowed commands by name.
+    // Failing to create the shims only disables the restriction, like the sandbox.
+    let command_shims = restrict_commands.then(|| {
+        let root = env::temp_dir().join("playground-command-shims");
+        match CommandShims::create(root, &allowed_commands) {
+            Ok(shims) => {
+                println!("  Allowed commands: {}", allowed_commands.join(", "));
+                if !shims.missing.is_empty() {
+                    println!("WARNING: Allowed commands not found on PATH: {}", shims.missing.join(", "));
+                }
+                Some(shims)
+            }
+            Err(err) => {
+                println!("WARNING: Cannot create command shims, commands are not restricted: {}", err);
+                None
+            }
+        }
+    }).flatten();
+    if !restrict_commands {
+        println!("  Command restrictions: disabled");
+    }
+
+    // Confine the commands that build and run user code. Without namespace support, e.g.
+    // in a container that lacks CAP_SYS_ADMIN, they run unconfined as before.
+    if sandbox_mode != SandboxMode::Off {
+        let private: Vec<PathBuf> = archive_dir.iter().chain([&ts_deps_cache]).map(PathBuf::from).collect();
+        let shared: Vec<PathBuf> = command_shims.iter().map(|shims| shims.root().to_path_buf()).collect();
+        let network = match sandbox_network.as_str() {
+            "none" => Ok(SandboxNetwork::Isolated),
+            "validator" => {
+                let solana_ws_url = env::var("SOLANA_WS_URL").unwrap_or_else(|_| "ws://solana-validator:8900".to_string());
+                ValidatorRelay::new(&[&solana_url, &solana_ws_url]).map(SandboxNetwork::Validator)
+            }
+            _ => Ok(SandboxNetwork::Shared),
+        };
+        let network_name = match &network {
+            Ok(SandboxNetwork::Isolated) => "isolated",
+            Ok(SandboxNetwork::Validator(_)) => "validator only",
+            _ => "shared",
+        };
+        match network.and_then(|network| Sandbox::new(&private, &shared, network).install()) {
+            Ok(()) => println!("  Sandbox: enabled (network {})", network_name),
+            Err(err) if sandbox_mode == SandboxMode::Required => {
+                println!("ERROR: Sandbox is required but cannot be set up: {}", err);
+                std::process::exit(1);
+            }
+            Err(err) => {
+                println!("WARNING: ************************************************************");
+                println!("WARNING: Sandbox cannot be set up, user code runs UNCONFINED: {}", err);
+                println!("WARNING: Run as root with CAP_SYS_ADMIN (or SANDBOX=off to silence this)");
+                println!("WARNING: ************************************************************");
+            }
+        }
+    } else {
+        println!("  Sandbox: disabled");
+    }
+
+    if let Some(shims) = command_shims {
+        shims.install();
+    }
+
+    // Hold running programs to the process and memory limits, each in a cgroup of its own
+    let job_cgroups = match (max_processes, max_memory_mb) {
+        (None, None) => Ok(()),
+        _ => cgroup::install(max_memory_mb.map(|max| max << 20), max_processes),
+    };
+    match (max_processes, &job_cgroups) {
+        (Some(max), Ok(())) => println!("  Max processes: {}", max),
+        (Some(max), Err(err)) => {
+            // Without job cgroups, fall back to RLIMIT_NPROC
+            println!(
+                "  Max processes: {} (RLIMIT_NPROC, job cgroups can't be created: {})",
+                max, err
+            );
+            // Sandboxed programs run as an unprivileged user, which the limit does hold
+            // SAFETY: geteuid has no preconditions
+            if unsafe { libc::geteuid() } == 0 && !sandbox::is_installed() {
+                println!("WARNING: MAX_PROCESSES has no effect while the server runs as root without job cgroups or a sandbox");
+            }
+            process_limit::install(max);
+        }
+        (None, _) => println!("  Max processes: unlimited"),
+    }
+    match (max_memory_mb, &job_cgroups) {
+        (Some(max), Ok(())) => println!("  Max memory: {} MB", max),
+        (Some(_), Err(err)) => println!(
+            "WARNING: MAX_MEMORY_MB has no effect, job cgroups can't be created: {}",
+            err
+        ),
+        (None, _) => println!("  Max memory: unlimited"),
+    }
+
+    let transient_patterns = Regex::new(&transient_patterns).unwrap_or_else(|err| {
+        println!("WARNING: VALIDATOR_TRANSIENT_PATTERNS is not a valid regex, using the default: {}", err);
+        Regex::new(retry::DEFAULT_TRANSIENT_PATTERNS).unwrap()
+    });
+    let retry_policy = RetryPolicy::new(
+        validator_retry_max,
+        Duration::from_millis(validator_retry_base_ms),
+        transient_patterns,
+    );
+
+    // A broken archive directory only disables archiving; runs themselves still work
+    let archive = archive_dir.and_then(|dir| {
+        match Archive::new(PathBuf::from(&dir), archive_max_bytes, archive_admin_token) {
+            Ok(archive) => Some(Arc::new(archive)),
+            Err(err) => {
+                println!("WARNING: Cannot use archive directory {}, disabling archiving: {}", dir, err);
+                None
+            }
+        }
+    });
+
+    // Snapshot the starter code now, since compile requests overwrite the entrypoints
+    let starter_rs = rust_enabled
+        .then(|| load_template_files("rust", &template_rs, "src/main.rs", "Cargo.toml"))
+        .flatten();
+    let starter_ts = typescript_enabled
+        .then(|| load_template_files("typescript", &template_ts, "src/index.ts", "package.json"))
+        .flatten();
+
+    let determinism = Determinism::detect();
+    match determinism.libfaketime() {
+        Some(path) => println!("  libfaketime: {}", path.display()),
+        None => println!("  libfaketime: not found, deterministic runs can't pin the clock"),
+    }
+
+    // Count the template's build units up front so the first build can report progress
+    let build_units = (rust_enabled && rust_unavailable.is_none())
+        .then(|| cargo::estimate_build_units(&template_rs))
+        .flatten();
+    match build_units {
+        Some(units) => println!("  Rust build units: {} (estimated)", units),
+        None => println!("  Rust build units: unknown until the first build"),
+    }
 
     // Simple app state
     let app_state = AppState {
         template_rs,
         template_ts,
+        ts_deps_cache,
+        ts_deps_cache_max,
+        starter_rs: Arc::new(starter_rs),
+        starter_ts: Arc::new(starter_ts),
+        versions: Arc::new(versions),
+        single_flight: Arc::new(SingleFlight::new()),
+        job_queue: Arc::new(JobQueue::new(max_concurrent_jobs)),
+        max_batch_size,
+        idle_timeout: (idle_timeout_secs > 0).then(|| Duration::from_secs(idle_timeout_secs)),
+        validator: Arc::new(Validator::new(solana_url, max_airdrop_lamports)),
+        active_workspaces: ActiveWorkspaces::default(),
+        metrics: Arc::new(Metrics::default()),
+        rust_enabled,
+        typescript_enabled,
+        rust_unavailable,
+        typescript_unavailable,
+        template_sbf,
+        sbf_unavailable,
+        sbf_build_lock: Arc::new(tokio::sync::Mutex::new(())),
+        matrix_targets: Arc::new(matrix::MatrixTargets::new(matrix_cached_versions)),
+        archive,
+        retry_policy: Arc::new(retry_policy),
+        build_total: Arc::new(BuildTotal::new(build_units)),
+        health: Arc::new(HealthCache::new(Duration::from_secs(health_cache_secs))),
+        stream_buffer,
+        determinism: Arc::new(determinism),
+        payers: Arc::new(PayerSessions::new(
+            Duration::from_secs(payer_session_ttl_secs),
+            payer_session_lamports,
+            payer_sessions_per_hour,
+        )),
     };
 
-    // Build our application with a route
-    let app = Router::new()
-        .route("/", get(hello))
-        .route("/health", get(health_check))
-        .route("/rust", post(compile_rust))
-        .route("/typescript", post(compile_typescript))
-        .layer(cors)
-        .with_state(app_state);
+    payer::spawn_expiry(app_state.payers.clone());
+    janitor::spawn(
+        app_state.active_workspaces.clone(),
+        app_state.metrics.clone(),
+        Duration::from_secs(janitor_interval_secs),
+        Duration::from_secs(workspace_max_age_secs),
+    );
 
     // Run the server
     let listener = tokio::net::TcpListener::bind(format!("{}:{}", host, port))
         .await
         .unwrap();
     println!("Listening on http://{}:{}", host, port);
-    axum::serve(listener, app).await.unwrap();
+    // Peer addresses identify clients in the guardrail events
+    axum::serve(listener, app(app_state).into_make_service_with_connect_info::<SocketAddr>())
+        .await
+        .unwrap();
+}
+
+// The routes and middleware of the server
+fn app(app_state: AppState) -> Router {
+    // Create a CORS middleware
+    let cors = CorsLayer::new()
+        .allow_origin(Any)
+        .allow_methods(Any)
+        .allow_headers(Any);
+
+    // Compress responses (gzip/brotli) when the client sends Accept-Encoding.
+    // The default predicate skips tiny bodies, images and `text/event-stream`,
+    // so streamed responses are never buffered by the compressor.
+    let compression = CompressionLayer::new();
+
+    // Inflate request bodies sent with `Content-Encoding: gzip`. The body limit is
+    // enforced by the extractors as they read the inflated body, so it caps the
+    // decompressed size and a small compressed body can't expand without bound.
+    let decompression = RequestDecompressionLayer::new();
+
+    // Build our application with a route
+    let mut app = Router::new()
+        .route("/", get(hello))
+        .route("/health", get(health::health_check))
+        .route("/versions", get(versions_handler))
+        .route("/capabilities", get(capabilities::capabilities_handler))
+        .route("/metrics", get(metrics::metrics_handler));
+    // A turned off language's own routes don't exist, so they answer 404
+    if app_state.rust_enabled {
+        app = app
+            .route("/rust", post(compile_rust))
+            .route("/rust/build-sbf", post(sbf::build_sbf_handler))
+            .route("/rust/format-check", post(format::format_check_handler))
+            .route("/rust/matrix", post(matrix::matrix_handler));
+    }
+    if app_state.typescript_enabled {
+        app = app
+            .route("/typescript", post(compile_typescript))
+            .route("/typescript/lint", post(lint::lint_handler));
+    }
+    app.route("/batch", post(batch::batch_handler))
+        .route("/ws", get(session::session_handler))
+        .route("/archive/:id", get(archive::archive_handler))
+        .route("/solana/airdrop", post(solana::airdrop_handler))
+        .route("/session", post(payer::create_session_handler))
+        .route("/templates", get(list_templates))
+        .route("/templates/:lang", get(get_template))
+        .layer(DefaultBodyLimit::max(MAX_REQUEST_BODY_BYTES))
+        .layer(decompression)
+        .layer(compression)
+        .layer(cors)
+        .with_state(app_state)
 }
 
 // Basic hello world handler to test the service is running
@@ -80,44 +665,343 @@ async fn hello() -> &'static str {
     "Hello, World! Welcome to the Solana Playground Service (Rust + TypeScript)"
 }
 
-// Health check endpoint for Docker integration
-async fn health_check() -> StatusCode {
-    println!("Health check request received");
-    // Verify critical components are working
-    let rust_working = std::process::Command::new("cargo")
-        .arg("--version")
-        .output()
-        .map(|output| output.status.success())
-        .unwrap_or(false);
-    
-    let ts_working = std::process::Command::new("pnpm")
-        .arg("--version")
-        .output()
-        .map(|output| output.status.success())
-        .unwrap_or(false);
-    
-    if rust_working && ts_working {
-        println!("Health check succeeded - Rust and TypeScript tools available");
-        StatusCode::OK
-    } else {
-        println!("Health check failed - Rust available: {}, TypeScript tools available: {}", 
-                 rust_working, ts_working);
-        StatusCode::SERVICE_UNAVAILABLE
+/// Report the toolchain and template dependency versions
+///
+/// Lets users compare the playground's environment with their local one, and lets
+/// the frontend show which versions snippets run against.
+async fn versions_handler(State(app_state): State<AppState>) -> Json<ToolVersions> {
+    println!("Received request to /versions");
+    Json(app_state.versions.as_ref().clone())
+}
+
+// Starter code and manifest of a language template
+#[derive(Serialize, Clone)]
+struct TemplateFiles {
+    language: &'static str,
+    // Path of the entrypoint relative to the template directory, e.g. "src/main.rs"
+    entrypoint: &'static str,
+    code: String,
+    // Path of the manifest relative to the template directory, e.g. "Cargo.toml"
+    manifest_path: &'static str,
+    manifest: String,
+}
+
+// Check that a template directory exists and contains the files jobs rely on
+fn check_template(template_dir: &str, files: &[&str]) -> Result<(), String> {
+    let dir = std::path::Path::new(template_dir);
+    if !dir.is_dir() {
+        return Err(format!("directory {} does not exist", template_dir));
+    }
+    match files.iter().find(|file| !dir.join(file).is_file()) {
+        Some(file) => Err(format!("{} is missing {}", template_dir, file)),
+        None => Ok(()),
+    }
+}
+
+// Read a template's entrypoint and manifest, warning instead of failing when missing
+fn load_template_files(
+    language: &'static str,
+    template_dir: &str,
+    entrypoint: &'static str,
+    manifest_path: &'static str,
+) -> Option<TemplateFiles> {
+    let read = |relative: &str| {
+        let path = format!("{}/{}", template_dir, relative);
+        fs::read_to_string(&path)
+            .map_err(|err| println!("WARNING: Could not read template file {}: {}", path, err))
+            .ok()
+    };
+
+    Some(TemplateFiles {
+        language,
+        entrypoint,
+        code: read(entrypoint)?,
+        manifest_path,
+        manifest: read(manifest_path)?,
+    })
+}
+
+// Starter templates for every language that has one
+#[derive(Serialize)]
+struct TemplatesResponse {
+    rust: Option<TemplateFiles>,
+    typescript: Option<TemplateFiles>,
+}
+
+/// List the starter templates
+///
+/// Returns the starter code and manifest of both the Rust and TypeScript
+/// templates, so clients don't have to hardcode their own copies.
+async fn list_templates(State(app_state): State<AppState>) -> Json<TemplatesResponse> {
+    println!("Received request to /templates");
+    Json(TemplatesResponse {
+        rust: app_state.starter_rs.as_ref().clone(),
+        typescript: app_state.starter_ts.as_ref().clone(),
+    })
+}
+
+/// Get the starter template for a single language (`rust` or `typescript`)
+async fn get_template(
+    State(app_state): State<AppState>,
+    Path(lang): Path<String>,
+) -> Result<Json<TemplateFiles>, (StatusCode, String)> {
+    println!("Received request to /templates/{}", lang);
+    let (template, enabled) = match lang.as_str() {
+        "rust" => (app_state.starter_rs.as_ref(), app_state.rust_enabled),
+        "typescript" => (app_state.starter_ts.as_ref(), app_state.typescript_enabled),
+        _ => return Err((StatusCode::NOT_FOUND, format!("Unknown language: {}", lang))),
+    };
+    if !enabled {
+        return Err((StatusCode::FORBIDDEN, format!("The {} language is disabled on this server", lang)));
     }
+
+    template.clone().map(Json).ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        format!("The {} template is not available on this server", lang),
+    ))
+}
+
+// Languages the playground can compile and run
+#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+enum Language {
+    Rust,
+    TypeScript,
 }
 
 // Request model for the compile endpoints
 #[derive(Deserialize)]
 struct CompileRequest {
     code: String,
+    // Command-line arguments passed to the Rust program's `main`
+    args: Option<Vec<String>>,
+    // Extra npm packages (name -> version) installed for a TypeScript program
+    dependencies: Option<BTreeMap<String, String>>,
+    // Extra environment variables the program runs with
+    env: Option<BTreeMap<String, String>>,
+    // File under `src/` the code is written to, instead of the template's entrypoint
+    entrypoint: Option<String>,
+    // cargo profile Rust code is built and run with
+    #[serde(default)]
+    profile: CargoProfile,
+    // Share the result with identical requests that are running at the same time.
+    // Only safe for code without side effects that must happen once per request
+    // (e.g. airdrops or transactions against the validator).
+    #[serde(default)]
+    coalesce: bool,
+    // Build Rust code with cargo's verbose output and return the build log
+    #[serde(default)]
+    verbose: bool,
+    // Return stdout as lossy UTF-8 (the default) or as base64 encoded raw bytes
+    #[serde(default)]
+    output_encoding: OutputEncoding,
+    // Only type check Rust code with rustc, without building or running it
+    #[serde(default)]
+    syntax_only: bool,
+    // Override the commitment level the code asks the validator for
+    commitment: Option<String>,
+    // Run the program again when it fails with a transient validator error. Only safe
+    // for code whose side effects may happen more than once.
+    #[serde(default)]
+    retry_on_transient: bool,
+    // Pin the program's clock and seeds, so that runs of the same code print the same
+    #[serde(default)]
+    deterministic: bool,
+    // Session from POST /session whose funded keypair the program gets
+    session_id: Option<String>,
+    // Return the code as it was written to disk, after the server's rewrites
+    #[serde(default)]
+    return_effective_source: bool,
+    // Let cargo resolve Rust dependencies instead of keeping them at the versions in
+    // the template's Cargo.lock
+    #[serde(default)]
+    update_deps: bool,
+    // When the client stops waiting, from the X-Request-Deadline header
+    #[serde(skip)]
+    deadline: Option<Instant>,
 }
 
+// Largest request body accepted, measured after decompression
+const MAX_REQUEST_BODY_BYTES: usize = 2 * 1024 * 1024;
+
+// How long a single compile-and-run job may take
+const EXECUTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Extra time the blocking task gets past the deadline to kill the process and report
+const TIMEOUT_GRACE: Duration = Duration::from_secs(5);
+
+// Limits on the program arguments a request may pass
+const MAX_ARGS: usize = 32;
+const MAX_ARGS_TOTAL_LEN: usize = 4096;
+
+// Limits on the environment variables a request may set, counting names and values
+const MAX_ENV_VARS: usize = 32;
+const MAX_ENV_TOTAL_LEN: usize = 8192;
+
+// Conventional environment variable names: upper case, digits and underscores
+static ENV_NAME: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[A-Z_][A-Z0-9_]*$").unwrap());
+
+// Variables a request may not set, because they change how the toolchain, the dynamic
+// loader or the runtime behave rather than configuring the program
+const DENIED_ENV_VARS: [&str; 10] = [
+    "PATH",
+    "HOME",
+    "SHELL",
+    "USER",
+    "TMPDIR",
+    "IFS",
+    "RUSTFLAGS",
+    "RUST_LOG",
+    "NODE_OPTIONS",
+    "NODE_PATH",
+];
+const DENIED_ENV_PREFIXES: [&str; 8] = [
+    "LD_", "DYLD_", "CARGO_", "RUSTC", "RUSTDOC", "RUSTUP_", "NPM_CONFIG_", "PNPM_",
+];
+
 // Response model for the compile endpoints
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 struct CompileResponse {
     success: bool,
     output: String,
     error: Option<String>,
+    // Total wall-clock time spent handling the job, in milliseconds
+    duration_ms: u64,
+    // Time spent in the compile and run phases, when they run as separate steps
+    compile_ms: Option<u64>,
+    run_ms: Option<u64>,
+    // The job was killed at the timeout; `output` holds what it printed until then
+    timed_out: bool,
+    // The program stopped printing for the idle timeout and was killed; `output`
+    // holds everything it printed, and the run fails with `idle_timeout`
+    idle_terminated: bool,
+    // cargo's build log, only returned for Rust requests that set `verbose`
+    build_log: Option<String>,
+    // cargo changed Cargo.lock during the build, because the request set `update_deps`
+    // or the lockfile no longer matched the manifest
+    lockfile_updated: bool,
+    // rustc's warnings for Rust code; always empty for TypeScript
+    warnings: Vec<Diagnostic>,
+    // The program's stdout wasn't valid UTF-8. Unless the request asked for base64,
+    // invalid bytes in `output` were replaced with U+FFFD.
+    binary_output: bool,
+    // How often the run was repeated because it failed with a transient validator
+    // error; only non-zero for requests that set `retry_on_transient`
+    retries_used: u32,
+    // Id under which the run was archived, when the server archives runs
+    archive_id: Option<String>,
+    // What kind of failure `error` describes, for clients that shouldn't parse it;
+    // null on success
+    error_kind: Option<ErrorKind>,
+    // Where the job stood in line if it had to wait for a slot; sent as headers
+    #[serde(skip)]
+    queue_wait: Option<QueueWait>,
+    // The request asked for a deterministic run and everything the server can pin was
+    // pinned; false when libfaketime isn't installed, since the clock then runs free
+    deterministic_applied: bool,
+    // The server changed the code before writing it to disk, by pointing validator URLs
+    // at the playground's validator or pinning the commitment
+    source_rewritten: bool,
+    // The code as written to disk; only returned for requests that set
+    // `return_effective_source`
+    effective_source: Option<String>,
+    // The guardrail that turned the request away, if one did; only for the metrics
+    #[serde(skip)]
+    rejected_by: Option<&'static str>,
+}
+
+// Stable categories of failed jobs
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ErrorKind {
+    // The code didn't compile
+    Compile,
+    // The program failed while running
+    Runtime,
+    // The job was killed at the execution timeout
+    Timeout,
+    // The program was killed for printing nothing for IDLE_TIMEOUT_SECS
+    IdleTimeout,
+    // The client's X-Request-Deadline passed before the job could start
+    DeadlineExceeded,
+    // The program was killed for using too much memory
+    Oom,
+    // The program was refused a process or thread over MAX_PROCESSES
+    ProcessLimit,
+    // The request exceeded a size limit
+    TooLarge,
+    // The request itself was invalid or couldn't be parsed
+    InvalidRequest,
+    // The validator turned the program's requests away for making too many
+    RateLimited,
+    // The program couldn't reach the validator
+    ValidatorUnreachable,
+    // Something went wrong on the server's side
+    Internal,
+}
+
+// Runtime errors that are really the validator failing to answer, or refusing to,
+// as opposed to the program itself failing
+static VALIDATOR_UNREACHABLE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)connection refused|connection reset|error sending request|failed to fetch|ECONNREFUSED")
+        .unwrap()
+});
+static RATE_LIMITED: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)429 too many requests").unwrap());
+
+// The cargo profiles a Rust request can choose between
+#[derive(Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum CargoProfile {
+    // Unoptimized with debug assertions, and quick to build
+    #[default]
+    Dev,
+    // Optimized, for code whose speed matters; takes considerably longer to build
+    Release,
+}
+
+impl CargoProfile {
+    // cargo arguments that select this profile
+    fn args(self) -> &'static [&'static str] {
+        match self {
+            CargoProfile::Dev => &[],
+            CargoProfile::Release => &["--release"],
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            CargoProfile::Dev => "dev",
+            CargoProfile::Release => "release",
+        }
+    }
+}
+
+// How the program's stdout is put into `output`
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum OutputEncoding {
+    // Decoded as UTF-8, replacing invalid bytes
+    #[default]
+    Utf8,
+    // The raw bytes, base64 encoded
+    Base64,
+}
+
+impl OutputEncoding {
+    fn encode(self, stdout: Vec<u8>) -> String {
+        match self {
+            OutputEncoding::Utf8 => String::from_utf8_lossy(&stdout).to_string(),
+            OutputEncoding::Base64 => BASE64_STANDARD.encode(stdout),
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            OutputEncoding::Utf8 => "utf8",
+            OutputEncoding::Base64 => "base64",
+        }
+    }
 }
 
 // Custom error type for compile operations
@@ -129,111 +1013,1022 @@ enum CompileError {
     Compile(String),
     #[error("Failed to run: {0}")]
     Run(String),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    // The request body couldn't be parsed into a request at all
+    #[error("Malformed request: {0}")]
+    MalformedRequest(String),
+    // The language can't be used on this server, e.g. its template is missing
+    #[error("Unavailable: {0}")]
+    Unavailable(String),
+    // The language is turned off on this server
+    #[error("Disabled: {0}")]
+    Disabled(String),
+    // The request body was larger than the server accepts
+    #[error("Request too large: {0}")]
+    TooLarge(String),
+    // The program was killed for running out of memory; carries its error output
+    #[error("Out of memory: {0}")]
+    OutOfMemory(String),
+    // The program couldn't start a process or thread over the limit; carries its error
+    // output
+    #[error("Process/thread limit exceeded: {0}")]
+    ProcessLimit(String),
+    // The server failed to handle the job, e.g. the task running it panicked
+    #[error("Internal error: {0}")]
+    Internal(String),
+    // The job was killed at the deadline; carries whatever it printed until then, and
+    // how long the job was given once `finish_job` knows it
+    #[error("Execution timed out")]
+    TimedOut {
+        output: String,
+        after: Option<Duration>,
+    },
+    // The client's deadline passed before the job got to run
+    #[error("Deadline exceeded: {0}")]
+    DeadlineExceeded(String),
+    // The program printed nothing for the idle timeout and was killed; carries whatever
+    // it printed until then
+    #[error("Idle timeout")]
+    IdleTimeout { output: String, after: Duration },
 }
 
-impl IntoResponse for CompileError {
-    fn into_response(self) -> axum::response::Response {
-        let (status, error_message) = match self {
-            CompileError::Io(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
-            CompileError::Compile(err) => (StatusCode::BAD_REQUEST, err),
-            CompileError::Run(err) => (StatusCode::BAD_REQUEST, err),
-        };
+impl CompileError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            CompileError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            CompileError::Compile(_) => StatusCode::BAD_REQUEST,
+            CompileError::Run(_) => StatusCode::BAD_REQUEST,
+            CompileError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            CompileError::MalformedRequest(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            CompileError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            CompileError::Disabled(_) => StatusCode::FORBIDDEN,
+            CompileError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            CompileError::OutOfMemory(_) => StatusCode::BAD_REQUEST,
+            CompileError::ProcessLimit(_) => StatusCode::BAD_REQUEST,
+            CompileError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            CompileError::TimedOut { .. } => StatusCode::BAD_REQUEST,
+            CompileError::DeadlineExceeded(_) => StatusCode::REQUEST_TIMEOUT,
+            CompileError::IdleTimeout { .. } => StatusCode::BAD_REQUEST,
+        }
+    }
 
-        let body = Json(CompileResponse {
-            success: false,
-            output: String::new(),
-            error: Some(error_message),
-        });
+    fn kind(&self) -> ErrorKind {
+        match self {
+            CompileError::Io(_) => ErrorKind::Internal,
+            CompileError::Compile(_) => ErrorKind::Compile,
+            CompileError::Run(output) if RATE_LIMITED.is_match(output) => ErrorKind::RateLimited,
+            CompileError::Run(output) if VALIDATOR_UNREACHABLE.is_match(output) => {
+                ErrorKind::ValidatorUnreachable
+            }
+            CompileError::Run(_) => ErrorKind::Runtime,
+            CompileError::InvalidRequest(_) => ErrorKind::InvalidRequest,
+            CompileError::MalformedRequest(_) => ErrorKind::InvalidRequest,
+            CompileError::Unavailable(_) => ErrorKind::Internal,
+            CompileError::Disabled(_) => ErrorKind::InvalidRequest,
+            CompileError::TooLarge(_) => ErrorKind::TooLarge,
+            CompileError::OutOfMemory(_) => ErrorKind::Oom,
+            CompileError::ProcessLimit(_) => ErrorKind::ProcessLimit,
+            CompileError::Internal(_) => ErrorKind::Internal,
+            CompileError::TimedOut { .. } => ErrorKind::Timeout,
+            CompileError::DeadlineExceeded(_) => ErrorKind::DeadlineExceeded,
+            CompileError::IdleTimeout { .. } => ErrorKind::IdleTimeout,
+        }
+    }
+
+    // Split into the error message and any output produced before the failure
+    fn into_parts(self) -> (String, String) {
+        match self {
+            CompileError::Io(err) => (err.to_string(), String::new()),
+            CompileError::Compile(err) => (err, String::new()),
+            CompileError::Run(err) => (err, String::new()),
+            CompileError::InvalidRequest(err) => (err, String::new()),
+            CompileError::MalformedRequest(err) => (err, String::new()),
+            CompileError::Unavailable(err) => (err, String::new()),
+            CompileError::Disabled(err) => (err, String::new()),
+            CompileError::TooLarge(err) => (err, String::new()),
+            CompileError::OutOfMemory(err) => (
+                format!("Out of memory: the program was killed for using more memory than this server allows\n\n{}", err),
+                String::new(),
+            ),
+            CompileError::ProcessLimit(err) => (
+                format!("Process/thread limit exceeded: the program tried to start more processes or threads than this server allows\n\n{}", err),
+                String::new(),
+            ),
+            CompileError::Internal(err) => (err, String::new()),
+            CompileError::TimedOut { output, after } => {
+                let after = match after {
+                    Some(after) if after.subsec_millis() == 0 => {
+                        format!(" after {} seconds", after.as_secs())
+                    }
+                    Some(after) => format!(" after {} ms", after.as_millis()),
+                    None => String::new(),
+                };
+                (
+                    format!("Execution timed out{}. Your code took too long to run.", after),
+                    output,
+                )
+            }
+            CompileError::DeadlineExceeded(err) => (err, String::new()),
+            CompileError::IdleTimeout { output, after } => (
+                format!(
+                    "The program was stopped after printing nothing for {} seconds",
+                    after.as_secs()
+                ),
+                output,
+            ),
+        }
+    }
+}
+
+// Details about how a job ran, collected inside the blocking task and reported
+// whether or not the job succeeded
+#[derive(Default)]
+struct JobReport {
+    // Time spent in each phase
+    compile_ms: Option<u64>,
+    run_ms: Option<u64>,
+    // The program was killed after going quiet for the idle timeout
+    idle_terminated: bool,
+    // What cargo printed while building, kept when the build was verbose
+    build_log: Option<String>,
+    // The build changed Cargo.lock
+    lockfile_updated: bool,
+    // Compiler warnings, reported whether or not the build succeeded
+    warnings: Vec<Diagnostic>,
+    // How often the run was repeated after a transient validator error
+    retries_used: u32,
+    // Units the Rust build consisted of, when it succeeded and cargo reported them
+    build_units: Option<u64>,
+}
+
+// The HTTP status and body a compile job responds with
+type JobReply = (StatusCode, Json<CompileResponse>);
+
+// What a blocking job hands back: the program output (or error) and its report
+type JobResult = (Result<Vec<u8>, CompileError>, JobReport);
+
+// The error for a process killed at the idle timeout `after`, keeping the output it
+// produced so far
+fn idle_timeout_error(process_output: &ProcessOutput, after: Option<Duration>) -> CompileError {
+    CompileError::IdleTimeout {
+        output: format!(
+            "{}{}",
+            String::from_utf8_lossy(&process_output.stdout),
+            String::from_utf8_lossy(&process_output.stderr)
+        ),
+        after: after.unwrap_or_default(),
+    }
+}
 
-        (status, body).into_response()
+// The timeout error for a killed process, keeping the output it produced so far
+fn timed_out_error(process_output: &ProcessOutput) -> CompileError {
+    CompileError::TimedOut {
+        output: format!(
+            "{}{}",
+            String::from_utf8_lossy(&process_output.stdout),
+            String::from_utf8_lossy(&process_output.stderr)
+        ),
+        after: None,
     }
 }
 
+fn elapsed_ms(start: Instant) -> u64 {
+    start.elapsed().as_millis() as u64
+}
+
+// Build the HTTP response for a finished, failed, or timed out job that was given
+// `timeout` to run
+fn finish_job(
+    task_result: Result<Result<JobResult, JoinError>, Elapsed>,
+    timeout: Duration,
+    encoding: OutputEncoding,
+    started: Instant,
+) -> JobReply {
+    let (result, report) = match task_result {
+        // Task completed within timeout
+        Ok(Ok(job_result)) => job_result,
+        Ok(Err(e)) => (
+            Err(CompileError::Internal(format!("Task panic: {}", e))),
+            JobReport::default(),
+        ),
+        // Task timed out
+        Err(_) => (
+            Err(CompileError::TimedOut {
+                output: String::new(),
+                after: None,
+            }),
+            JobReport::default(),
+        ),
+    };
+    // The processes only know their deadline, so the timeout is filled in here
+    let result = result.map_err(|error| match error {
+        CompileError::TimedOut { output, .. } => CompileError::TimedOut {
+            output,
+            after: Some(timeout),
+        },
+        error => error,
+    });
+
+    job_response(result, report, encoding, started)
+}
+
+// Build the HTTP response for a job's output or error
+fn job_response(
+    result: Result<Vec<u8>, CompileError>,
+    report: JobReport,
+    encoding: OutputEncoding,
+    started: Instant,
+) -> JobReply {
+    let timed_out = matches!(result, Err(CompileError::TimedOut { .. }));
+    let error_kind = result.as_ref().err().map(CompileError::kind);
+    let binary_output = matches!(&result, Ok(stdout) if std::str::from_utf8(stdout).is_err());
+    let (status, output, error) = match result {
+        Ok(stdout) => (StatusCode::OK, encoding.encode(stdout), None),
+        Err(error) => {
+            let status = error.status_code();
+            let (message, output) = error.into_parts();
+            (status, output, Some(message))
+        }
+    };
+
+    let body = Json(CompileResponse {
+        success: error.is_none(),
+        output,
+        error,
+        timed_out,
+        duration_ms: elapsed_ms(started),
+        compile_ms: report.compile_ms,
+        run_ms: report.run_ms,
+        idle_terminated: report.idle_terminated,
+        build_log: report.build_log,
+        lockfile_updated: report.lockfile_updated,
+        warnings: report.warnings,
+        binary_output,
+        retries_used: report.retries_used,
+        archive_id: None,
+        error_kind,
+        queue_wait: None,
+        deterministic_applied: false,
+        source_rewritten: false,
+        effective_source: None,
+        rejected_by: None,
+    });
+
+    (status, body)
+}
+
+// Respond to a request that the guardrail `rule` turned away, e.g. for setting an
+// environment variable on the denylist
+fn rejected(
+    rule: &'static str,
+    error: CompileError,
+    encoding: OutputEncoding,
+    started: Instant,
+) -> JobReply {
+    let (status, Json(mut response)) = job_response(Err(error), JobReport::default(), encoding, started);
+    response.rejected_by = Some(rule);
+    (status, Json(response))
+}
+
 /// Compile and run Rust code
 ///
 /// Handler that takes Rust code, writes it to main.rs, 
 /// compiles and runs it, then returns the output.
 async fn compile_rust(
     State(app_state): State<AppState>,
-    Json(request): Json<CompileRequest>,
-) -> Result<Json<CompileResponse>, CompileError> {
+    client: ClientId,
+    format: ResponseFormat,
+    RequestDeadline(deadline): RequestDeadline,
+    JobJson(mut request): JobJson<CompileRequest>,
+) -> Response {
     println!("Received Rust compilation request");
+    request.deadline = deadline;
+    let reply = rust_job(app_state.clone(), request, false).await;
+    guardrails::record_response(&app_state.metrics, &client, &reply.1);
+    negotiate::respond(format, with_queue_headers(reply))
+}
+
+// Tell the client where its job stood in line, if it had to wait for a slot
+fn with_queue_headers(reply: JobReply) -> (HeaderMap, JobReply) {
+    let mut headers = HeaderMap::new();
+    if let Some(wait) = reply.1.queue_wait {
+        headers.insert("x-queue-position", HeaderValue::from(wait.position));
+        if let Some(secs) = wait.estimated_wait_secs {
+            headers.insert("x-estimated-wait-seconds", HeaderValue::from(secs));
+        }
+    }
+    (headers, reply)
+}
+
+// Compile and run one Rust request. An `isolated` job builds in a private copy of the
+// template rather than the template itself, so it can run alongside other jobs. Jobs
+// only use the template in place when the job slots let no other job run meanwhile.
+async fn rust_job(app_state: AppState, request: CompileRequest, isolated: bool) -> JobReply {
+    let started = Instant::now();
+    let encoding = request.output_encoding;
+    if !app_state.rust_enabled {
+        let error = CompileError::Disabled("Rust is disabled on this server".to_string());
+        return job_response(Err(error), JobReport::default(), encoding, started);
+    }
+    if let Some(reason) = app_state.rust_unavailable {
+        let error = CompileError::Unavailable(reason.to_string());
+        return job_response(Err(error), JobReport::default(), encoding, started);
+    }
     // Get configuration and clone needed values for the blocking task
     let template_rs = app_state.template_rs.clone();
-    let code = request.code.clone();
+    let args = request.args.unwrap_or_default();
+    if let Err(error) = validate_args(&args) {
+        return rejected("args", error, encoding, started);
+    }
+    let mut env = request.env.unwrap_or_default();
+    if let Err(error) = validate_env(&env) {
+        return rejected("env", error, encoding, started);
+    }
+    // Added after the checks, since they set variables requests may not
+    let deterministic_applied = request.deterministic && app_state.determinism.pin(&mut env);
+    let payer = match app_state.payers.lookup(request.session_id.as_deref()) {
+        Ok(payer) => payer,
+        Err(error) => {
+            let error = CompileError::InvalidRequest(error);
+            return job_response(Err(error), JobReport::default(), encoding, started);
+        }
+    };
+    // The keypair may only be written into a private copy of the template, never into
+    // the shared template where other jobs could read it. With more than one job slot,
+    // jobs building in the template at the same time would overwrite each other's code.
+    let isolated = isolated || payer.is_some() || app_state.job_queue.capacity() > 1;
+    let entrypoint = match RustEntrypoint::parse(request.entrypoint.as_deref()) {
+        Ok(entrypoint) => entrypoint,
+        Err(error) => return rejected("entrypoint", error, encoding, started),
+    };
+    let code = match pin_commitment(Language::Rust, &request.code, request.commitment.as_deref()) {
+        Ok(code) => code,
+        Err(error) => return job_response(Err(error), JobReport::default(), encoding, started),
+    };
+    let verbose = request.verbose;
+    let profile = request.profile;
+    let update_deps = request.update_deps;
+    let syntax_only = request.syntax_only;
+    let retry_on_transient = request.retry_on_transient;
+    // A coalesced job runs to the first request's deadline, so requests that bring their
+    // own always run on their own
+    let coalesce_key = (request.coalesce && request.deadline.is_none()).then(|| {
+        single_flight::job_key(&[
+            "rust",
+            &code,
+            &entrypoint.path(),
+            // Arguments can't contain NUL bytes, so joining on NUL is unambiguous
+            &args.join("\0"),
+            &serde_json::to_string(&env).unwrap_or_default(),
+            request.session_id.as_deref().unwrap_or_default(),
+            &request.return_effective_source.to_string(),
+            &verbose.to_string(),
+            profile.as_str(),
+            &update_deps.to_string(),
+            encoding.as_str(),
+            &syntax_only.to_string(),
+            &retry_on_transient.to_string(),
+        ])
+    });
+    let job_queue = app_state.job_queue.clone();
+    let idle_timeout = app_state.idle_timeout;
+    let active_workspaces = app_state.active_workspaces.clone();
+    let archive = app_state.archive.clone();
+    let retry_policy = app_state.retry_policy.clone();
+    let build_total = app_state.build_total.clone();
+    let archived_source = archive.is_some().then(|| (code.clone(), args.clone()));
+    // What is written to disk, with validator URLs pointed at the playground's validator.
+    // Syntax checks never talk to the validator, so they check the code as it is.
+    let effective_source = if syntax_only {
+        code
+    } else {
+        rewrite_validator_urls(&code)
+    };
+    let source_rewritten = effective_source != request.code;
+    let returned_source = request.return_effective_source.then(|| effective_source.clone());
     
+    let client_deadline = request.deadline;
+    let job = async move {
+        // Wait for a free slot; the timeout only starts once the job is running
+        let (slot, budget) = match deadline::acquire_slot(&job_queue, client_deadline).await {
+            Ok(acquired) => acquired,
+            Err(error) => return job_response(Err(error), JobReport::default(), encoding, started),
+        };
+
+        // If the client disconnects, this future is dropped and the guard kills
+        // whatever the blocking task is running, rather than letting it finish unseen
+        let cancel = Arc::new(Cancellation::default());
+        let guard = CancelOnDrop::new(cancel.clone());
+
+        // Processes are killed at the deadline so their partial output can be returned;
+        // the task timeout is only a backstop in case the job gets stuck anyway
+        let limits = RunLimits {
+            deadline: Some(Instant::now() + budget),
+            idle_timeout,
+            cancel: Some(cancel),
+        };
+        let task_timeout = budget + TIMEOUT_GRACE;
+
+        // Move the blocking operations to a separate thread with timeout
+        let timed_task = timeout(task_timeout, tokio::task::spawn_blocking(move || {
+            let mut report = JobReport::default();
+            if syntax_only {
+                let result = check_rust_syntax(&effective_source, &entrypoint, &mut report, limits);
+                return (result, report);
+            }
+
+            // Another entrypoint would add a file to the shared template, where concurrent
+            // jobs would build it too, and updated dependencies would rewrite its
+            // Cargo.lock, so both always get a private copy
+            let workspace = if isolated || entrypoint != RustEntrypoint::Main || update_deps {
+                Workspace::rust_copy(&template_rs, &active_workspaces)
+            } else {
+                Ok(Workspace::in_place(template_rs))
+            };
+            // Other entrypoints are only added for this job; the template keeps main.rs alone
+            let workspace = workspace.map(|workspace| match entrypoint {
+                RustEntrypoint::Main => workspace,
+                _ => {
+                    let file = workspace.dir.join(entrypoint.path());
+                    workspace.owning(file)
+                }
+            });
+            // So is the session's keypair
+            let workspace = workspace.and_then(|workspace| match &payer {
+                Some(payer) => payer.inject(workspace, &mut env),
+                None => Ok(workspace),
+            });
+            let mut retries_used = 0;
+            let result = workspace.map_err(CompileError::from).and_then(|workspace| {
+                let mut attempt = || {
+                    run_rust(
+                        &workspace,
+                        &effective_source,
+                        &ProgramInput {
+                            entrypoint: &entrypoint,
+                            profile,
+                            update_deps,
+                            active_workspaces: &active_workspaces,
+                            args: &args,
+                            env: &env,
+                        },
+                        verbose,
+                        &mut report,
+                        limits.clone(),
+                        &mut |_| {},
+                    )
+                };
+                if retry_on_transient {
+                    retry_policy.run(&mut retries_used, &limits, attempt)
+                } else {
+                    attempt()
+                }
+            });
+            report.retries_used = retries_used;
+            if let Some(units) = report.build_units {
+                build_total.set(units);
+            }
+            (result, report)
+        }));
+
+        let mut reply = finish_job(timed_task.await, budget, encoding, started);
+        reply.1.queue_wait = slot.wait;
+        reply.1.deterministic_applied = deterministic_applied;
+        reply.1.source_rewritten = source_rewritten;
+        reply.1.effective_source = returned_source;
+        guard.disarm();
+        if let (Some(archive), Some((code, args))) = (&archive, &archived_source) {
+            archive.record(Language::Rust, code, args, &BTreeMap::new(), &mut reply.1);
+        }
+        reply
+    };
+
+    match coalesce_key {
+        Some(key) => app_state.single_flight.run(key, job).await,
+        None => job.await,
+    }
+}
+
+// Point code written against a local validator at the playground's validator
+fn rewrite_validator_urls(code: &str) -> String {
     // Replace localhost/127.0.0.1:8899 with solana-validator URL
     let solana_validator_url = std::env::var("SOLANA_URL")
         .unwrap_or_else(|_| "http://solana-validator:8899".to_string());
-    
+
     // Get WebSocket URL from environment variable
     let solana_ws_url = std::env::var("SOLANA_WS_URL")
         .unwrap_or_else(|_| "ws://solana-validator:8900".to_string());
-    
-    // Set a timeout for the blocking task (30 seconds max)
-    let task_timeout = Duration::from_secs(30);
-    
-    // Move the blocking operations to a separate thread with timeout
-    let timed_task = timeout(task_timeout, tokio::task::spawn_blocking(move || {
-        // Path to the main.rs file in the playground
-        let main_rs_path = format!("{}/src/main.rs", template_rs);
-        
-        // Replace URLs in the code
-        let code_with_replaced_url = code
-            .replace("http://127.0.0.1:8899", &solana_validator_url)
-            .replace("ws://127.0.0.1:8900", &solana_ws_url);
-
-        // Update the main.rs file with the provided code
-        let mut main_file = File::create(main_rs_path)?;
-        write!(main_file, "{}", code_with_replaced_url)?;
-
-        // Run the code
-        let run_output = Command::new("cargo")
-            .current_dir(&template_rs)
-            .args(["run", "--verbose"])
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .output()?;
-        
-        let stdout = String::from_utf8_lossy(&run_output.stdout).to_string();
-        let stderr = String::from_utf8_lossy(&run_output.stderr).to_string();
-
-        if !run_output.status.success() {
-            // Check if this is a compilation error
-            if stderr.contains("error[E") || 
-               stderr.contains("could not compile") ||
-               stderr.contains("error: aborting due to") {
-                return Err(CompileError::Compile(stderr));
-            }
-            
-            // For runtime errors
-            Err(CompileError::Run(stderr))
-        } else {
-            // Success - return the program output
-            Ok(CompileResponse {
-                success: true,
-                output: stdout,
-                error: None,
-            })
-        }
-    }));
-    
-    // Handle timeout and task result
-    match timed_task.await {
-        Ok(task_result) => {
-            // Task completed within timeout
-            match task_result {
-                Ok(result) => match result {
-                    Ok(response) => Ok(Json(response)),
-                    Err(error) => Err(error),
-                },
-                Err(e) => Err(CompileError::Run(format!("Task panic: {}", e))),
-            }
-        },
-        Err(_) => {
-            // Task timed out
-            Err(CompileError::Run("Execution timed out after 30 seconds. Your code took too long to run.".to_string()))
+
+    code.replace("http://127.0.0.1:8899", &solana_validator_url)
+        .replace("ws://127.0.0.1:8900", &solana_ws_url)
+}
+
+// Commitment levels a request may pin the code to
+const COMMITMENTS: [&str; 3] = ["processed", "confirmed", "finalized"];
+
+// The commitment settings `pin_commitment` knows how to override: the template's
+// `CommitmentConfig::confirmed()` for Rust and `commitment: "confirmed"` options for
+// TypeScript
+static RUST_COMMITMENT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"CommitmentConfig::(processed|confirmed|finalized)\(\)").unwrap()
+});
+static TS_COMMITMENT: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"commitment:\s*(["'])(processed|confirmed|finalized)["']"#).unwrap()
+});
+
+// Rewrite every recognised commitment setting in the code to `commitment`. Code that
+// sets its commitment some other way is left as it is.
+fn pin_commitment(
+    language: Language,
+    code: &str,
+    commitment: Option<&str>,
+) -> Result<String, CompileError> {
+    let Some(commitment) = commitment else {
+        return Ok(code.to_string());
+    };
+    if !COMMITMENTS.contains(&commitment) {
+        return Err(CompileError::InvalidRequest(format!(
+            "commitment must be one of: {}",
+            COMMITMENTS.join(", ")
+        )));
+    }
+
+    let code = match language {
+        Language::Rust => RUST_COMMITMENT
+            .replace_all(code, format!("CommitmentConfig::{}()", commitment)),
+        Language::TypeScript => TS_COMMITMENT
+            .replace_all(code, format!("commitment: ${{1}}{}${{1}}", commitment)),
+    };
+    Ok(code.to_string())
+}
+
+// Check that environment variables have conventional names, aren't on the denylist
+// and are within the size limits
+fn validate_env(env: &BTreeMap<String, String>) -> Result<(), CompileError> {
+    if env.len() > MAX_ENV_VARS {
+        return Err(CompileError::InvalidRequest(format!(
+            "Too many environment variables: {} (maximum is {})",
+            env.len(),
+            MAX_ENV_VARS
+        )));
+    }
+
+    let total_len: usize = env.iter().map(|(name, value)| name.len() + value.len()).sum();
+    if total_len > MAX_ENV_TOTAL_LEN {
+        return Err(CompileError::InvalidRequest(format!(
+            "Environment variables are too long: {} bytes in total (maximum is {})",
+            total_len, MAX_ENV_TOTAL_LEN
+        )));
+    }
+
+    for (name, value) in env {
+        if !ENV_NAME.is_match(name) {
+            return Err(CompileError::InvalidRequest(format!(
+                "Invalid environment variable name: {:?} (must match [A-Z_][A-Z0-9_]*)",
+                name
+            )));
         }
+        if DENIED_ENV_VARS.contains(&name.as_str())
+            || DENIED_ENV_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+        {
+            return Err(CompileError::InvalidRequest(format!(
+                "Environment variable {} may not be set",
+                name
+            )));
+        }
+        if value.contains('\0') {
+            return Err(CompileError::InvalidRequest(format!(
+                "Environment variable {} contains a NUL byte",
+                name
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// Check that program arguments are single-line, NUL-free and within the size limits
+fn validate_args(args: &[String]) -> Result<(), CompileError> {
+    if args.len() > MAX_ARGS {
+        return Err(CompileError::InvalidRequest(format!(
+            "Too many arguments: {} (maximum is {})",
+            args.len(),
+            MAX_ARGS
+        )));
+    }
+
+    let total_len: usize = args.iter().map(|arg| arg.len()).sum();
+    if total_len > MAX_ARGS_TOTAL_LEN {
+        return Err(CompileError::InvalidRequest(format!(
+            "Arguments are too long: {} bytes in total (maximum is {})",
+            total_len, MAX_ARGS_TOTAL_LEN
+        )));
+    }
+
+    for (index, arg) in args.iter().enumerate() {
+        if arg.contains('\0') {
+            return Err(CompileError::InvalidRequest(format!(
+                "Argument {} contains a NUL byte",
+                index
+            )));
+        }
+        if arg.contains('\n') || arg.contains('\r') {
+            return Err(CompileError::InvalidRequest(format!(
+                "Argument {} must be a single line",
+                index
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// Path segments allowed in an entrypoint: no hidden files, and no `..` or `.`
+static ENTRYPOINT_SEGMENT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[A-Za-z0-9_][A-Za-z0-9_.-]*$").unwrap());
+
+// Most directories an entrypoint may be nested in below `src/`
+const MAX_ENTRYPOINT_DEPTH: usize = 4;
+
+// Check that an entrypoint is a relative path inside `src/` with the given extension,
+// so the code can't be written anywhere outside the workspace's sources
+fn validate_entrypoint(entrypoint: &str, extension: &str) -> Result<(), CompileError> {
+    let invalid = |reason: &str| {
+        CompileError::InvalidRequest(format!("Invalid entrypoint {:?}: {}", entrypoint, reason))
+    };
+    let Some(relative) = entrypoint.strip_prefix("src/") else {
+        return Err(invalid("must be a path inside src/"));
+    };
+    let segments: Vec<&str> = relative.split('/').collect();
+    if segments.len() > MAX_ENTRYPOINT_DEPTH + 1 {
+        return Err(invalid("too deeply nested"));
+    }
+    if segments
+        .iter()
+        .any(|segment| !ENTRYPOINT_SEGMENT.is_match(segment) || segment.contains(".."))
+    {
+        return Err(invalid(
+            "path segments may only contain letters, digits, '_', '-' and '.'",
+        ));
+    }
+    if !entrypoint.ends_with(extension) {
+        return Err(invalid(&format!("must be a {} file", extension)));
+    }
+    Ok(())
+}
+
+// Which target of the Rust template the code is, chosen by its entrypoint
+#[derive(Clone, PartialEq, Eq)]
+enum RustEntrypoint {
+    // `src/main.rs`, the template's own binary
+    Main,
+    // `src/lib.rs`, built as a library and not run
+    Lib,
+    // `src/bin/<name>.rs`, another binary
+    Bin(String),
+}
+
+impl RustEntrypoint {
+    fn parse(entrypoint: Option<&str>) -> Result<Self, CompileError> {
+        let Some(entrypoint) = entrypoint else {
+            return Ok(RustEntrypoint::Main);
+        };
+        validate_entrypoint(entrypoint, ".rs")?;
+        let name = entrypoint
+            .strip_prefix("src/bin/")
+            .and_then(|file| file.strip_suffix(".rs"))
+            .filter(|name| !name.contains('/'));
+        match (entrypoint, name) {
+            ("src/main.rs", _) => Ok(RustEntrypoint::Main),
+            ("src/lib.rs", _) => Ok(RustEntrypoint::Lib),
+            (_, Some(name)) => Ok(RustEntrypoint::Bin(name.to_string())),
+            _ => Err(CompileError::InvalidRequest(format!(
+                "Invalid entrypoint {:?}: Rust code must go in src/main.rs, src/lib.rs or src/bin/<name>.rs",
+                entrypoint
+            ))),
+        }
+    }
+
+    fn path(&self) -> String {
+        match self {
+            RustEntrypoint::Main => "src/main.rs".to_string(),
+            RustEntrypoint::Lib => "src/lib.rs".to_string(),
+            RustEntrypoint::Bin(name) => format!("src/bin/{}.rs", name),
+        }
+    }
+
+    // cargo arguments that select this target
+    fn target_args(&self) -> Vec<String> {
+        match self {
+            RustEntrypoint::Main => Vec::new(),
+            RustEntrypoint::Lib => vec!["--lib".to_string()],
+            RustEntrypoint::Bin(name) => vec!["--bin".to_string(), name.clone()],
+        }
+    }
+}
+
+// The TypeScript template's entrypoint, which its `start` script runs
+const TS_ENTRYPOINT: &str = "src/index.ts";
+
+// Where a program's code goes, how it is built and what it is started with
+struct ProgramInput<'a> {
+    // Which target the code is; only supported for Rust
+    entrypoint: &'a RustEntrypoint,
+    // The dev profile unless the request asked for release
+    profile: CargoProfile,
+    // Run `cargo update` and build without `--locked`, so dependencies move to their
+    // newest compatible versions
+    update_deps: bool,
+    // Where a private copy of a shared workspace is registered, for builds that have to
+    // change Cargo.lock
+    active_workspaces: &'a ActiveWorkspaces,
+    // Command-line arguments; only supported for Rust
+    args: &'a [String],
+    // Extra environment variables, already checked by `validate_env`
+    env: &'a BTreeMap<String, String>,
+}
+
+// Something a Rust job reports while it is still running
+enum JobEvent<'a> {
+    // A chunk of output: cargo's log while building, then the program's output
+    Output(Stream, &'a [u8]),
+    // Another unit of the build finished; carries how many have so far
+    BuildProgress(u64),
+}
+
+// Write the code into the Rust workspace, then build and run it as two timed steps.
+//
+// Output and build progress are handed to `on_event` as they happen (cargo's stdout is
+// JSON, so only its log is passed on as output), and both steps are killed once the
+// deadline passes. The idle timeout only applies to the run: a build can legitimately
+// stay quiet for long.
+//
+// cargo is kept quiet unless `verbose` is set, so a successful run only shows what
+// the program itself printed.
+fn run_rust(
+    workspace: &Workspace,
+    code: &str,
+    input: &ProgramInput,
+    verbose: bool,
+    report: &mut JobReport,
+    limits: RunLimits,
+    on_event: &mut dyn FnMut(JobEvent),
+) -> Result<Vec<u8>, CompileError> {
+    write_entrypoint(workspace, input.entrypoint, code)?;
+
+    let verbosity = if verbose { "--verbose" } else { "--quiet" };
+    let target = input.entrypoint.target_args();
+
+    // Dependencies stay at the versions in Cargo.lock unless the request asked otherwise
+    let mut locked: &[&str] = if input.update_deps { &[] } else { &["--locked"] };
+    let lockfile = workspace.dir.join("Cargo.lock");
+    let lockfile_before = fs::read(&lockfile).ok();
+
+    // Compile the code, asking cargo for machine-readable diagnostics on stdout
+    let compile_start = Instant::now();
+    let mut build = |workspace: &Workspace, locked: &[&str]| {
+        let mut progress = BuildProgress::default();
+        process::run(
+            workspace.confine(
+                Command::new("cargo")
+                    .current_dir(&workspace.dir)
+                    .envs(workspace.envs.iter().cloned())
+                    .args(["build", verbosity, "--message-format=json"])
+                    .args(locked)
+                    .args(input.profile.args())
+                    .args(&target),
+            ),
+            RunLimits {
+                idle_timeout: None,
+                ..limits.clone()
+            },
+            &mut |stream, bytes| match stream {
+                Stream::Stderr => on_event(JobEvent::Output(stream, bytes)),
+                Stream::Stdout => {
+                    if progress.feed(bytes) {
+                        on_event(JobEvent::BuildProgress(progress.compiled));
+                    }
+                }
+            },
+        )
+    };
+    if input.update_deps {
+        update_dependencies(workspace, limits.clone())?;
+    }
+    let mut build_output = build(workspace, locked)?;
+    // The lockfile doesn't cover the manifest, e.g. the template gained a dependency
+    // after it was made, so let cargo resolve rather than failing the job. It does so
+    // in a private copy, so the shared template's Cargo.lock is never rewritten.
+    let private_copy;
+    let mut workspace = workspace;
+    if !build_output.status.success()
+        && String::from_utf8_lossy(&build_output.stderr).contains("--locked was passed")
+    {
+        if !workspace.is_private() {
+            let template = workspace.dir.to_string_lossy();
+            private_copy = Workspace::rust_copy(&template, input.active_workspaces)?;
+            workspace = &private_copy;
+            write_entrypoint(workspace, input.entrypoint, code)?;
+        }
+        locked = &[];
+        build_output = build(workspace, locked)?;
+    }
+    report.compile_ms = Some(elapsed_ms(compile_start));
+    report.lockfile_updated = fs::read(workspace.dir.join("Cargo.lock")).ok() != lockfile_before;
+    if verbose {
+        report.build_log = Some(String::from_utf8_lossy(&build_output.stderr).to_string());
+    }
+
+    if build_output.timed_out {
+        // stdout is cargo's JSON stream; the build log on stderr is what's useful here
+        return Err(CompileError::TimedOut {
+            output: String::from_utf8_lossy(&build_output.stderr).to_string(),
+            after: None,
+        });
+    }
+    let mut build_report = BuildReport::parse(&String::from_utf8_lossy(&build_output.stdout));
+    report.warnings = std::mem::take(&mut build_report.warnings);
+    if !build_output.status.success() {
+        let stderr = String::from_utf8_lossy(&build_output.stderr).to_string();
+        return Err(classify_build_failure(build_report, stderr));
+    }
+    // Other targets build a different set of units than the template's binary
+    if build_report.json_available && *input.entrypoint == RustEntrypoint::Main {
+        report.build_units = Some(build_report.units);
+    }
+    // A library has nothing to run, so a successful build is all there is to it
+    if *input.entrypoint == RustEntrypoint::Lib {
+        return Ok(Vec::new());
+    }
+
+    // Run the freshly built binary directly rather than through `cargo run`, which
+    // would need to write the target directory the program only gets to read
+    let Some(executable) = build_report.executable else {
+        return Err(CompileError::Internal(
+            "cargo didn't report the binary it built".to_string(),
+        ));
+    };
+    let idle_timeout = limits.idle_timeout;
+    let cgroup = JobCgroup::new()?;
+    let run_start = Instant::now();
+    let run_output = process::run(
+        process_limit::cap(workspace.confine_run(
+            cgroup.enter(command_policy::restrict(
+                Command::new(executable)
+                    .current_dir(&workspace.dir)
+                    .envs(workspace.envs.iter().cloned())
+                    .envs(input.env)
+                    .args(input.args),
+            )),
+            false,
+        )),
+        limits,
+        &mut |stream, bytes| on_event(JobEvent::Output(stream, bytes)),
+    )?;
+    report.run_ms = Some(elapsed_ms(run_start));
+
+    if run_output.timed_out {
+        return Err(timed_out_error(&run_output));
+    }
+    if run_output.idle_terminated {
+        report.idle_terminated = true;
+        return Err(idle_timeout_error(&run_output, idle_timeout));
+    }
+
+    let stderr = String::from_utf8_lossy(&run_output.stderr).to_string();
+
+    if !run_output.status.success() {
+        if cgroup.hit_process_limit() || process_limit::hit_limit(&stderr) {
+            return Err(CompileError::ProcessLimit(stderr));
+        }
+        if cgroup.out_of_memory() {
+            return Err(CompileError::OutOfMemory(stderr));
+        }
+        // The build already succeeded, so this is a runtime error
+        return Err(CompileError::Run(stderr));
+    }
+
+    // Success - return the program output
+    Ok(run_output.stdout)
+}
+
+// Write the code to the entrypoint (main.rs unless the request chose another)
+fn write_entrypoint(
+    workspace: &Workspace,
+    entrypoint: &RustEntrypoint,
+    code: &str,
+) -> io::Result<()> {
+    let entrypoint_path = workspace.dir.join(entrypoint.path());
+    if let Some(parent) = entrypoint_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(entrypoint_path, code)
+}
+
+// Move the workspace's dependencies to the newest versions the manifest allows
+fn update_dependencies(workspace: &Workspace, limits: RunLimits) -> Result<(), CompileError> {
+    let output = process::run(
+        workspace.confine(
+            Command::new("cargo")
+                .current_dir(&workspace.dir)
+                .envs(workspace.envs.iter().cloned())
+                .args(["update", "--quiet"]),
+        ),
+        RunLimits {
+            idle_timeout: None,
+            ..limits
+        },
+        &mut |_, _| {},
+    )?;
+    if output.timed_out {
+        return Err(timed_out_error(&output));
+    }
+    if !output.status.success() {
+        return Err(CompileError::Compile(format!(
+            "Failed to update dependencies:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    Ok(())
+}
+
+// Type check the code with rustc alone, without cargo or the template's dependencies.
+// Much faster than a build, but code that uses external crates fails to resolve them.
+// Nothing is run, so on success the output is empty.
+fn check_rust_syntax(
+    code: &str,
+    entrypoint: &RustEntrypoint,
+    report: &mut JobReport,
+    limits: RunLimits,
+) -> Result<Vec<u8>, CompileError> {
+    // Laid out like the template so diagnostics point at e.g. `src/main.rs` as usual
+    let dir = tempfile::Builder::new().prefix("playground-check-").tempdir()?;
+    let path = entrypoint.path();
+    let file = dir.path().join(&path);
+    if let Some(parent) = file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(file, code)?;
+    let crate_type = match entrypoint {
+        RustEntrypoint::Lib => "lib",
+        _ => "bin",
+    };
+
+    let check_start = Instant::now();
+    let output = process::run(
+        sandbox::confine(
+            Command::new("rustc")
+                .args(["--edition", "2021", "--crate-name", "playground"])
+                .args(["--crate-type", crate_type])
+                .args(["--emit=metadata", "-o", "/dev/null", "--error-format=json"])
+                .arg(&path)
+                .current_dir(dir.path()),
+            &[dir.path()],
+            &[],
+        ),
+        limits,
+        &mut |_, _| {},
+    )?;
+    report.compile_ms = Some(elapsed_ms(check_start));
+
+    if output.timed_out {
+        return Err(timed_out_error(&output));
+    }
+    let mut build_report = BuildReport::parse_rustc(&String::from_utf8_lossy(&output.stderr));
+    report.warnings = std::mem::take(&mut build_report.warnings);
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        let message = if build_report.json_available {
+            build_report.rendered
+        } else {
+            stderr
+        };
+        return Err(CompileError::Compile(message));
+    }
+
+    Ok(Vec::new())
+}
+
+// Decide why a failed `cargo build` failed. rustc's JSON diagnostics are authoritative;
+// the substring heuristic is only used when cargo produced no JSON at all.
+fn classify_build_failure(report: BuildReport, stderr: String) -> CompileError {
+    let compile_error = if report.json_available {
+        report.has_errors
+    } else {
+        looks_like_compile_error(&stderr)
+    };
+
+    // JSON mode keeps diagnostics off stderr, so put them back, ahead of whatever
+    // cargo itself printed (its summary line, or the whole log when verbose)
+    let message = format!("{}{}", report.rendered, stderr);
+
+    if compile_error {
+        CompileError::Compile(message)
+    } else {
+        // cargo itself failed without rustc reporting an error, e.g. a build script
+        // or dependency download failed
+        CompileError::Run(message)
     }
 }
 
@@ -243,84 +2038,792 @@ async fn compile_rust(
 /// runs it with esrun (from @digitak/esrun), then returns the output.
 async fn compile_typescript(
     State(app_state): State<AppState>,
-    Json(request): Json<CompileRequest>,
-) -> Result<Json<CompileResponse>, CompileError> {
+    client: ClientId,
+    format: ResponseFormat,
+    RequestDeadline(deadline): RequestDeadline,
+    JobJson(mut request): JobJson<CompileRequest>,
+) -> Response {
     println!("Received TypeScript compilation request");
+    request.deadline = deadline;
+    let reply = typescript_job(app_state.clone(), request, false).await;
+    guardrails::record_response(&app_state.metrics, &client, &reply.1);
+    negotiate::respond(format, with_queue_headers(reply))
+}
+
+// Run one TypeScript request; `isolated` works the same as for `rust_job`
+async fn typescript_job(app_state: AppState, request: CompileRequest, isolated: bool) -> JobReply {
+    let started = Instant::now();
+    let encoding = request.output_encoding;
+    if !app_state.typescript_enabled {
+        let error = CompileError::Disabled("TypeScript is disabled on this server".to_string());
+        return job_response(Err(error), JobReport::default(), encoding, started);
+    }
+    if let Some(reason) = app_state.typescript_unavailable {
+        let error = CompileError::Unavailable(reason.to_string());
+        return job_response(Err(error), JobReport::default(), encoding, started);
+    }
     // Get configuration and clone needed values for the blocking task
     let template_ts = app_state.template_ts.clone();
-    let code = request.code.clone();
-    
-    // Replace localhost/127.0.0.1:8899 with solana-validator URL
-    let solana_validator_url = std::env::var("SOLANA_URL")
-        .unwrap_or_else(|_| "http://solana-validator:8899".to_string());
-    
-    // Get WebSocket URL from environment variable
-    let solana_ws_url = std::env::var("SOLANA_WS_URL")
-        .unwrap_or_else(|_| "ws://solana-validator:8900".to_string());
-    
-    // Set a timeout for the blocking task (30 seconds max)
-    let task_timeout = Duration::from_secs(30);
+    let ts_deps_cache = app_state.ts_deps_cache.clone();
+    let ts_deps_cache_max = app_state.ts_deps_cache_max;
+    let dependencies = request.dependencies.unwrap_or_default();
+    if let Err(error) = npm::validate_dependencies(&dependencies) {
+        return rejected("dependencies", error, encoding, started);
+    }
+    let mut env = request.env.unwrap_or_default();
+    if let Err(error) = validate_env(&env) {
+        return rejected("env", error, encoding, started);
+    }
+    // Added after the checks, since they set variables requests may not
+    let deterministic_applied = request.deterministic && app_state.determinism.pin(&mut env);
+    let payer = match app_state.payers.lookup(request.session_id.as_deref()) {
+        Ok(payer) => payer,
+        Err(error) => {
+            let error = CompileError::InvalidRequest(error);
+            return job_response(Err(error), JobReport::default(), encoding, started);
+        }
+    };
+    // The keypair may only be written into a private copy of the template, never into
+    // the shared template where other jobs could read it, and jobs that may run at the
+    // same time can't share it either
+    let isolated = isolated || payer.is_some() || app_state.job_queue.capacity() > 1;
+    let entrypoint = match request.entrypoint {
+        Some(entrypoint) => match validate_entrypoint(&entrypoint, ".ts") {
+            Ok(()) => entrypoint,
+            Err(error) => return rejected("entrypoint", error, encoding, started),
+        },
+        None => TS_ENTRYPOINT.to_string(),
+    };
+    let code = match pin_commitment(
+        Language::TypeScript,
+        &request.code,
+        request.commitment.as_deref(),
+    ) {
+        Ok(code) => code,
+        Err(error) => return job_response(Err(error), JobReport::default(), encoding, started),
+    };
+    let retry_on_transient = request.retry_on_transient;
+    // A coalesced job runs to the first request's deadline, so requests that bring their
+    // own always run on their own
+    let coalesce_key = (request.coalesce && request.deadline.is_none()).then(|| {
+        let dependencies = serde_json::to_string(&dependencies).unwrap_or_default();
+        single_flight::job_key(&[
+            "typescript",
+            &code,
+            &entrypoint,
+            &dependencies,
+            &serde_json::to_string(&env).unwrap_or_default(),
+            request.session_id.as_deref().unwrap_or_default(),
+            &request.return_effective_source.to_string(),
+            encoding.as_str(),
+            &retry_on_transient.to_string(),
+        ])
+    });
+    let job_queue = app_state.job_queue.clone();
+    let idle_timeout = app_state.idle_timeout;
+    let active_workspaces = app_state.active_workspaces.clone();
+    let archive = app_state.archive.clone();
+    let retry_policy = app_state.retry_policy.clone();
+    let archived_source = archive.is_some().then(|| (code.clone(), dependencies.clone()));
+    // What is written to disk, with validator URLs pointed at the playground's validator
+    let effective_source = rewrite_validator_urls(&code);
+    let source_rewritten = effective_source != request.code;
+    let returned_source = request.return_effective_source.then(|| effective_source.clone());
     
-    // Move the blocking operations to a separate thread with timeout
-    let timed_task = timeout(task_timeout, tokio::task::spawn_blocking(move || {
-        // Path to the index.ts file in the TypeScript template
-        let index_ts_path = format!("{}/src/index.ts", template_ts);
-        
-        // Replace URLs in the code
-        let code_with_replaced_url = code
-            .replace("http://127.0.0.1:8899", &solana_validator_url)
-            .replace("ws://127.0.0.1:8900", &solana_ws_url);
-
-        // Update the index.ts file with the provided code
-        let mut index_file = File::create(index_ts_path)?;
-        write!(index_file, "{}", code_with_replaced_url)?;
-
-        // Run the TypeScript code using esrun with pnpm
-        let run_output = Command::new("pnpm")
-            .current_dir(&template_ts)
-            .args(["run", "start"])
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .output()?;
-        
-        let stdout = String::from_utf8_lossy(&run_output.stdout).to_string();
-        let stderr = String::from_utf8_lossy(&run_output.stderr).to_string();
-
-        if !run_output.status.success() {
-            // For TypeScript, compilation and runtime errors are both handled by esrun
-            if stderr.contains("TypeScript error") || 
-               stderr.contains("TypeError") ||
-               stderr.contains("SyntaxError") {
-                return Err(CompileError::Compile(stderr));
+    let client_deadline = request.deadline;
+    let job = async move {
+        // Wait for a free slot; the timeout only starts once the job is running
+        let (slot, budget) = match deadline::acquire_slot(&job_queue, client_deadline).await {
+            Ok(acquired) => acquired,
+            Err(error) => return job_response(Err(error), JobReport::default(), encoding, started),
+        };
+
+        // Kill the job's processes if the client disconnects, as for Rust jobs
+        let cancel = Arc::new(Cancellation::default());
+        let guard = CancelOnDrop::new(cancel.clone());
+
+        // Processes are killed at the deadline so their partial output can be returned;
+        // the task timeout is only a backstop in case the job gets stuck anyway
+        let deadline = Instant::now() + budget;
+        let limits = RunLimits {
+            deadline: Some(deadline),
+            idle_timeout,
+            cancel: Some(cancel),
+        };
+        let task_timeout = budget + TIMEOUT_GRACE;
+
+        // Move the blocking operations to a separate thread with timeout
+        let timed_task = timeout(task_timeout, tokio::task::spawn_blocking(move || {
+            // Extra dependencies run in their own cached copy of the template
+            let template = if dependencies.is_empty() {
+                Ok(PathBuf::from(template_ts))
+            } else {
+                npm::prepare_workspace(
+                    &template_ts,
+                    &ts_deps_cache,
+                    ts_deps_cache_max,
+                    &dependencies,
+                    limits.clone(),
+                )
+            };
+            let workspace = template.and_then(|template| {
+                if isolated {
+                    Ok(Workspace::typescript_copy(template, &active_workspaces)?)
+                } else {
+                    Ok(Workspace::in_place(template))
+                }
+            });
+            // As for Rust, another entrypoint only exists for the duration of the job
+            let workspace = workspace.map(|workspace| {
+                if entrypoint == TS_ENTRYPOINT {
+                    workspace
+                } else {
+                    let file = workspace.dir.join(&entrypoint);
+                    workspace.owning(file)
+                }
+            });
+            let workspace = workspace.and_then(|workspace| match &payer {
+                Some(payer) => Ok(payer.inject(workspace, &mut env)?),
+                None => Ok(workspace),
+            });
+
+            // esrun compiles and runs in a single step, so there are no phase timings
+            let mut report = JobReport::default();
+            let mut retries_used = 0;
+            let result = workspace.and_then(|workspace| {
+                let mut attempt = || {
+                    run_typescript(
+                        &workspace,
+                        &effective_source,
+                        &entrypoint,
+                        &env,
+                        &mut report,
+                        limits.clone(),
+                        &mut |_, _| {},
+                    )
+                };
+                if retry_on_transient {
+                    retry_policy.run(&mut retries_used, &limits, attempt)
+                } else {
+                    attempt()
+                }
+            });
+            report.retries_used = retries_used;
+            (result, report)
+        }));
+
+        let mut reply = finish_job(timed_task.await, budget, encoding, started);
+        reply.1.queue_wait = slot.wait;
+        reply.1.deterministic_applied = deterministic_applied;
+        reply.1.source_rewritten = source_rewritten;
+        reply.1.effective_source = returned_source;
+        guard.disarm();
+        if let (Some(archive), Some((code, dependencies))) = (&archive, &archived_source) {
+            archive.record(Language::TypeScript, code, &[], dependencies, &mut reply.1);
+        }
+        reply
+    };
+
+    match coalesce_key {
+        Some(key) => app_state.single_flight.run(key, job).await,
+        None => job.await,
+    }
+}
+
+// Write the code into the TypeScript workspace and run it with esrun, streaming
+// output to `on_output` and killing the run once it hits the deadline or goes idle.
+// The template's own entrypoint is run through its `start` script, any other file
+// with esrun directly.
+fn run_typescript(
+    workspace: &Workspace,
+    code: &str,
+    entrypoint: &str,
+    env: &BTreeMap<String, String>,
+    report: &mut JobReport,
+    limits: RunLimits,
+    on_output: &mut dyn FnMut(Stream, &[u8]),
+) -> Result<Vec<u8>, CompileError> {
+    // Path to the entrypoint (index.ts unless the request chose another) in the template
+    let entrypoint_path = workspace.dir.join(entrypoint);
+    if let Some(parent) = entrypoint_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Update the entrypoint with the provided code
+    let mut entrypoint_file = File::create(entrypoint_path)?;
+    write!(entrypoint_file, "{}", code)?;
+    let script: &[&str] = if entrypoint == TS_ENTRYPOINT {
+        &["run", "start"]
+    } else {
+        &["exec", "esrun", entrypoint]
+    };
+
+    // Run the TypeScript code using esrun with pnpm
+    let idle_timeout = limits.idle_timeout;
+    let cgroup = JobCgroup::new()?;
+    let run_output = process::run(
+        process_limit::cap(workspace.confine_run(
+            cgroup.enter(command_policy::restrict(
+                Command::new("pnpm")
+                    .current_dir(&workspace.dir)
+                    .envs(workspace.envs.iter().cloned())
+                    .envs(env)
+                    .args(script),
+            )),
+            true,
+        )),
+        limits,
+        on_output,
+    )?;
+
+    if run_output.timed_out {
+        return Err(timed_out_error(&run_output));
+    }
+    if run_output.idle_terminated {
+        report.idle_terminated = true;
+        return Err(idle_timeout_error(&run_output, idle_timeout));
+    }
+
+    let stderr = String::from_utf8_lossy(&run_output.stderr).to_string();
+
+    if !run_output.status.success() {
+        if cgroup.hit_process_limit() {
+            return Err(CompileError::ProcessLimit(stderr));
+        }
+        if cgroup.out_of_memory() {
+            return Err(CompileError::OutOfMemory(stderr));
+        }
+        // For TypeScript, compilation and runtime errors are both handled by esrun
+        return Err(classify_ts_error(stderr));
+    }
+
+    // Success - return the program output
+    Ok(run_output.stdout)
+}
+
+// TypeScript diagnostic codes, e.g. `TS2304` in "error TS2304: Cannot find name 'foo'"
+static TS_DIAGNOSTIC_CODE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\bTS\d{4,5}\b").unwrap());
+
+// Decide whether esrun failed before the program started (compile) or while it ran
+fn classify_ts_error(stderr: String) -> CompileError {
+    if process_limit::hit_limit(&stderr) {
+        return CompileError::ProcessLimit(stderr);
+    }
+    let is_compile_error = TS_DIAGNOSTIC_CODE.is_match(&stderr)
+        || stderr.contains("TypeScript error")
+        // Module resolution failures, from esbuild or from Node's ESM loader
+        || stderr.contains("Cannot find module")
+        || stderr.contains("Cannot find package")
+        || stderr.contains("Could not resolve")
+        || stderr.contains("ERR_MODULE_NOT_FOUND")
+        // esbuild parse and transform failures, e.g. `✘ [ERROR] Expected ";" but found "x"`
+        || stderr.contains("[ERROR]")
+        || stderr.contains("Transform failed")
+        || stderr.contains("Build failed")
+        || stderr.contains("SyntaxError");
+
+    if is_compile_error {
+        CompileError::Compile(stderr)
+    } else {
+        // Anything else, including a `TypeError` thrown by running code, is a runtime error
+        CompileError::Run(stderr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Request},
+    };
+    use std::io::{Read, Write};
+    use tower::Service;
+
+    // A Rust template without dependencies, so it builds offline and quickly
+    fn rust_template() -> tempfile::TempDir {
+        let template = tempfile::tempdir().unwrap();
+        fs::write(
+            template.path().join("Cargo.toml"),
+            "[package]\nname = \"template\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[dependencies]\n",
+        )
+        .unwrap();
+        fs::create_dir(template.path().join("src")).unwrap();
+        fs::write(template.path().join("src/main.rs"), "fn main() {}\n").unwrap();
+        let lockfile = Command::new("cargo")
+            .current_dir(template.path())
+            .args(["generate-lockfile", "--offline"])
+            .status()
+            .unwrap();
+        assert!(lockfile.success());
+        template
+    }
+
+    // Server state for Rust requests against `template_rs`, with TypeScript turned off
+    fn test_state(template_rs: &std::path::Path) -> AppState {
+        let template_rs = template_rs.to_string_lossy().to_string();
+        let transient = Regex::new(retry::DEFAULT_TRANSIENT_PATTERNS).unwrap();
+        AppState {
+            versions: Arc::new(ToolVersions::detect(&template_rs)),
+            template_rs,
+            template_ts: String::new(),
+            ts_deps_cache: String::new(),
+            ts_deps_cache_max: 1,
+            starter_rs: Arc::new(None),
+            starter_ts: Arc::new(None),
+            single_flight: Arc::new(SingleFlight::new()),
+            job_queue: Arc::new(JobQueue::new(4)),
+            max_batch_size: 16,
+            idle_timeout: None,
+            validator: Arc::new(Validator::new("http://127.0.0.1:1".to_string(), 0)),
+            active_workspaces: ActiveWorkspaces::default(),
+            metrics: Arc::new(Metrics::default()),
+            rust_enabled: true,
+            typescript_enabled: false,
+            rust_unavailable: None,
+            typescript_unavailable: None,
+            template_sbf: String::new(),
+            sbf_unavailable: Some("SBF template not configured"),
+            sbf_build_lock: Arc::new(tokio::sync::Mutex::new(())),
+            matrix_targets: Arc::new(matrix::MatrixTargets::new(1)),
+            archive: None,
+            retry_policy: Arc::new(RetryPolicy::new(0, Duration::ZERO, transient)),
+            build_total: Arc::new(BuildTotal::new(None)),
+            health: Arc::new(HealthCache::new(Duration::ZERO)),
+            stream_buffer: 16,
+            determinism: Arc::new(Determinism::detect()),
+            payers: Arc::new(PayerSessions::new(Duration::from_secs(60), 0, None)),
+        }
+    }
+
+    // POST `body` to `uri` with the given extra headers
+    async fn post_json(
+        app_state: AppState,
+        uri: &str,
+        headers: &[(&str, &str)],
+        body: impl Into<Body>,
+    ) -> Response {
+        let mut request = Request::post(uri).header(header::CONTENT_TYPE, "application/json");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        // The router is always ready, so it can be called without polling it first
+        app(app_state)
+            .call(request.body(body.into()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn body_bytes(response: Response) -> Vec<u8> {
+        to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap()
+            .to_vec()
+    }
+
+    // Run `request` on /rust against `template`, returning the status and the response
+    async fn run_rust_request(
+        template: &tempfile::TempDir,
+        request: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value) {
+        let response =
+            post_json(test_state(template.path()), "/rust", &[], request.to_string()).await;
+        let status = response.status();
+        let body = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        (status, body)
+    }
+
+    #[test]
+    fn esrun_compile_failures_are_compile_errors() {
+        let samples = [
+            // esbuild parse error
+            "✘ [ERROR] Expected \";\" but found \"world\"\n\n    src/index.ts:1:12:\n      1 │ const hello world = 1;\n        ╵             ^\n\nError: Build failed with 1 error:\nsrc/index.ts:1:12: ERROR: Expected \";\" but found \"world\"\n",
+            // A package that isn't installed, as esbuild reports it
+            "✘ [ERROR] Could not resolve \"@solana/web3.jsx\"\n\n    src/index.ts:1:22:\n      1 │ import * as web3 from \"@solana/web3.jsx\";\n",
+            // ... and as Node's ESM loader does
+            "node:internal/errors:496\n    ErrorCaptureStackTrace(err);\n    ^\n\nError [ERR_MODULE_NOT_FOUND]: Cannot find package 'lodash' imported from /app/template-ts/src/index.ts\n",
+            // Type checker diagnostic
+            "src/index.ts(3,1): error TS2304: Cannot find name 'foo'.\n",
+        ];
+        for stderr in samples {
+            let error = classify_ts_error(stderr.to_string());
+            assert!(matches!(error, CompileError::Compile(_)), "{}", stderr);
+        }
+    }
+
+    #[test]
+    fn esrun_runtime_failures_are_run_errors() {
+        let samples = [
+            "/app/template-ts/src/index.ts:3\n  console.log(value.length);\n                    ^\n\nTypeError: Cannot read properties of undefined (reading 'length')\n    at main (/app/template-ts/src/index.ts:3:21)\n",
+            "Error: failed to send transaction: Transaction simulation failed: Attempt to debit an account but found no record of a prior credit.\n    at Connection.sendEncodedTransaction (/app/template-ts/node_modules/@solana/web3.js/lib/index.cjs.js:8026:13)\n",
+        ];
+        for stderr in samples {
+            let error = classify_ts_error(stderr.to_string());
+            assert!(matches!(error, CompileError::Run(_)), "{}", stderr);
+        }
+    }
+
+    #[tokio::test]
+    async fn large_responses_are_compressed_when_asked_for() {
+        let template = rust_template();
+        let code = r#"fn main() { for i in 0..2000 { println!("line {} of the output", i); } }"#;
+        let request = serde_json::json!({ "code": code }).to_string();
+
+        let response = post_json(
+            test_state(template.path()),
+            "/rust",
+            &[("accept-encoding", "gzip")],
+            request.clone(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        let compressed = body_bytes(response).await;
+        let mut json = String::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_string(&mut json)
+            .unwrap();
+        assert!(compressed.len() < json.len() / 4);
+        let body: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert!(body["output"].as_str().unwrap().ends_with("line 1999 of the output\n"));
+
+        let response = post_json(test_state(template.path()), "/rust", &[], request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+        let body: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert!(body["output"].as_str().unwrap().ends_with("line 1999 of the output\n"));
+    }
+
+    #[tokio::test]
+    async fn linker_errors_are_compile_errors() {
+        let template = rust_template();
+        let code = r#"
+            extern "C" {
+                fn playground_missing_symbol();
             }
-            
-            // For other runtime errors
-            Err(CompileError::Run(stderr))
-        } else {
-            // Success - return the program output
-            Ok(CompileResponse {
-                success: true,
-                output: stdout,
-                error: None,
-            })
-        }
-    }));
-    
-    // Handle timeout and task result
-    match timed_task.await {
-        Ok(task_result) => {
-            // Task completed within timeout
-            match task_result {
-                Ok(result) => match result {
-                    Ok(response) => Ok(Json(response)),
-                    Err(error) => Err(error),
-                },
-                Err(e) => Err(CompileError::Run(format!("Task panic: {}", e))),
+            fn main() {
+                unsafe { playground_missing_symbol() }
+            }
+        "#;
+        let (status, body) = run_rust_request(&template, serde_json::json!({ "code": code })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error_kind"], "compile");
+    }
+
+    #[tokio::test]
+    async fn panics_quoting_compiler_errors_are_runtime_errors() {
+        let template = rust_template();
+        let code = r#"fn main() { panic!("error[E0308]: could not compile `x`; error: aborting due to previous error"); }"#;
+        let (status, body) = run_rust_request(&template, serde_json::json!({ "code": code })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error_kind"], "runtime");
+    }
+
+    #[tokio::test]
+    async fn panics_are_runtime_errors() {
+        let template = rust_template();
+        let code = "fn main() { let empty: Vec<u8> = Vec::new(); println!(\"{}\", empty[1]); }";
+        let (status, body) = run_rust_request(&template, serde_json::json!({ "code": code })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error_kind"], "runtime");
+        assert!(body["error"].as_str().unwrap().contains("index out of bounds"));
+    }
+
+    #[tokio::test]
+    async fn warnings_come_with_successful_runs() {
+        let template = rust_template();
+        let code = "fn main() {\n    let unused = 1;\n    println!(\"ran\");\n}\n";
+        let (status, body) = run_rust_request(&template, serde_json::json!({ "code": code })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["success"], true);
+        assert_eq!(body["output"], "ran\n");
+        let warnings = body["warnings"].as_array().unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0]["code"], "unused_variables");
+        assert_eq!(warnings[0]["line"], 2);
+    }
+
+    #[tokio::test]
+    async fn gzip_requests_are_handled_like_plain_ones() {
+        let template = rust_template();
+        let request =
+            serde_json::json!({ "code": r#"fn main() { println!("hello"); }"# }).to_string();
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(request.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let response = post_json(
+            test_state(template.path()),
+            "/rust",
+            &[("content-encoding", "gzip")],
+            compressed,
+        )
+        .await;
+        let status = response.status();
+        let gzip_body: serde_json::Value =
+            serde_json::from_slice(&body_bytes(response).await).unwrap();
+        let (plain_status, plain_body) =
+            run_rust_request(&template, serde_json::from_str(&request).unwrap()).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(status, plain_status);
+        assert_eq!(gzip_body["success"], true);
+        assert_eq!(gzip_body["output"], "hello\n");
+        assert_eq!(gzip_body["output"], plain_body["output"]);
+        assert_eq!(gzip_body["error"], plain_body["error"]);
+    }
+
+    #[tokio::test]
+    async fn malformed_bodies_are_rejected_in_the_response_shape() {
+        let template = rust_template();
+        let bodies = [
+            ("", "empty"),
+            (r#"{"code": "fn main() {}""#, "invalid json"),
+            (r#"{"code": 42}"#, "invalid type"),
+            (r#"{"code": "fn main() {}", "args": "a b"}"#, "invalid type"),
+        ];
+        for (body, expected) in bodies {
+            let response = post_json(test_state(template.path()), "/rust", &[], body).await;
+            assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY, "{}", body);
+            let response: serde_json::Value =
+                serde_json::from_slice(&body_bytes(response).await).unwrap();
+            assert_eq!(response["success"], false, "{}", body);
+            assert_eq!(response["output"], "", "{}", body);
+            assert_eq!(response["error_kind"], "invalid_request", "{}", body);
+            let error = response["error"].as_str().unwrap();
+            assert!(error.to_lowercase().contains(expected), "{}: {}", body, error);
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_batches_are_too_large() {
+        let template = rust_template();
+        let job = serde_json::json!({ "language": "rust", "code": "fn main() {}" });
+        let request = serde_json::json!({ "jobs": vec![job; 17] });
+        let response =
+            post_json(test_state(template.path()), "/batch", &[], request.to_string()).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body: serde_json::Value = serde_json::from_slice(&body_bytes(response).await).unwrap();
+        assert_eq!(body["success"], false);
+        assert_eq!(body["error_kind"], "too_large");
+        assert!(body["error"].as_str().unwrap().contains("at most 16 jobs, got 17"));
+    }
+
+    #[tokio::test]
+    async fn a_client_disconnect_kills_the_running_program() {
+        let template = rust_template();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = app(test_state(template.path()));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        // Starts a grandchild, tells where both are, and waits for far longer than the
+        // test does
+        let pids = tempfile::tempdir().unwrap();
+        let pid_file = pids.path().join("pids");
+        let code = format!(
+            r#"
+            fn main() {{
+                let child = std::process::Command::new("sleep").arg("60").spawn().unwrap();
+                let pids = format!("{{}} {{}}", std::process::id(), child.id());
+                std::fs::write("{}", pids).unwrap();
+                std::thread::sleep(std::time::Duration::from_secs(60));
+            }}
+            "#,
+            pid_file.display()
+        );
+        let body = serde_json::json!({ "code": code }).to_string();
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "POST /rust HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            addr,
+            body.len(),
+            body
+        );
+        tokio::io::AsyncWriteExt::write_all(&mut client, request.as_bytes())
+            .await
+            .unwrap();
+
+        let started = Instant::now();
+        let pids = loop {
+            match fs::read_to_string(&pid_file) {
+                Ok(pids) if !pids.is_empty() => break pids,
+                _ => {
+                    assert!(
+                        started.elapsed() < Duration::from_secs(60),
+                        "the program never started"
+                    );
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            }
+        };
+        drop(client);
+
+        // Whether `pid` has exited; it may linger as a zombie until it is reaped
+        let exited = |pid: &str| match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => stat
+                .rsplit_once(") ")
+                .is_some_and(|(_, fields)| fields.starts_with('Z')),
+            Err(_) => true,
+        };
+        let gone_by = Instant::now() + Duration::from_secs(5);
+        for pid in pids.split(' ') {
+            while !exited(pid) && Instant::now() < gone_by {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            assert!(exited(pid), "process {} is still running", pid);
+        }
+    }
+
+    #[tokio::test]
+    async fn thread_bombs_are_process_limit_errors() {
+        // Every later job in this test process gets a job cgroup with this limit, which
+        // is plenty for the other tests' programs
+        if let Err(err) = cgroup::install(None, Some(64)) {
+            println!("Skipping: job cgroups can't be created here: {}", err);
+            return;
+        }
+        let template = rust_template();
+        let code = r#"
+            fn main() {
+                let threads: Vec<_> = (0..500)
+                    .map(|_| std::thread::spawn(|| std::thread::sleep(std::time::Duration::from_secs(30))))
+                    .collect();
+                println!("started {} threads", threads.len());
+            }
+        "#;
+        let (status, body) = run_rust_request(&template, serde_json::json!({ "code": code })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error_kind"], "process_limit", "{}", body["error"]);
+        let error = body["error"].as_str().unwrap();
+        assert!(error.starts_with("Process/thread limit exceeded"), "{}", error);
+        assert!(error.contains("Resource temporarily unavailable"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn release_runs_are_built_without_debug_assertions() {
+        assert_eq!(CargoProfile::Dev.args(), &[] as &[&str]);
+        assert_eq!(CargoProfile::Release.args(), &["--release"]);
+
+        let template = rust_template();
+        let code = r#"fn main() { println!("{}", cfg!(debug_assertions)); }"#;
+        let (status, body) = run_rust_request(&template, serde_json::json!({ "code": code })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["output"], "true\n");
+
+        let request = serde_json::json!({ "code": code, "profile": "release" });
+        let (status, body) = run_rust_request(&template, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["output"], "false\n");
+    }
+
+    #[tokio::test]
+    async fn concurrent_jobs_get_their_own_output() {
+        let template = rust_template();
+        let state = test_state(template.path());
+        let jobs = (0..4).map(|i| {
+            let code = format!(
+                "fn main() {{ std::thread::sleep(std::time::Duration::from_millis(300)); println!(\"job {}\"); }}",
+                i
+            );
+            let request = serde_json::json!({ "code": code }).to_string();
+            let state = state.clone();
+            async move {
+                let response = post_json(state, "/rust", &[], request).await;
+                let body: serde_json::Value =
+                    serde_json::from_slice(&body_bytes(response).await).unwrap();
+                (i, body)
+            }
+        });
+        for (i, body) in futures::future::join_all(jobs).await {
+            assert_eq!(body["output"], format!("job {}\n", i), "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_session_clients_slow_the_program_down() {
+        use futures::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message;
+
+        let template = rust_template();
+        // Small socket buffers on both ends, so the kernel can't take in the output on
+        // the client's behalf while it isn't reading. Accepted sockets inherit the
+        // listener's.
+        let listener = tokio::net::TcpSocket::new_v4().unwrap();
+        listener.set_send_buffer_size(8192).unwrap();
+        listener.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = listener.listen(16).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = app(test_state(template.path()));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let tcp = tokio::net::TcpSocket::new_v4().unwrap();
+        tcp.set_recv_buffer_size(8192).unwrap();
+        let tcp = tcp.connect(addr).await.unwrap();
+        let (mut socket, _) = tokio_tungstenite::client_async(format!("ws://{}/ws", addr), tcp)
+            .await
+            .unwrap();
+        // Prints far more than the server buffers, one 8000 byte chunk at a time, then
+        // how long that took
+        let code = r#"
+            fn main() {
+                let started = std::time::Instant::now();
+                let line = "x".repeat(7999);
+                for _ in 0..120 {
+                    println!("{}", line);
+                }
+                eprintln!("printing took {} ms", started.elapsed().as_millis());
+            }
+        "#;
+        let run = serde_json::json!({ "action": "run", "code": code }).to_string();
+        socket.send(Message::Text(run)).await.unwrap();
+
+        const PRINTED: usize = 120 * 8000;
+        let mut printing_took = None;
+        let mut stdout_bytes = 0;
+        let mut unread_when_printed = None;
+        let mut finished = false;
+        while let Some(message) = socket.next().await {
+            let Message::Text(text) = message.unwrap() else {
+                continue;
+            };
+            let frame: serde_json::Value = serde_json::from_str(&text).unwrap();
+            match frame["type"].as_str().unwrap() {
+                "stdout" => {
+                    // Read like a client on a slow connection
+                    stdout_bytes += frame["data"].as_str().unwrap().len();
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+                "stderr" => {
+                    let data = frame["data"].as_str().unwrap();
+                    if let Some(ms) = data.trim().strip_prefix("printing took ") {
+                        printing_took =
+                            ms.strip_suffix(" ms").and_then(|ms| ms.parse::<u64>().ok());
+                        unread_when_printed = Some(PRINTED - stdout_bytes);
+                    }
+                }
+                "result" => {
+                    assert_eq!(frame["success"], true);
+                    finished = true;
+                    break;
+                }
+                _ => {}
             }
-        },
-        Err(_) => {
-            // Task timed out
-            Err(CompileError::Run("Execution timed out after 30 seconds. Your code took too long to run.".to_string()))
         }
+
+        // Nothing was dropped along the way
+        assert_eq!(stdout_bytes, PRINTED);
+        assert!(finished);
+        // What the client hadn't read yet when the program was done printing is all the
+        // output held between them: the session's 16 frames, the 16 chunks read ahead
+        // from the pipe and a few in flight, of at most 8 KiB each, plus what the pipe
+        // and the small socket buffers hold
+        let unread = unread_when_printed.unwrap();
+        assert!(unread <= 36 * 8192 + 128 * 1024, "{} bytes unread", unread);
+        // Printing alone takes a few milliseconds; the program could only get past the
+        // output the server buffers once the client had read the frames before it
+        let printing_took = printing_took.unwrap();
+        assert!(printing_took >= 1500, "printing took {} ms", printing_took);
     }
 }
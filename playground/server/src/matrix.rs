@@ -0,0 +1,526 @@
+use crate::{
+    cargo::{looks_like_compile_error, BuildReport, Diagnostic},
+    elapsed_ms,
+    extract::JobJson,
+    guardrails::{self, ClientId},
+    job_response,
+    process::{self, CancelOnDrop, Cancellation, RunLimits},
+    rejected,
+    workspace::{ActiveWorkspaces, Workspace},
+    AppState, CompileError, JobReply, JobReport, OutputEncoding,
+};
+use axum::{extract::State, Json};
+use futures::{stream, StreamExt};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+// Most SDK versions one request may build against
+const MAX_MATRIX_VERSIONS: usize = 8;
+
+// How many builds of one matrix run at once. Each still waits for one of the server-wide
+// job slots, so this only keeps a single matrix from taking all of them.
+const MATRIX_PARALLELISM: usize = 2;
+
+// Every build gets its own deadline. The first build against a version compiles the
+// whole SDK, which takes about as long as a first SBF build.
+const MATRIX_BUILD_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Exact crates.io versions only, e.g. "2.1.0" or "2.2.0-beta.1"; ranges would make the
+// result depend on when the matrix was run
+static SDK_VERSION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^\d+\.\d+\.\d+(-[0-9A-Za-z.]+)?$").unwrap());
+
+#[derive(Deserialize)]
+pub struct MatrixRequest {
+    // Source of src/main.rs
+    code: String,
+    // solana-sdk versions to build against, each pinned exactly
+    sdk_versions: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct MatrixResult {
+    version: String,
+    success: bool,
+    outcome: MatrixOutcome,
+    // What rustc reported about the code when built against this version
+    diagnostics: Vec<MatrixDiagnostic>,
+    // cargo's error output when the build failed
+    error: Option<String>,
+    duration_ms: u64,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum MatrixOutcome {
+    Success,
+    // The code doesn't compile against this version
+    CompileError,
+    // cargo couldn't resolve the dependencies with this version pinned, typically
+    // because crates.io has no such version
+    ResolutionFailed,
+    // The build was killed at MATRIX_BUILD_TIMEOUT
+    TimedOut,
+    // The build failed for some other reason, e.g. a download failed
+    Error,
+}
+
+#[derive(Serialize)]
+struct MatrixDiagnostic {
+    // "error" or "warning"
+    level: &'static str,
+    #[serde(flatten)]
+    diagnostic: Diagnostic,
+}
+
+// How a build against one version went
+struct MatrixBuild {
+    outcome: MatrixOutcome,
+    diagnostics: Vec<MatrixDiagnostic>,
+    error: Option<String>,
+}
+
+/// Build Rust code against several solana-sdk versions
+///
+/// Each version is built (not run) in its own copy of the template with `solana-sdk`
+/// pinned to exactly that version, with its own timeout. Results come back in the
+/// order the versions were given; a failing build doesn't affect the others.
+pub async fn matrix_handler(
+    State(app_state): State<AppState>,
+    client: ClientId,
+    JobJson(request): JobJson<MatrixRequest>,
+) -> Result<Json<Vec<MatrixResult>>, JobReply> {
+    println!(
+        "Received SDK matrix request for {} versions",
+        request.sdk_versions.len()
+    );
+    let started = Instant::now();
+    if let Some(reason) = app_state.rust_unavailable {
+        let error = CompileError::Unavailable(reason.to_string());
+        let reply = job_response(
+            Err(error),
+            JobReport::default(),
+            OutputEncoding::Utf8,
+            started,
+        );
+        return Err(reply);
+    }
+    if let Err(message) = validate_versions(&request.sdk_versions) {
+        guardrails::record_rejection(&app_state.metrics, &client, "sdk_versions", &message);
+        let error = CompileError::MalformedRequest(message);
+        return Err(rejected(
+            "sdk_versions",
+            error,
+            OutputEncoding::Utf8,
+            started,
+        ));
+    }
+
+    let code = Arc::new(request.code);
+    let results = stream::iter(request.sdk_versions)
+        .map(|version| {
+            let app_state = app_state.clone();
+            let code = code.clone();
+            async move {
+                let started = Instant::now();
+                let _slot = app_state.job_queue.acquire().await;
+
+                // Kills the build if the client disconnects, as for other jobs
+                let cancel = Arc::new(Cancellation::default());
+                let _guard = CancelOnDrop::new(cancel.clone());
+                let limits = RunLimits {
+                    deadline: Some(Instant::now() + MATRIX_BUILD_TIMEOUT),
+                    idle_timeout: None,
+                    cancel: Some(cancel),
+                };
+
+                let template_rs = app_state.template_rs.clone();
+                let active_workspaces = app_state.active_workspaces.clone();
+                let targets = app_state.matrix_targets.clone();
+                let pinned = version.clone();
+                let build = tokio::task::spawn_blocking(move || {
+                    // Held for the build, so the version's target directory isn't evicted
+                    // from under it
+                    let target = targets.lease(&template_rs, &pinned);
+                    let build = build_against(
+                        &template_rs,
+                        &active_workspaces,
+                        target.dir.clone(),
+                        &code,
+                        &pinned,
+                        limits,
+                    );
+                    drop(target);
+                    targets.evict(&template_rs);
+                    build
+                });
+                let build = match build.await {
+                    Ok(Ok(build)) => build,
+                    Ok(Err(err)) => MatrixBuild::failed(err.to_string()),
+                    Err(err) => MatrixBuild::failed(format!("Task panic: {}", err)),
+                };
+                MatrixResult {
+                    version,
+                    success: build.outcome == MatrixOutcome::Success,
+                    outcome: build.outcome,
+                    diagnostics: build.diagnostics,
+                    error: build.error,
+                    duration_ms: elapsed_ms(started),
+                }
+            }
+        })
+        .buffered(MATRIX_PARALLELISM)
+        .collect::<Vec<_>>()
+        .await;
+
+    Ok(Json(results))
+}
+
+fn validate_versions(versions: &[String]) -> Result<(), String> {
+    if versions.is_empty() {
+        return Err("sdk_versions must name at least one version".to_string());
+    }
+    if versions.len() > MAX_MATRIX_VERSIONS {
+        return Err(format!(
+            "A matrix may contain at most {} versions, got {}",
+            MAX_MATRIX_VERSIONS,
+            versions.len()
+        ));
+    }
+    let mut seen = HashSet::new();
+    for version in versions {
+        if !SDK_VERSION.is_match(version) {
+            return Err(format!(
+                "Invalid solana-sdk version {:?}; expected an exact version such as \"2.2.0\"",
+                version
+            ));
+        }
+        if !seen.insert(version) {
+            return Err(format!("solana-sdk version {} is listed twice", version));
+        }
+    }
+    Ok(())
+}
+
+// Build the code in a copy of the template with solana-sdk pinned to `version`, into
+// `target_dir`
+fn build_against(
+    template_rs: &str,
+    active_workspaces: &ActiveWorkspaces,
+    target_dir: PathBuf,
+    code: &str,
+    version: &str,
+    limits: RunLimits,
+) -> io::Result<MatrixBuild> {
+    let workspace = Workspace::rust_copy_with_target(template_rs, target_dir, active_workspaces)?;
+    pin_sdk(&workspace.dir.join("Cargo.toml"), version)?;
+    fs::write(workspace.dir.join("src/main.rs"), code)?;
+
+    let output = process::run(
+        workspace.confine(
+            Command::new("cargo")
+                .current_dir(&workspace.dir)
+                .envs(workspace.envs.iter().cloned())
+                .args(["build", "--quiet", "--message-format=json"]),
+        ),
+        limits,
+        &mut |_, _| {},
+    )?;
+    if output.timed_out {
+        return Ok(MatrixBuild {
+            outcome: MatrixOutcome::TimedOut,
+            diagnostics: Vec::new(),
+            error: Some(format!(
+                "Build timed out after {} seconds",
+                MATRIX_BUILD_TIMEOUT.as_secs()
+            )),
+        });
+    }
+
+    let report = BuildReport::parse(&String::from_utf8_lossy(&output.stdout));
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    let outcome = if output.status.success() {
+        MatrixOutcome::Success
+    } else if report.has_errors || (!report.json_available && looks_like_compile_error(&stderr)) {
+        MatrixOutcome::CompileError
+    } else if is_resolution_failure(&stderr) {
+        MatrixOutcome::ResolutionFailed
+    } else {
+        MatrixOutcome::Error
+    };
+    let error =
+        (outcome != MatrixOutcome::Success).then(|| format!("{}{}", report.rendered, stderr));
+    let errors = report
+        .errors
+        .into_iter()
+        .map(|diagnostic| MatrixDiagnostic {
+            level: "error",
+            diagnostic,
+        });
+    let warnings = report
+        .warnings
+        .into_iter()
+        .map(|diagnostic| MatrixDiagnostic {
+            level: "warning",
+            diagnostic,
+        });
+
+    Ok(MatrixBuild {
+        outcome,
+        diagnostics: errors.chain(warnings).collect(),
+        error,
+    })
+}
+
+// Pin the manifest's solana-sdk dependency to exactly `version`, keeping whatever else
+// the template sets for it, such as features
+fn pin_sdk(manifest_path: &Path, version: &str) -> io::Result<()> {
+    let manifest = fs::read_to_string(manifest_path)?;
+    let mut manifest = manifest.parse::<toml::Table>().map_err(io::Error::other)?;
+    let requirement = toml::Value::String(format!("={}", version));
+    let dependencies = manifest
+        .entry("dependencies")
+        .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+        .as_table_mut()
+        .ok_or_else(|| io::Error::other("The template's [dependencies] is not a table"))?;
+    match dependencies.get_mut("solana-sdk") {
+        Some(toml::Value::Table(dependency)) => {
+            dependency.insert("version".to_string(), requirement);
+        }
+        _ => {
+            dependencies.insert("solana-sdk".to_string(), requirement);
+        }
+    }
+    fs::write(
+        manifest_path,
+        toml::to_string(&manifest).map_err(io::Error::other)?,
+    )
+}
+
+// cargo reports a version that doesn't exist (or conflicts with the template's other
+// dependencies) while resolving, before anything is compiled
+fn is_resolution_failure(stderr: &str) -> bool {
+    stderr.contains("failed to select a version for the requirement")
+        || stderr.contains("no matching package named")
+}
+
+// The target directories matrix builds use, one per version under the template's
+// `target/sdk-matrix`. Every version builds into a directory of its own: cargo holds a
+// lock on the target directory for the whole build, so a shared one would run the builds
+// one at a time. It also keeps each version's dependencies built for next time, but
+// only for the `max_cached` most recently used versions; each takes as much space as a
+// full build of the SDK.
+pub struct MatrixTargets {
+    max_cached: usize,
+    // How many builds are using each version's directory right now
+    in_use: Mutex<HashMap<String, usize>>,
+}
+
+// A version's target directory, kept from eviction until dropped
+struct TargetLease<'a> {
+    targets: &'a MatrixTargets,
+    version: String,
+    dir: PathBuf,
+}
+
+impl MatrixTargets {
+    pub fn new(max_cached: usize) -> Self {
+        MatrixTargets {
+            max_cached,
+            in_use: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lease(&self, template_rs: &str, version: &str) -> TargetLease<'_> {
+        *self
+            .in_use
+            .lock()
+            .unwrap()
+            .entry(version.to_string())
+            .or_default() += 1;
+        let dir = matrix_root(template_rs).join(version);
+        // Marks the version as just used; the directory may not exist yet
+        let _ = fs::create_dir_all(&dir);
+        if let Ok(handle) = fs::File::open(&dir) {
+            let _ = handle.set_modified(SystemTime::now());
+        }
+        TargetLease {
+            targets: self,
+            version: version.to_string(),
+            dir,
+        }
+    }
+
+    // Remove the least recently used versions' directories beyond `max_cached`, except
+    // those a build is using
+    fn evict(&self, template_rs: &str) {
+        let root = matrix_root(template_rs);
+        let Ok(entries) = fs::read_dir(&root) else {
+            return;
+        };
+        let mut cached: Vec<(SystemTime, String)> = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let used = entry.metadata().ok()?.modified().ok()?;
+                Some((used, entry.file_name().to_str()?.to_string()))
+            })
+            .filter(|(_, version)| SDK_VERSION.is_match(version))
+            .collect();
+        cached.sort();
+        let excess = cached.len().saturating_sub(self.max_cached);
+
+        // Moved aside while no build can start using them, deleted after
+        let mut evicted = Vec::new();
+        {
+            let in_use = self.in_use.lock().unwrap();
+            for (_, version) in cached.into_iter().take(excess) {
+                if in_use.contains_key(&version) {
+                    continue;
+                }
+                let trash = root.join(format!(".evicted-{}", uuid::Uuid::new_v4().simple()));
+                if fs::rename(root.join(&version), &trash).is_ok() {
+                    evicted.push((version, trash));
+                }
+            }
+        }
+        for (version, trash) in evicted {
+            println!("Evicted SDK matrix target directory for {}", version);
+            let _ = fs::remove_dir_all(trash);
+        }
+    }
+}
+
+impl Drop for TargetLease<'_> {
+    fn drop(&mut self) {
+        let mut in_use = self.targets.in_use.lock().unwrap();
+        if let Some(count) = in_use.get_mut(&self.version) {
+            *count -= 1;
+            if *count == 0 {
+                in_use.remove(&self.version);
+            }
+        }
+    }
+}
+
+fn matrix_root(template_rs: &str) -> PathBuf {
+    Path::new(template_rs).join("target").join("sdk-matrix")
+}
+
+impl MatrixBuild {
+    fn failed(message: String) -> Self {
+        MatrixBuild {
+            outcome: MatrixOutcome::Error,
+            diagnostics: Vec::new(),
+            error: Some(message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn versions(list: &[&str]) -> Vec<String> {
+        list.iter().map(|version| version.to_string()).collect()
+    }
+
+    #[test]
+    fn only_exact_distinct_versions_are_accepted() {
+        assert!(validate_versions(&versions(&["2.1.0", "2.2.0-beta.1"])).is_ok());
+        assert!(validate_versions(&[]).is_err());
+        assert!(validate_versions(&versions(&["^2.1"])).is_err());
+        assert!(validate_versions(&versions(&[">=2.0.0"])).is_err());
+        assert!(validate_versions(&versions(&["2.1.0", "2.1.0"])).is_err());
+        let too_many: Vec<String> = (0..=MAX_MATRIX_VERSIONS)
+            .map(|minor| format!("2.{}.0", minor))
+            .collect();
+        assert!(validate_versions(&too_many).is_err());
+    }
+
+    fn pinned(manifest: &str, version: &str) -> toml::Table {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Cargo.toml");
+        fs::write(&path, manifest).unwrap();
+        pin_sdk(&path, version).unwrap();
+        fs::read_to_string(&path).unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn the_sdk_is_pinned_keeping_its_features() {
+        let manifest = pinned(
+            "[package]\nname = \"t\"\n\n[dependencies]\nsolana-sdk = { version = \"2.1\", features = [\"full\"] }\n",
+            "2.2.0",
+        );
+        let sdk = &manifest["dependencies"]["solana-sdk"];
+        assert_eq!(sdk["version"].as_str(), Some("=2.2.0"));
+        assert_eq!(sdk["features"][0].as_str(), Some("full"));
+
+        let manifest = pinned("[dependencies]\nsolana-sdk = \"2.1\"\n", "2.2.0");
+        assert_eq!(
+            manifest["dependencies"]["solana-sdk"].as_str(),
+            Some("=2.2.0")
+        );
+
+        let manifest = pinned("[package]\nname = \"t\"\n", "2.2.0");
+        assert_eq!(
+            manifest["dependencies"]["solana-sdk"].as_str(),
+            Some("=2.2.0")
+        );
+    }
+
+    #[test]
+    fn resolution_failures_are_told_apart() {
+        assert!(is_resolution_failure(
+            "error: failed to select a version for the requirement `solana-sdk = \"=9.9.9\"`"
+        ));
+        assert!(is_resolution_failure(
+            "error: no matching package named `solana-sdk` found"
+        ));
+        assert!(!is_resolution_failure(
+            "error: failed to download `solana-sdk v2.2.0`"
+        ));
+    }
+
+    fn cached(template: &Path, version: &str, unused_for: Duration) -> PathBuf {
+        let dir = template.join("target").join("sdk-matrix").join(version);
+        fs::create_dir_all(dir.join("debug")).unwrap();
+        let used = SystemTime::now() - unused_for;
+        fs::File::open(&dir).unwrap().set_modified(used).unwrap();
+        dir
+    }
+
+    #[test]
+    fn least_recently_used_versions_are_evicted_unless_in_use() {
+        let template = tempfile::tempdir().unwrap();
+        let template_rs = template.path().to_str().unwrap();
+        let hour = Duration::from_secs(3600);
+        let oldest = cached(template.path(), "2.0.0", 3 * hour);
+        let older = cached(template.path(), "2.1.0", 2 * hour);
+        let old = cached(template.path(), "2.2.0", hour);
+        let recent = cached(template.path(), "2.3.0", Duration::ZERO);
+
+        let targets = MatrixTargets::new(2);
+        let lease = targets.lease(template_rs, "2.0.0");
+        targets.evict(template_rs);
+        assert!(oldest.exists(), "a version in use was evicted");
+        assert!(!older.exists());
+        assert!(!old.exists());
+        assert!(recent.exists());
+
+        drop(lease);
+        cached(template.path(), "2.0.0", 3 * hour);
+        let newest = cached(template.path(), "2.4.0", Duration::ZERO);
+        targets.evict(template_rs);
+        assert!(!oldest.exists());
+        assert!(recent.exists());
+        assert!(newest.exists());
+    }
+}
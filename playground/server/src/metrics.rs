@@ -0,0 +1,51 @@
+use crate::AppState;
+use axum::{extract::State, http::header, response::IntoResponse};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+// Counters exposed on /metrics
+#[derive(Default)]
+pub struct Metrics {
+    // Leaked workspace directories removed by the janitor
+    pub workspace_leaks_reclaimed_total: AtomicU64,
+    // Requests rejected by a guardrail, by the rule that rejected them
+    compile_rejected_total: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl Metrics {
+    pub fn count_rejection(&self, rule: &'static str) {
+        *self.compile_rejected_total.lock().unwrap().entry(rule).or_default() += 1;
+    }
+
+    // Render the counters in the Prometheus text format
+    fn render(&self) -> String {
+        let mut text = format!(
+            "# HELP workspace_leaks_reclaimed_total Leaked workspace directories removed by the janitor.\n\
+             # TYPE workspace_leaks_reclaimed_total counter\n\
+             workspace_leaks_reclaimed_total {}\n",
+            self.workspace_leaks_reclaimed_total.load(Ordering::Relaxed)
+        );
+        text.push_str(
+            "# HELP compile_rejected_total Requests rejected by a guardrail, by rule.\n\
+             # TYPE compile_rejected_total counter\n",
+        );
+        for (rule, count) in self.compile_rejected_total.lock().unwrap().iter() {
+            let _ = writeln!(text, "compile_rejected_total{{reason=\"{}\"}} {}", rule, count);
+        }
+        text
+    }
+}
+
+/// Metrics in the Prometheus text format
+pub async fn metrics_handler(State(app_state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        app_state.metrics.render(),
+    )
+}
@@ -0,0 +1,120 @@
+use crate::{session::ServerFrame, AppState, JobReply};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+
+// The shapes a compile response can be returned in
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseFormat {
+    // The CompileResponse, as always
+    #[default]
+    Json,
+    // Only what the program printed, or the error, as raw text
+    Text,
+    // The output line by line as WebSocket `stdout` frames, then a `result` frame
+    Ndjson,
+}
+
+#[derive(Deserialize)]
+struct FormatParams {
+    format: Option<ResponseFormat>,
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for ResponseFormat {
+    type Rejection = (StatusCode, String);
+
+    // `?format=` wins over the Accept header, for clients that can't set headers
+    async fn from_request_parts(parts: &mut Parts, _: &AppState) -> Result<Self, Self::Rejection> {
+        let Query(params) = Query::<FormatParams>::try_from_uri(&parts.uri).map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                "Invalid format; expected json, text or ndjson".to_string(),
+            )
+        })?;
+        if let Some(format) = params.format {
+            return Ok(format);
+        }
+        let accept = parts
+            .headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .unwrap_or_default();
+        Ok(from_accept(accept))
+    }
+}
+
+// The most preferred format the Accept header names, JSON if it names none (e.g. `*/*`)
+fn from_accept(accept: &str) -> ResponseFormat {
+    let mut ranges: Vec<(f32, ResponseFormat)> = accept
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let format = match parts.next()?.trim().to_ascii_lowercase().as_str() {
+                "application/json" => ResponseFormat::Json,
+                "text/plain" => ResponseFormat::Text,
+                "application/x-ndjson" => ResponseFormat::Ndjson,
+                _ => return None,
+            };
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|quality| quality.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (quality > 0.0).then_some((quality, format))
+        })
+        .collect();
+    // The sort is stable, so of equally preferred formats the first listed wins
+    ranges.sort_by(|a, b| b.0.total_cmp(&a.0));
+    ranges
+        .first()
+        .map(|&(_, format)| format)
+        .unwrap_or_default()
+}
+
+// Turn a finished job's reply into the response the client asked for. The status code
+// is the same in every format, so plain text clients can still tell failures apart.
+pub fn respond(
+    format: ResponseFormat,
+    (mut headers, (status, Json(response))): (HeaderMap, JobReply),
+) -> Response {
+    // The body depends on the Accept header, so caches must not mix the formats up
+    headers.append(header::VARY, HeaderValue::from_static("accept"));
+    match format {
+        ResponseFormat::Json => (status, headers, Json(response)).into_response(),
+        ResponseFormat::Text => {
+            // What a terminal would show: the output, then the error after it
+            let mut body = response.output;
+            if let Some(error) = response.error {
+                if !body.is_empty() && !body.ends_with('\n') {
+                    body.push('\n');
+                }
+                body.push_str(&error);
+            }
+            let content_type = [(header::CONTENT_TYPE, "text/plain; charset=utf-8")];
+            (status, headers, content_type, body).into_response()
+        }
+        ResponseFormat::Ndjson => {
+            let mut frames: Vec<ServerFrame> = response
+                .output
+                .split_inclusive('\n')
+                .map(|line| ServerFrame::Stdout {
+                    data: line.to_string(),
+                })
+                .collect();
+            frames.push(ServerFrame::Result(response));
+            let body: String = frames
+                .iter()
+                .filter_map(|frame| serde_json::to_string(frame).ok())
+                .map(|line| line + "\n")
+                .collect();
+            let content_type = [(header::CONTENT_TYPE, "application/x-ndjson")];
+            (status, headers, content_type, body).into_response()
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use crate::{
+    cgroup::JobCgroup,
+    process::{self, RunLimits},
+    process_limit, sandbox, timed_out_error, CompileError,
+};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::LazyLock,
+    time::{Duration, Instant, SystemTime},
+};
+
+// pnpm's package store, inside the cache root so installs can hard link from it
+const STORE_DIR: &str = ".pnpm-store";
+
+// A cached workspace used this recently may still be in use by a running job, which
+// is much shorter, so eviction leaves it alone
+const EVICTION_GRACE: Duration = Duration::from_secs(600);
+
+// Limits on the extra dependencies a TypeScript request may add
+const MAX_DEPENDENCIES: usize = 20;
+const MAX_VERSION_LEN: usize = 64;
+
+// npm package names: optionally scoped, lowercase, URL-safe, at most 214 characters
+static PACKAGE_NAME: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(@[a-z0-9~-][a-z0-9._~-]*/)?[a-z0-9~-][a-z0-9._~-]*$").unwrap());
+
+// Version specs are limited to semver-like characters so they can never carry shell
+// metacharacters, URLs or file paths (e.g. "1.98.0", "^0.30.1", "~2.1", "latest")
+static VERSION_SPEC: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[A-Za-z0-9.^~*+-]+$").unwrap());
+
+// Check that every dependency has a valid npm package name and a plain version spec
+pub fn validate_dependencies(dependencies: &BTreeMap<String, String>) -> Result<(), CompileError> {
+    if dependencies.len() > MAX_DEPENDENCIES {
+        return Err(CompileError::InvalidRequest(format!(
+            "Too many dependencies: {} (maximum is {})",
+            dependencies.len(),
+            MAX_DEPENDENCIES
+        )));
+    }
+
+    for (name, version) in dependencies {
+        if name.len() > 214 || !PACKAGE_NAME.is_match(name) {
+            return Err(CompileError::InvalidRequest(format!(
+                "Invalid npm package name: {}",
+                name
+            )));
+        }
+        if version.len() > MAX_VERSION_LEN || !VERSION_SPEC.is_match(version) {
+            return Err(CompileError::InvalidRequest(format!(
+                "Invalid version for {}: {:?}",
+                name, version
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// Return a copy of the TypeScript template with the extra dependencies installed,
+// reusing a previous install of the same dependency set when there is one.
+//
+// Workspaces are cached under `cache_root` by a hash of the template manifest and the
+// dependency set. Each is built in a scratch directory and renamed into place only
+// after `pnpm install` succeeds, so a cached workspace is always complete. Once there
+// are more than `max_cached` of them, the least recently used ones are removed.
+pub fn prepare_workspace(
+    template_ts: &str,
+    cache_root: &str,
+    max_cached: usize,
+    dependencies: &BTreeMap<String, String>,
+    limits: RunLimits,
+) -> Result<PathBuf, CompileError> {
+    let package_json = fs::read_to_string(Path::new(template_ts).join("package.json"))?;
+    let workspace = Path::new(cache_root).join(workspace_hash(&package_json, dependencies));
+    if workspace.join("node_modules").exists() {
+        touch(&workspace);
+        return Ok(workspace);
+    }
+
+    println!(
+        "Installing TypeScript dependencies into {}: {:?}",
+        workspace.display(),
+        dependencies
+    );
+    fs::create_dir_all(cache_root)?;
+    let scratch = Path::new(cache_root).join(format!(".tmp-{}", uuid::Uuid::new_v4()));
+    let store = Path::new(cache_root).join(STORE_DIR);
+    fs::create_dir_all(&store)?;
+    let result = build_workspace(
+        template_ts,
+        &package_json,
+        &scratch,
+        &store,
+        dependencies,
+        limits.clone(),
+    )
+    .and_then(|()| {
+        // Another request may have finished installing the same set first; keep theirs
+        match fs::rename(&scratch, &workspace) {
+            Err(err) if !workspace.join("node_modules").exists() => Err(err.into()),
+            _ => Ok(()),
+        }
+    });
+    let _ = fs::remove_dir_all(&scratch);
+    if result.is_ok() {
+        touch(&workspace);
+        evict(Path::new(cache_root), &store, max_cached, limits.deadline);
+    }
+    result.map(|()| workspace)
+}
+
+// Mark a cached workspace as just used
+fn touch(workspace: &Path) {
+    if let Ok(dir) = fs::File::open(workspace) {
+        let _ = dir.set_modified(SystemTime::now());
+    }
+}
+
+// Remove the least recently used workspaces beyond `max_cached`, then the packages
+// only they needed from pnpm's store
+fn evict(cache_root: &Path, store: &Path, max_cached: usize, deadline: Option<Instant>) {
+    let Ok(entries) = fs::read_dir(cache_root) else {
+        return;
+    };
+    // Scratch directories and the store start with a dot
+    let mut cached: Vec<(SystemTime, PathBuf)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .collect();
+    if cached.len() <= max_cached {
+        return;
+    }
+    cached.sort();
+    let excess = cached.len() - max_cached;
+    let now = SystemTime::now();
+    let mut evicted = 0;
+    for (used, workspace) in cached.into_iter().take(excess) {
+        let recently_used = now
+            .duration_since(used)
+            .is_ok_and(|since| since < EVICTION_GRACE);
+        if !recently_used && fs::remove_dir_all(&workspace).is_ok() {
+            println!(
+                "Evicted TypeScript dependency cache {}",
+                workspace.display()
+            );
+            evicted += 1;
+        }
+    }
+
+    if evicted > 0 {
+        let prune = process::run(
+            Command::new("pnpm")
+                .args(["store", "prune", "--store-dir"])
+                .arg(store),
+            RunLimits {
+                deadline,
+                idle_timeout: None,
+                cancel: None,
+            },
+            &mut |_, _| {},
+        );
+        if let Err(err) = prune {
+            println!("Failed to prune the pnpm store: {}", err);
+        }
+    }
+}
+
+// Copy the template's top-level files into `dir`, merge in the dependencies and install
+fn build_workspace(
+    template_ts: &str,
+    package_json: &str,
+    dir: &Path,
+    store: &Path,
+    dependencies: &BTreeMap<String, String>,
+    limits: RunLimits,
+) -> Result<(), CompileError> {
+    fs::create_dir_all(dir.join("src"))?;
+
+    // Config files such as tsconfig.json and the lockfile come along; node_modules
+    // and src are left behind since they are installed and written per request
+    for entry in fs::read_dir(template_ts)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() && entry.file_name() != "package.json" {
+            fs::copy(entry.path(), dir.join(entry.file_name()))?;
+        }
+    }
+
+    let mut package: serde_json::Value = serde_json::from_str(package_json)
+        .map_err(|err| io::Error::other(format!("Invalid template package.json: {}", err)))?;
+    let Some(package_deps) = package
+        .as_object_mut()
+        .map(|package| {
+            package
+                .entry("dependencies")
+                .or_insert_with(|| serde_json::json!({}))
+        })
+        .and_then(|deps| deps.as_object_mut())
+    else {
+        return Err(io::Error::other("Invalid template package.json: expected an object").into());
+    };
+    for (name, version) in dependencies {
+        package_deps.insert(name.clone(), serde_json::Value::String(version.clone()));
+    }
+    fs::write(
+        dir.join("package.json"),
+        serde_json::to_string_pretty(&package).map_err(io::Error::other)?,
+    )?;
+
+    // The copied lockfile doesn't know about the new dependencies, so let pnpm update it.
+    // The install is sandboxed too, but keeps the network to reach the registry. Package
+    // lifecycle scripts would run arbitrary code from the registry, so they are skipped.
+    let cgroup = JobCgroup::processes_only()?;
+    let install_output = process::run(
+        process_limit::cap(sandbox::confine_sharing_network(
+            cgroup.enter(
+                Command::new("pnpm")
+                    .current_dir(dir)
+                    .args(["install", "--no-frozen-lockfile", "--ignore-scripts"])
+                    .arg("--store-dir")
+                    .arg(store),
+            ),
+            &[dir, store],
+            &[],
+        )),
+        RunLimits {
+            idle_timeout: None,
+            ..limits
+        },
+        &mut |_, _| {},
+    )?;
+
+    if install_output.timed_out {
+        return Err(timed_out_error(&install_output));
+    }
+    if !install_output.status.success() {
+        return Err(CompileError::Compile(format!(
+            "Failed to install dependencies:\n{}{}",
+            String::from_utf8_lossy(&install_output.stdout),
+            String::from_utf8_lossy(&install_output.stderr)
+        )));
+    }
+
+    Ok(())
+}
+
+// Stable cache key for a template manifest plus dependency set. BTreeMap iteration is
+// sorted, so the same set always hashes the same regardless of the order it was sent in.
+fn workspace_hash(package_json: &str, dependencies: &BTreeMap<String, String>) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(package_json.as_bytes());
+    for (name, version) in dependencies {
+        hasher.update(name.as_bytes());
+        hasher.update(b"@");
+        hasher.update(version.as_bytes());
+        hasher.update(b"\n");
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached(root: &Path, name: &str, unused_for: Duration) -> PathBuf {
+        let dir = root.join(name);
+        fs::create_dir_all(dir.join("node_modules")).unwrap();
+        let used = SystemTime::now() - unused_for;
+        fs::File::open(&dir).unwrap().set_modified(used).unwrap();
+        dir
+    }
+
+    #[test]
+    fn least_recently_used_workspaces_are_evicted() {
+        let root = tempfile::tempdir().unwrap();
+        let hour = Duration::from_secs(3600);
+        let oldest = cached(root.path(), "a", 3 * hour);
+        let older = cached(root.path(), "b", 2 * hour);
+        let old = cached(root.path(), "c", hour);
+        let recent = cached(root.path(), "d", Duration::ZERO);
+        let store = root.path().join(STORE_DIR);
+        fs::create_dir_all(&store).unwrap();
+
+        evict(root.path(), &store, 2, None);
+        assert!(!oldest.exists());
+        assert!(!older.exists());
+        assert!(old.exists());
+        assert!(recent.exists());
+        assert!(store.exists());
+    }
+
+    #[test]
+    fn recently_used_workspaces_are_kept_over_the_limit() {
+        let root = tempfile::tempdir().unwrap();
+        let first = cached(root.path(), "a", Duration::from_secs(60));
+        let second = cached(root.path(), "b", Duration::ZERO);
+        let store = root.path().join(STORE_DIR);
+
+        evict(root.path(), &store, 1, None);
+        assert!(first.exists());
+        assert!(second.exists());
+    }
+
+    #[test]
+    fn dependency_order_doesnt_change_the_hash() {
+        let first: BTreeMap<String, String> = [("b", "1.0.0"), ("a", "^2")]
+            .iter()
+            .map(|(name, version)| (name.to_string(), version.to_string()))
+            .collect();
+        let mut second = BTreeMap::new();
+        second.insert("a".to_string(), "^2".to_string());
+        second.insert("b".to_string(), "1.0.0".to_string());
+        assert_eq!(workspace_hash("{}", &first), workspace_hash("{}", &second));
+        assert_ne!(workspace_hash("{}", &first), workspace_hash("{ }", &first));
+    }
+}
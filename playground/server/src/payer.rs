@@ -0,0 +1,207 @@
+use crate::{
+    guardrails::{self, ClientId, RateLimit},
+    workspace::Workspace,
+    AppState,
+};
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    process::Command,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// Funded fee payers that outlive a single run. Each session has its own keypair,
+// which compile requests that name the session get in their workspace. Sessions that
+// go unused for `ttl` are forgotten along with their keypair.
+pub struct PayerSessions {
+    ttl: Duration,
+    // Airdropped to every new session's account
+    lamports: u64,
+    sessions: Mutex<HashMap<String, Payer>>,
+    // How many sessions each client may create per hour, if limited
+    creation_limit: Option<RateLimit>,
+}
+
+// A session's keypair
+#[derive(Clone)]
+pub struct Payer {
+    pubkey: String,
+    // The keypair in solana-keygen's format, a JSON array of its 64 bytes
+    keypair: String,
+    last_used: Instant,
+}
+
+impl PayerSessions {
+    pub fn new(ttl: Duration, lamports: u64, per_client_hourly: Option<u32>) -> Self {
+        PayerSessions {
+            ttl,
+            lamports,
+            sessions: Mutex::new(HashMap::new()),
+            creation_limit: per_client_hourly
+                .map(|max| RateLimit::new(max, Duration::from_secs(3600))),
+        }
+    }
+
+    // The payer of the session a request names, if it names one, marking it as used
+    pub fn lookup(&self, session_id: Option<&str>) -> Result<Option<Payer>, String> {
+        let Some(session_id) = session_id else {
+            return Ok(None);
+        };
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get_mut(session_id) {
+            Some(payer) if payer.last_used.elapsed() < self.ttl => {
+                payer.last_used = Instant::now();
+                Ok(Some(payer.clone()))
+            }
+            _ => Err(format!("Unknown or expired session: {}", session_id)),
+        }
+    }
+
+    // Forget the sessions that went unused for the TTL, returning how many went
+    fn expire(&self) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, payer| payer.last_used.elapsed() < self.ttl);
+        before - sessions.len()
+    }
+}
+
+impl Payer {
+    // Write the keypair into the workspace for one job, and tell the program where it
+    // is. The file goes away with the workspace.
+    pub fn inject(
+        &self,
+        workspace: Workspace,
+        env: &mut BTreeMap<String, String>,
+    ) -> io::Result<Workspace> {
+        let file = workspace
+            .dir
+            .join(format!("payer-{}.json", uuid::Uuid::new_v4().simple()));
+        let workspace = workspace.owning(file.clone());
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&file)
+            .and_then(|mut keypair_file| keypair_file.write_all(self.keypair.as_bytes()))?;
+        env.insert("PAYER_KEYPAIR".to_string(), file.to_string_lossy().to_string());
+        env.insert("PAYER_PUBKEY".to_string(), self.pubkey.clone());
+        Ok(workspace)
+    }
+}
+
+// Periodically forget expired sessions, so their keypairs don't stay in memory
+// until someone asks for them
+pub fn spawn_expiry(sessions: Arc<PayerSessions>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(sessions.ttl.min(Duration::from_secs(60)));
+        loop {
+            ticker.tick().await;
+            let expired = sessions.expire();
+            if expired > 0 {
+                println!("Expired {} payer sessions", expired);
+            }
+        }
+    });
+}
+
+#[derive(Serialize)]
+pub struct SessionResponse {
+    session_id: String,
+    pubkey: String,
+    // Balance of `pubkey` after the airdrop was confirmed
+    balance: u64,
+}
+
+/// Create a session with its own funded fee payer
+///
+/// Generates a keypair, airdrops the configured amount to it and returns the session
+/// id. Compile requests that pass the id get the keypair in their workspace, so every
+/// run of the session pays from the same account without airdropping first.
+pub async fn create_session_handler(
+    State(app_state): State<AppState>,
+    client: ClientId,
+) -> Result<Json<SessionResponse>, (StatusCode, String)> {
+    println!("Received request to /session");
+    let payers = &app_state.payers;
+
+    // Every session is an airdrop, so one client can't be allowed to drain the faucet
+    let allowed = payers
+        .creation_limit
+        .as_ref()
+        .map(|limit| limit.check(&client));
+    if let Some(Err(retry_after)) = allowed {
+        let message = format!(
+            "Too many sessions created; try again in {} seconds",
+            retry_after.as_secs().max(1)
+        );
+        guardrails::record_rejection(&app_state.metrics, &client, "session_rate", &message);
+        return Err((StatusCode::TOO_MANY_REQUESTS, message));
+    }
+
+    let (pubkey, keypair) = tokio::task::spawn_blocking(generate_keypair)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, format!("Task panic: {}", err)))?
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => (
+                StatusCode::NOT_IMPLEMENTED,
+                "solana-keygen is not installed on this server".to_string(),
+            ),
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+        })?;
+
+    // Anything that goes wrong with the airdrop is the validator's fault, not the client's
+    let (signature, balance) = app_state
+        .validator
+        .airdrop(&pubkey, payers.lamports)
+        .await
+        .map_err(|err| (StatusCode::BAD_GATEWAY, err))?;
+
+    let session_id = uuid::Uuid::new_v4().simple().to_string();
+    payers.sessions.lock().unwrap().insert(
+        session_id.clone(),
+        Payer {
+            pubkey: pubkey.clone(),
+            keypair,
+            last_used: Instant::now(),
+        },
+    );
+    println!(
+        "Created payer session {} for {}, airdropped {} lamports ({})",
+        session_id, pubkey, payers.lamports, signature
+    );
+    Ok(Json(SessionResponse {
+        session_id,
+        pubkey,
+        balance,
+    }))
+}
+
+// Generate a keypair with solana-keygen, returning its public key and the keypair file
+fn generate_keypair() -> io::Result<(String, String)> {
+    let dir = tempfile::Builder::new().prefix("playground-keygen-").tempdir()?;
+    let path = dir.path().join("keypair.json");
+    let output = Command::new("solana-keygen")
+        .args(["new", "--no-bip39-passphrase", "--silent", "--force", "--outfile"])
+        .arg(&path)
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "solana-keygen failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    let keypair = fs::read_to_string(&path)?;
+    let bytes: Vec<u8> = serde_json::from_str(&keypair)
+        .ok()
+        .filter(|bytes: &Vec<u8>| bytes.len() == 64)
+        .ok_or_else(|| io::Error::other("solana-keygen wrote an invalid keypair"))?;
+    // The second half of a keypair is its public key
+    Ok((bs58::encode(&bytes[32..]).into_string(), keypair))
+}
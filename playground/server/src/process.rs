@@ -0,0 +1,351 @@
+use std::{
+    io::{self, Read},
+    os::unix::process::CommandExt,
+    process::{Command, ExitStatus, Stdio},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+// How long to keep draining pipes after the process was killed, in case a
+// grandchild that escaped the process group is still holding them open
+const DRAIN_GRACE: Duration = Duration::from_secs(1);
+
+// Most output kept (and streamed) per pipe; anything past this is read and discarded
+// so a runaway printer can't exhaust memory before the deadline kills it
+pub const MAX_OUTPUT_BYTES: usize = 1024 * 1024;
+
+// Chunks read from the pipes but not yet handled. When `on_output` is slow, e.g. because
+// a streaming client is, the readers stop reading once this fills up, and the program
+// then blocks writing to the full pipe instead of output piling up in memory.
+const READ_AHEAD_CHUNKS: usize = 16;
+
+// Appended once to a pipe's output when it hits MAX_OUTPUT_BYTES
+const TRUNCATED_NOTICE: &[u8] = b"\n[output truncated: limit of 1 MiB reached]\n";
+
+// Which pipe a chunk of output was read from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+// When a running command gets killed
+#[derive(Clone, Default)]
+pub struct RunLimits {
+    // Kill the command if it is still running at this point
+    pub deadline: Option<Instant>,
+    // Kill the command once it has gone this long without writing any output
+    pub idle_timeout: Option<Duration>,
+    // Kill the command as soon as this is cancelled
+    pub cancel: Option<Arc<Cancellation>>,
+}
+
+impl RunLimits {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.is_cancelled())
+    }
+}
+
+// Lets another thread stop whatever command a job is currently running, e.g. because
+// the client that asked for the job has gone away
+#[derive(Default)]
+pub struct Cancellation {
+    state: Mutex<CancelState>,
+}
+
+#[derive(Default)]
+struct CancelState {
+    cancelled: bool,
+    // Process group of the command that is running right now, if any
+    running: Option<u32>,
+}
+
+impl Cancellation {
+    // Kill the running command, and make any later one fail before it starts
+    pub fn cancel(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.cancelled = true;
+        if let Some(pid) = state.running.take() {
+            kill_process_group(pid);
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.lock().unwrap().cancelled
+    }
+
+    // Returns false if the job was already cancelled, in which case the caller has to
+    // stop the process itself
+    fn register(&self, pid: u32) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.cancelled {
+            return false;
+        }
+        state.running = Some(pid);
+        true
+    }
+
+    // Must happen before the process is reaped, so its pid is never signalled after
+    // it could have been reused
+    fn unregister(&self) {
+        self.state.lock().unwrap().running = None;
+    }
+}
+
+// Cancels a job when dropped, unless it was disarmed first. A request handler holds
+// one while its job runs: if the client disconnects, axum drops the handler's future
+// and with it the guard, which kills the job's process instead of letting it run on
+// in the blocking pool.
+pub struct CancelOnDrop(Option<Arc<Cancellation>>);
+
+impl CancelOnDrop {
+    pub fn new(cancel: Arc<Cancellation>) -> Self {
+        CancelOnDrop(Some(cancel))
+    }
+
+    // The job finished normally, so there is nothing left to cancel
+    pub fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(cancel) = self.0.take() {
+            println!("Request dropped before its job finished, cancelling it");
+            cancel.cancel();
+        }
+    }
+}
+
+fn cancelled() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "job was cancelled")
+}
+
+// Everything a finished (or killed) process wrote, plus how it ended
+pub struct ProcessOutput {
+    pub status: ExitStatus,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    // The process was killed because it was still running at the deadline
+    pub timed_out: bool,
+    // The process was killed because it stopped producing output for the idle timeout
+    pub idle_terminated: bool,
+}
+
+// Run a command to completion, handing every chunk of output to `on_output` as it
+// arrives and also collecting it for the caller. `on_output` may block to slow the
+// command down, but must return by the deadline for the command to be killed on time.
+//
+// The command runs in its own process group so that, when it hits one of its limits,
+// the whole tree (e.g. `cargo run` and the program it started) is killed, not only the
+// direct child.
+pub fn run(
+    command: &mut Command,
+    limits: RunLimits,
+    on_output: &mut dyn FnMut(Stream, &[u8]),
+) -> io::Result<ProcessOutput> {
+    run_with_stdin(command, Stdio::null(), limits, on_output)
+}
+
+// `run`, with the command reading `stdin` instead of nothing
+pub fn run_with_stdin(
+    command: &mut Command,
+    stdin: Stdio,
+    limits: RunLimits,
+    on_output: &mut dyn FnMut(Stream, &[u8]),
+) -> io::Result<ProcessOutput> {
+    if limits.is_cancelled() {
+        return Err(cancelled());
+    }
+
+    let mut child = command
+        .stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()?;
+    if let Some(cancel) = &limits.cancel {
+        if !cancel.register(child.id()) {
+            kill_process_group(child.id());
+        }
+    }
+
+    let (sender, receiver) = mpsc::sync_channel(READ_AHEAD_CHUNKS);
+    let readers = [
+        child
+            .stdout
+            .take()
+            .map(|pipe| spawn_reader(pipe, Stream::Stdout, sender.clone())),
+        child
+            .stderr
+            .take()
+            .map(|pipe| spawn_reader(pipe, Stream::Stderr, sender.clone())),
+    ];
+    drop(sender);
+
+    let mut output = ProcessOutput {
+        status: ExitStatus::default(),
+        stdout: Vec::new(),
+        stderr: Vec::new(),
+        timed_out: false,
+        idle_terminated: false,
+    };
+
+    // Read until both pipes are closed, or until the deadline passes or the process
+    // goes idle
+    let mut drain_until = None;
+    let mut last_output = Instant::now();
+    loop {
+        let idle_until = limits.idle_timeout.map(|idle| last_output + idle);
+        let wait_until = drain_until.or(match (limits.deadline, idle_until) {
+            (Some(deadline), Some(idle_until)) => Some(deadline.min(idle_until)),
+            (deadline, idle_until) => deadline.or(idle_until),
+        });
+        let chunk = match wait_until {
+            Some(until) => receiver.recv_timeout(until.saturating_duration_since(Instant::now())),
+            None => receiver
+                .recv()
+                .map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+
+        match chunk {
+            Ok((stream, bytes)) => {
+                last_output = Instant::now();
+                let buffer = match stream {
+                    Stream::Stdout => &mut output.stdout,
+                    Stream::Stderr => &mut output.stderr,
+                };
+                if buffer.len() >= MAX_OUTPUT_BYTES {
+                    continue;
+                }
+
+                let kept = &bytes[..bytes.len().min(MAX_OUTPUT_BYTES - buffer.len())];
+                on_output(stream, kept);
+                buffer.extend_from_slice(kept);
+                if buffer.len() >= MAX_OUTPUT_BYTES {
+                    on_output(stream, TRUNCATED_NOTICE);
+                    buffer.extend_from_slice(TRUNCATED_NOTICE);
+                }
+                // Time spent waiting for `on_output` doesn't count as the program being idle
+                last_output = Instant::now();
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Err(mpsc::RecvTimeoutError::Timeout) if drain_until.is_some() => break,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                // Hitting the deadline wins when both limits expire together
+                if limits
+                    .deadline
+                    .is_some_and(|deadline| Instant::now() >= deadline)
+                {
+                    output.timed_out = true;
+                } else {
+                    output.idle_terminated = true;
+                }
+                kill_process_group(child.id());
+                drain_until = Some(Instant::now() + DRAIN_GRACE);
+            }
+        }
+    }
+
+    if let Some(cancel) = &limits.cancel {
+        cancel.unregister();
+    }
+    output.status = child.wait()?;
+    if drain_until.is_none() {
+        for reader in readers.into_iter().flatten() {
+            let _ = reader.join();
+        }
+    }
+
+    if limits.is_cancelled() {
+        return Err(cancelled());
+    }
+    Ok(output)
+}
+
+// Kill every process in the group led by `pid`
+pub fn kill_process_group(pid: u32) {
+    // SAFETY: killpg only sends a signal; a stale pid at worst fails with ESRCH
+    unsafe {
+        libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+    }
+}
+
+// Forward everything read from a pipe to the channel until EOF
+fn spawn_reader(
+    mut pipe: impl Read + Send + 'static,
+    stream: Stream,
+    sender: mpsc::SyncSender<(Stream, Vec<u8>)>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut buffer = [0u8; 8192];
+        loop {
+            match pipe.read(&mut buffer) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Ok(0) | Err(_) => break,
+                Ok(read) => {
+                    if sender.send((stream, buffer[..read].to_vec())).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // Whether `pid` has exited; it may linger as a zombie until whoever inherited it
+    // reaps it
+    fn exited(pid: &str) -> bool {
+        match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => stat
+                .rsplit_once(") ")
+                .is_some_and(|(_, fields)| fields.starts_with('Z')),
+            Err(_) => true,
+        }
+    }
+
+    #[test]
+    fn a_client_disconnect_kills_the_process_group() {
+        let cancel = Arc::new(Cancellation::default());
+        let guard = CancelOnDrop::new(cancel.clone());
+        let (sender, receiver) = mpsc::channel();
+        // The client goes away once the command has started a grandchild
+        let client = thread::spawn(move || {
+            let pid: String = receiver.recv().unwrap();
+            drop(guard);
+            pid
+        });
+
+        let limits = RunLimits {
+            deadline: Some(Instant::now() + Duration::from_secs(20)),
+            idle_timeout: None,
+            cancel: Some(cancel),
+        };
+        let started = Instant::now();
+        let result = run(
+            Command::new("bash").args(["-c", "sleep 30 & echo $!; wait"]),
+            limits,
+            &mut |_, bytes| {
+                let _ = sender.send(String::from_utf8_lossy(bytes).trim().to_string());
+            },
+        );
+        assert!(matches!(result, Err(err) if err.kind() == io::ErrorKind::Interrupted));
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        let grandchild = client.join().unwrap();
+        let gone_by = Instant::now() + Duration::from_secs(2);
+        while !exited(&grandchild) && Instant::now() < gone_by {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(exited(&grandchild), "sleep {} is still running", grandchild);
+    }
+}
@@ -0,0 +1,46 @@
+use std::{io, os::unix::process::CommandExt, process::Command, sync::OnceLock};
+
+// Most processes and threads a program may have, from MAX_PROCESSES. Only set when job
+// cgroups can't be created to enforce it; until then (or without MAX_PROCESSES) `cap`
+// leaves commands as they are.
+static MAX_PROCESSES: OnceLock<u64> = OnceLock::new();
+
+// Make `max` the limit for every later `cap`
+pub fn install(max: u64) {
+    let _ = MAX_PROCESSES.set(max);
+}
+
+// Limit the processes and threads `command` and its children may create, so a fork or
+// thread bomb fails instead of exhausting the container's PIDs. This is the fallback
+// for when there are no job cgroups: RLIMIT_NPROC counts every process and thread of
+// the server's user, not just the command's, and root isn't held to it at all.
+//
+// Apply this after the sandbox, so the sandbox's own setup isn't counted against it.
+pub fn cap(command: &mut Command) -> &mut Command {
+    if let Some(&max) = MAX_PROCESSES.get() {
+        let limit = libc::rlimit {
+            rlim_cur: max as libc::rlim_t,
+            rlim_max: max as libc::rlim_t,
+        };
+        // SAFETY: setrlimit is async-signal-safe and only reads `limit`, which was
+        // prepared before forking
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setrlimit(libc::RLIMIT_NPROC, &limit) == 0 {
+                    Ok(())
+                } else {
+                    Err(io::Error::last_os_error())
+                }
+            });
+        }
+    }
+    command
+}
+
+// Whether a failed program was refused a new process or thread by RLIMIT_NPROC. Both
+// Rust and Node report the EAGAIN that fork and pthread_create then fail with; with job
+// cgroups, `JobCgroup::hit_process_limit` tells instead.
+pub fn hit_limit(stderr: &str) -> bool {
+    MAX_PROCESSES.get().is_some()
+        && (stderr.contains("Resource temporarily unavailable") || stderr.contains("EAGAIN"))
+}
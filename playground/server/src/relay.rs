@@ -0,0 +1,461 @@
+use std::{
+    ffi::CString,
+    fs,
+    io::{self, Write},
+    mem,
+    net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs},
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::Path,
+};
+use tempfile::NamedTempFile;
+
+// Connections one sandboxed command may have open to the validator at once; further
+// ones wait in the listen backlog until one closes
+const MAX_CONNECTIONS: usize = 32;
+
+// Validator ports one relay forwards, e.g. RPC and WebSocket
+const MAX_ENDPOINTS: usize = 4;
+
+// How often the relay checks whether the command has exited when the kernel has no
+// pidfd support to be woken up by
+const EXIT_POLL_MS: libc::c_int = 50;
+
+// Gives sandboxed commands that have no network of their own a way to the validator
+// and nowhere else.
+//
+// Inside the command's network namespace, where only loopback is up, the relay listens
+// on the validator's ports and forwards every connection to the real validator from the
+// server's network. Validator host names are pointed at loopback by a replacement
+// /etc/hosts, so URLs such as http://solana-validator:8899 keep working unchanged.
+pub struct ValidatorRelay {
+    endpoints: Vec<Endpoint>,
+    // The /etc/hosts mounted into the sandbox, when the validator has a host name
+    hosts: Option<NamedTempFile>,
+}
+
+struct Endpoint {
+    // Where sandboxed commands connect to, on their own loopback interface
+    listen: SocketAddr,
+    // The validator, as seen from the server
+    target: SocketAddr,
+}
+
+impl ValidatorRelay {
+    // A relay to the hosts and ports of `urls`, e.g. the validator's RPC and WebSocket
+    // URLs. Host names are resolved once, now.
+    pub fn new(urls: &[&str]) -> io::Result<Self> {
+        let mut endpoints: Vec<Endpoint> = Vec::new();
+        let mut host_names: Vec<String> = Vec::new();
+        for url in urls {
+            let (host, port) = host_and_port(url)
+                .ok_or_else(|| io::Error::other(format!("Invalid validator URL {:?}", url)))?;
+            let listen_ip = match host.parse::<IpAddr>() {
+                Ok(ip) if ip.is_loopback() => ip,
+                // Commands couldn't even route to any other address
+                Ok(_) => {
+                    return Err(io::Error::other(format!(
+                        "{} must name the validator by host name or a loopback address",
+                        url
+                    )))
+                }
+                Err(_) => {
+                    if host != "localhost" && !host_names.contains(&host) {
+                        host_names.push(host.clone());
+                    }
+                    IpAddr::V4(Ipv4Addr::LOCALHOST)
+                }
+            };
+            let target = (host.as_str(), port)
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| io::Error::other(format!("{} has no address", host)))?;
+            let listen = SocketAddr::new(listen_ip, port);
+            match endpoints.iter().find(|endpoint| endpoint.listen == listen) {
+                Some(existing) if existing.target != target => {
+                    return Err(io::Error::other(format!(
+                        "Validator URLs {} and {} can't both be reached on port {}",
+                        existing.target, target, port
+                    )))
+                }
+                Some(_) => {}
+                None => endpoints.push(Endpoint { listen, target }),
+            }
+        }
+        if endpoints.len() > MAX_ENDPOINTS {
+            return Err(io::Error::other("Too many validator ports to relay"));
+        }
+
+        let hosts = if host_names.is_empty() {
+            None
+        } else {
+            if !Path::new("/etc/hosts").exists() {
+                return Err(io::Error::other(
+                    "/etc/hosts is missing, so the validator's host name can't be redirected",
+                ));
+            }
+            Some(hosts_file(&host_names)?)
+        };
+
+        Ok(ValidatorRelay { endpoints, hosts })
+    }
+
+    // What the sandbox needs to start the relay in a freshly forked child
+    pub(crate) fn plan(&self) -> RelayPlan {
+        let mut listen = [Address::EMPTY; MAX_ENDPOINTS];
+        let mut target = [Address::EMPTY; MAX_ENDPOINTS];
+        for (index, endpoint) in self.endpoints.iter().enumerate() {
+            listen[index] = Address::new(endpoint.listen);
+            target[index] = Address::new(endpoint.target);
+        }
+        RelayPlan {
+            count: self.endpoints.len(),
+            listen,
+            target,
+            hosts: self.hosts.as_ref().map(|hosts| {
+                CString::new(hosts.path().as_os_str().as_bytes())
+                    .expect("paths can't contain NUL bytes")
+            }),
+        }
+    }
+}
+
+// An /etc/hosts that resolves `host_names` to loopback
+fn hosts_file(host_names: &[String]) -> io::Result<NamedTempFile> {
+    let mut hosts = tempfile::Builder::new()
+        .prefix("playground-hosts-")
+        .tempfile()?;
+    writeln!(hosts, "127.0.0.1 localhost")?;
+    writeln!(hosts, "::1 localhost")?;
+    for name in host_names {
+        writeln!(hosts, "127.0.0.1 {}", name)?;
+    }
+    fs::set_permissions(hosts.path(), fs::Permissions::from_mode(0o644))?;
+    Ok(hosts)
+}
+
+// The host and port of e.g. "http://solana-validator:8899/", with the scheme's
+// default port when the URL has none
+fn host_and_port(url: &str) -> Option<(String, u16)> {
+    let (scheme, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let authority = authority.rsplit('@').next()?;
+    let (host, port) = match authority.strip_prefix('[') {
+        // An IPv6 address, e.g. [::1]:8899
+        Some(bracketed) => {
+            let (host, rest) = bracketed.split_once(']')?;
+            (host, rest.strip_prefix(':'))
+        }
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => match scheme {
+            "http" | "ws" => 80,
+            "https" | "wss" => 443,
+            _ => return None,
+        },
+    };
+    (!host.is_empty()).then(|| (host.to_string(), port))
+}
+
+// A socket address in the form the system calls take
+#[derive(Clone, Copy)]
+pub(crate) struct Address {
+    storage: libc::sockaddr_storage,
+    len: libc::socklen_t,
+}
+
+impl Address {
+    // SAFETY: all-zero bytes are a valid sockaddr_storage
+    const EMPTY: Address = Address {
+        storage: unsafe { mem::zeroed() },
+        len: 0,
+    };
+
+    fn new(address: SocketAddr) -> Self {
+        let mut result = Address::EMPTY;
+        match address {
+            SocketAddr::V4(address) => {
+                // SAFETY: sockaddr_storage is large enough and aligned for any address
+                let sin = unsafe {
+                    &mut *(&mut result.storage as *mut libc::sockaddr_storage)
+                        .cast::<libc::sockaddr_in>()
+                };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = address.port().to_be();
+                sin.sin_addr.s_addr = u32::from_ne_bytes(address.ip().octets());
+                result.len = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+            }
+            SocketAddr::V6(address) => {
+                // SAFETY: as above
+                let sin6 = unsafe {
+                    &mut *(&mut result.storage as *mut libc::sockaddr_storage)
+                        .cast::<libc::sockaddr_in6>()
+                };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = address.port().to_be();
+                sin6.sin6_addr.s6_addr = address.ip().octets();
+                result.len = mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+            }
+        }
+        result
+    }
+
+    fn family(&self) -> libc::c_int {
+        self.storage.ss_family as libc::c_int
+    }
+
+    fn as_ptr(&self) -> *const libc::sockaddr {
+        (&self.storage as *const libc::sockaddr_storage).cast()
+    }
+}
+
+// Everything the relay needs, prepared before forking so that the child doesn't
+// allocate
+pub(crate) struct RelayPlan {
+    count: usize,
+    listen: [Address; MAX_ENDPOINTS],
+    target: [Address; MAX_ENDPOINTS],
+    // The replacement /etc/hosts
+    pub hosts: Option<CString>,
+}
+
+// The relay's listening sockets, set up in the command's network namespace
+pub(crate) struct Listeners {
+    fds: [libc::c_int; MAX_ENDPOINTS],
+    count: usize,
+}
+
+impl Listeners {
+    pub fn fds(&self) -> &[libc::c_int] {
+        &self.fds[..self.count]
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+// Bring up loopback in a new network namespace, where it starts out down, and listen
+// on the validator's ports there
+pub(crate) unsafe fn listen(plan: &RelayPlan) -> io::Result<Listeners> {
+    let fd = check(libc::socket(
+        libc::AF_INET,
+        libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+        0,
+    ))?;
+    let mut request: libc::ifreq = mem::zeroed();
+    request.ifr_name[0] = b'l' as libc::c_char;
+    request.ifr_name[1] = b'o' as libc::c_char;
+    let up = check(libc::ioctl(fd, libc::SIOCGIFFLAGS, &mut request)).and_then(|_| {
+        request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+        check(libc::ioctl(fd, libc::SIOCSIFFLAGS, &request))
+    });
+    libc::close(fd);
+    up?;
+
+    let mut listeners = Listeners {
+        fds: [-1; MAX_ENDPOINTS],
+        count: 0,
+    };
+    for address in &plan.listen[..plan.count] {
+        let fd = check(libc::socket(
+            address.family(),
+            libc::SOCK_STREAM | libc::SOCK_CLOEXEC,
+            0,
+        ))?;
+        listeners.fds[listeners.count] = fd;
+        listeners.count += 1;
+        let one: libc::c_int = 1;
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_REUSEADDR,
+            (&one as *const libc::c_int).cast(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        );
+        check(libc::bind(fd, address.as_ptr(), address.len))?;
+        check(libc::listen(fd, 64))?;
+    }
+    Ok(listeners)
+}
+
+// Forward connections to the validator until the command `pid` exits. Runs in the
+// process that waits for the command, which has gone back to the server's network
+// namespace, so it must not allocate either.
+pub(crate) unsafe fn serve(plan: &RelayPlan, listeners: &Listeners, pid: libc::pid_t) {
+    let pidfd = libc::syscall(libc::SYS_pidfd_open, pid, 0) as libc::c_int;
+    // Open connections as (command side, validator side) pairs, -1 when unused
+    let mut connections = [(-1, -1); MAX_CONNECTIONS];
+    let mut buffer = [0u8; 16 * 1024];
+    let mut fds = [libc::pollfd {
+        fd: -1,
+        events: 0,
+        revents: 0,
+    }; 1 + MAX_ENDPOINTS + 2 * MAX_CONNECTIONS];
+
+    // Once the command has exited, what it sent before exiting is still passed on, but
+    // nothing more is read from the validator
+    let mut exited = false;
+
+    loop {
+        // Slot 0 is the pidfd, then the listeners, then both sides of each connection,
+        // in the order of `connections`. Unused slots hold -1 and are ignored by poll.
+        let open = connections
+            .iter()
+            .filter(|(inside, _)| *inside != -1)
+            .count();
+        fds[0].fd = if exited { -1 } else { pidfd };
+        for index in 0..MAX_ENDPOINTS {
+            let listener = listeners.fds[index];
+            fds[1 + index].fd = if open < MAX_CONNECTIONS { listener } else { -1 };
+        }
+        for (index, (inside, outside)) in connections.iter().enumerate() {
+            fds[1 + MAX_ENDPOINTS + 2 * index].fd = *inside;
+            fds[2 + MAX_ENDPOINTS + 2 * index].fd = if exited { -1 } else { *outside };
+        }
+        for fd in fds.iter_mut() {
+            fd.events = libc::POLLIN;
+            fd.revents = 0;
+        }
+
+        let timeout = match () {
+            _ if exited => 0,
+            _ if pidfd >= 0 => -1,
+            _ => EXIT_POLL_MS,
+        };
+        let ready = libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout);
+        if ready == -1 && io::Error::last_os_error().kind() != io::ErrorKind::Interrupted
+            || ready == 0 && exited
+        {
+            return;
+        }
+        if !exited && (pidfd >= 0 && fds[0].revents != 0 || pidfd < 0 && has_exited(pid)) {
+            exited = true;
+        }
+
+        for index in 0..listeners.count {
+            if fds[1 + index].revents == 0 {
+                continue;
+            }
+            let Some(slot) = connections.iter().position(|(inside, _)| *inside == -1) else {
+                break;
+            };
+            let inside = libc::accept4(
+                listeners.fds[index],
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                libc::SOCK_CLOEXEC,
+            );
+            if inside == -1 {
+                continue;
+            }
+            let target = &plan.target[index];
+            let outside = libc::socket(target.family(), libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0);
+            if outside == -1 || libc::connect(outside, target.as_ptr(), target.len) == -1 {
+                // The command sees the connection closed, as if the validator were down
+                libc::close(outside);
+                libc::close(inside);
+                continue;
+            }
+            connections[slot] = (inside, outside);
+        }
+
+        for index in 0..MAX_CONNECTIONS {
+            let (inside, outside) = connections[index];
+            if inside == -1 {
+                continue;
+            }
+            let inside_ready = fds[1 + MAX_ENDPOINTS + 2 * index].revents != 0;
+            let outside_ready = fds[2 + MAX_ENDPOINTS + 2 * index].revents != 0;
+            let open = (!inside_ready || forward(inside, outside, &mut buffer))
+                && (!outside_ready || forward(outside, inside, &mut buffer));
+            if !open {
+                libc::close(inside);
+                libc::close(outside);
+                connections[index] = (-1, -1);
+            }
+        }
+    }
+}
+
+// Copy what is waiting on `from` to `to`; false once either side is closed
+unsafe fn forward(from: libc::c_int, to: libc::c_int, buffer: &mut [u8]) -> bool {
+    let read = libc::read(from, buffer.as_mut_ptr().cast(), buffer.len());
+    if read <= 0 {
+        return read == -1 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted;
+    }
+    let mut written = 0;
+    while written < read as usize {
+        let count = libc::write(
+            to,
+            buffer[written..].as_ptr().cast(),
+            read as usize - written,
+        );
+        if count == -1 {
+            if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return false;
+        }
+        written += count as usize;
+    }
+    true
+}
+
+// Whether `pid` has exited, without reaping it
+unsafe fn has_exited(pid: libc::pid_t) -> bool {
+    let mut info: libc::siginfo_t = mem::zeroed();
+    let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+    libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, flags) == -1 || info.si_pid() != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_give_host_and_port() {
+        let parse = |url| host_and_port(url);
+        assert_eq!(
+            parse("http://solana-validator:8899"),
+            Some(("solana-validator".to_string(), 8899))
+        );
+        assert_eq!(
+            parse("ws://solana-validator:8900/path"),
+            Some(("solana-validator".to_string(), 8900))
+        );
+        assert_eq!(
+            parse("https://api.devnet.solana.com"),
+            Some(("api.devnet.solana.com".to_string(), 443))
+        );
+        assert_eq!(parse("http://[::1]:8899"), Some(("::1".to_string(), 8899)));
+        assert_eq!(parse("solana-validator:8899"), None);
+        assert_eq!(parse("ftp://solana-validator"), None);
+    }
+
+    #[test]
+    fn only_host_names_and_loopback_can_be_relayed() {
+        assert!(ValidatorRelay::new(&["http://127.0.0.1:8899"]).is_ok());
+        assert!(ValidatorRelay::new(&["http://10.1.2.3:8899"]).is_err());
+    }
+
+    #[test]
+    fn host_names_are_pointed_at_loopback() {
+        let relay = ValidatorRelay::new(&["http://localhost:8899"]).unwrap();
+        assert_eq!(relay.endpoints[0].listen, "127.0.0.1:8899".parse().unwrap());
+
+        let hosts = hosts_file(&["solana-validator".to_string()]).unwrap();
+        let hosts = fs::read_to_string(hosts.path()).unwrap();
+        assert!(hosts
+            .lines()
+            .any(|line| line == "127.0.0.1 solana-validator"));
+    }
+}
@@ -0,0 +1,170 @@
+use crate::{process::RunLimits, CompileError};
+use regex::Regex;
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+// Longest a backoff sleeps before checking again whether the job was cancelled
+const SLEEP_SLICE: Duration = Duration::from_millis(50);
+
+// Errors a run can hit because the shared validator is briefly unavailable, e.g. right
+// after a reset, rather than because the code is wrong. Matched case-insensitively
+// against the run's error output.
+pub const DEFAULT_TRANSIENT_PATTERNS: &str = concat!(
+    r"(?i)node is behind|connection refused|connection reset|error sending request",
+    r"|blockhash not found|service unavailable|429 too many requests",
+);
+
+// When and how often a failed run is tried again
+pub struct RetryPolicy {
+    // Most re-runs after the first attempt
+    max_retries: u32,
+    // Wait before the first re-run; doubled for every further one
+    base_delay: Duration,
+    transient: Regex,
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32, base_delay: Duration, transient: Regex) -> Self {
+        RetryPolicy {
+            max_retries,
+            base_delay,
+            transient,
+        }
+    }
+
+    // Only runtime failures can be transient; a compile error will fail the same way
+    // every time
+    fn is_transient(&self, error: &CompileError) -> bool {
+        matches!(error, CompileError::Run(output) if self.transient.is_match(output))
+    }
+
+    // Run `attempt` until it succeeds, fails for a reason that isn't transient, or has
+    // been retried `max_retries` times, sleeping with exponential backoff in between.
+    // Gives up early with the last failure once the job is cancelled, or when the
+    // backoff would reach the job's deadline. Counts the re-runs in `retries_used`.
+    pub fn run<T>(
+        &self,
+        retries_used: &mut u32,
+        limits: &RunLimits,
+        mut attempt: impl FnMut() -> Result<T, CompileError>,
+    ) -> Result<T, CompileError> {
+        loop {
+            let result = attempt();
+            match &result {
+                Err(error) if *retries_used < self.max_retries && self.is_transient(error) => {
+                    let delay = self.base_delay * 2u32.saturating_pow(*retries_used);
+                    // A re-run starting at the deadline would be killed right away
+                    if limits
+                        .deadline
+                        .is_some_and(|deadline| Instant::now() + delay >= deadline)
+                    {
+                        return result;
+                    }
+                    println!(
+                        "Run failed with a transient validator error, retrying in {}ms ({}/{})",
+                        delay.as_millis(),
+                        *retries_used + 1,
+                        self.max_retries
+                    );
+                    if !sleep(delay, limits) {
+                        return result;
+                    }
+                    *retries_used += 1;
+                }
+                _ => return result,
+            }
+        }
+    }
+}
+
+// Sleep for `delay` unless the job is cancelled in the meantime; returns whether it
+// slept the whole time
+fn sleep(delay: Duration, limits: &RunLimits) -> bool {
+    let until = Instant::now() + delay;
+    loop {
+        if limits.is_cancelled() {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= until {
+            return true;
+        }
+        thread::sleep((until - now).min(SLEEP_SLICE));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::Cancellation;
+    use std::sync::Arc;
+
+    fn policy(base_delay: Duration) -> RetryPolicy {
+        let transient = Regex::new(DEFAULT_TRANSIENT_PATTERNS).unwrap();
+        RetryPolicy::new(3, base_delay, transient)
+    }
+
+    fn transient_failure() -> Result<(), CompileError> {
+        Err(CompileError::Run("Connection refused".to_string()))
+    }
+
+    #[test]
+    fn transient_failures_are_retried() {
+        let mut attempts = 0;
+        let mut retries_used = 0;
+        let result =
+            policy(Duration::from_millis(1)).run(&mut retries_used, &RunLimits::default(), || {
+                attempts += 1;
+                transient_failure()
+            });
+        assert!(result.is_err());
+        assert_eq!(attempts, 4);
+        assert_eq!(retries_used, 3);
+    }
+
+    #[test]
+    fn cancelling_stops_the_backoff() {
+        let cancel = Arc::new(Cancellation::default());
+        let limits = RunLimits {
+            cancel: Some(cancel.clone()),
+            ..RunLimits::default()
+        };
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            cancel.cancel();
+        });
+
+        let started = Instant::now();
+        let mut attempts = 0;
+        let mut retries_used = 0;
+        let result = policy(Duration::from_secs(30)).run(&mut retries_used, &limits, || {
+            attempts += 1;
+            transient_failure()
+        });
+        canceller.join().unwrap();
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(attempts, 1);
+        assert_eq!(retries_used, 0);
+    }
+
+    #[test]
+    fn no_retry_is_started_past_the_deadline() {
+        let limits = RunLimits {
+            deadline: Some(Instant::now() + Duration::from_secs(1)),
+            ..RunLimits::default()
+        };
+        let started = Instant::now();
+        let mut attempts = 0;
+        let mut retries_used = 0;
+        let result = policy(Duration::from_secs(30)).run(&mut retries_used, &limits, || {
+            attempts += 1;
+            transient_failure()
+        });
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(attempts, 1);
+    }
+}
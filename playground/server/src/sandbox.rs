@@ -0,0 +1,727 @@
+use crate::relay::{self, Listeners, RelayPlan, ValidatorRelay};
+use std::{
+    env,
+    ffi::CString,
+    fs, io, mem,
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::{ffi::OsStrExt, process::CommandExt},
+    },
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    ptr,
+    sync::OnceLock,
+};
+
+// How commands that build and run user code are confined. Set once at startup; until
+// then (or when sandboxing is off) `confine` leaves commands as they are.
+static SANDBOX: OnceLock<Sandbox> = OnceLock::new();
+
+// Directories hidden behind an empty tmpfs in every sandbox: other users' workspaces
+// in the temp directories, and home directories with keys and credentials
+const HIDDEN_DIRS: [&str; 5] = ["/tmp", "/var/tmp", "/dev/shm", "/home", "/root"];
+
+// The user and group sandboxed commands run as, `nobody` and `nogroup`
+const SANDBOX_ID: u32 = 65534;
+
+// From <linux/mount.h>, which older libc versions don't cover
+const OPEN_TREE_CLONE: libc::c_uint = 1;
+const AT_RECURSIVE: libc::c_uint = 0x8000;
+const MOVE_MOUNT_F_EMPTY_PATH: libc::c_uint = 4;
+const MOUNT_ATTR_RDONLY: u64 = 0x1;
+const MOUNT_ATTR_IDMAP: u64 = 0x0010_0000;
+
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+// Whether sandboxing was asked for, from the SANDBOX variable
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SandboxMode {
+    Off,
+    // Sandbox when the kernel and the server's privileges allow it, otherwise warn
+    Auto,
+    // Refuse to start without a working sandbox
+    Required,
+}
+
+impl SandboxMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "off" => Some(SandboxMode::Off),
+            "auto" => Some(SandboxMode::Auto),
+            "required" => Some(SandboxMode::Required),
+            _ => None,
+        }
+    }
+}
+
+// What network sandboxed commands get, from the SANDBOX_NETWORK variable
+pub enum SandboxNetwork {
+    // The server's own
+    Shared,
+    // None at all, not even the validator
+    Isolated,
+    // Only the validator, through a relay
+    Validator(ValidatorRelay),
+}
+
+// Each sandboxed command runs in its own mount and PID namespace (and optionally its
+// own network namespace) in which:
+// - the root filesystem is read-only,
+// - `hidden` directories are replaced by empty, writable tmpfs mounts,
+// - the command's workspace and `writable` are bind-mounted back read-write,
+// - `readable` directories are bind-mounted back read-only,
+// - /proc only shows the command's own processes,
+// - the command runs as SANDBOX_ID, without capabilities and unable to gain any.
+// The bind mounts are idmapped so that SANDBOX_ID owns what the server owns in them.
+pub struct Sandbox {
+    hidden: Vec<PathBuf>,
+    // Directories every sandboxed command needs to write, e.g. cargo's package cache.
+    // Commands that run user programs get them read-only.
+    writable: Vec<PathBuf>,
+    // Directories every sandboxed command needs to read, e.g. the Rust toolchain
+    readable: Vec<PathBuf>,
+    network: SandboxNetwork,
+    // The user namespace the bind mounts are idmapped with
+    idmap: io::Result<OwnedFd>,
+}
+
+impl Sandbox {
+    // The standard sandbox, additionally hiding `private` directories such as the archive
+    // and letting every command read `shared` ones such as the command shims
+    pub fn new(private: &[PathBuf], shared: &[PathBuf], network: SandboxNetwork) -> Self {
+        let home = env::var_os("HOME").map(PathBuf::from);
+        let tool_dir = |variable: &str, default: &str| {
+            env::var_os(variable)
+                .map(PathBuf::from)
+                .or_else(|| home.as_ref().map(|home| home.join(default)))
+        };
+
+        let mut hidden: Vec<PathBuf> = HIDDEN_DIRS.iter().map(PathBuf::from).collect();
+        hidden.extend(private.iter().cloned());
+        let mut readable: Vec<PathBuf> = tool_dir("RUSTUP_HOME", ".rustup").into_iter().collect();
+        readable.extend(shared.iter().cloned());
+        Sandbox {
+            hidden,
+            writable: tool_dir("CARGO_HOME", ".cargo").into_iter().collect(),
+            readable,
+            network,
+            idmap: idmap_namespace(),
+        }
+    }
+
+    // Make this the sandbox for every later `confine`, after checking that it works
+    pub fn install(self) -> io::Result<()> {
+        if let Err(err) = &self.idmap {
+            return Err(io::Error::new(
+                err.kind(),
+                format!("couldn't create the idmapping user namespace: {}", err),
+            ));
+        }
+        let dir = tempfile::tempdir()?;
+        let mut command = Command::new("true");
+        command.current_dir(dir.path());
+        self.apply(&mut command, &[dir.path()], &[], Access::Build);
+        let status = command.status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "sandboxed test command failed: {}",
+                status
+            )));
+        }
+
+        let _ = SANDBOX.set(self);
+        Ok(())
+    }
+
+    fn apply(&self, command: &mut Command, writable: &[&Path], readable: &[&Path], access: Access) {
+        let mut flags = libc::CLONE_NEWNS | libc::CLONE_NEWPID;
+        let relay = match &self.network {
+            _ if access == Access::SharedNetwork => None,
+            SandboxNetwork::Shared => None,
+            SandboxNetwork::Isolated => {
+                flags |= libc::CLONE_NEWNET;
+                None
+            }
+            SandboxNetwork::Validator(relay) => {
+                flags |= libc::CLONE_NEWNET;
+                Some(relay.plan())
+            }
+        };
+
+        // Paths are resolved now, so that symlinks (e.g. a workspace's node_modules)
+        // are bound where they point and missing directories are skipped
+        let resolve = |paths: Vec<&Path>| -> Vec<PathBuf> {
+            paths
+                .into_iter()
+                .filter_map(|path| path.canonicalize().ok())
+                .collect()
+        };
+        let mut hidden = resolve(self.hidden.iter().map(PathBuf::as_path).collect());
+        // A directory inside another hidden one is covered up already
+        let covered: Vec<PathBuf> = hidden
+            .iter()
+            .filter(|path| {
+                hidden
+                    .iter()
+                    .any(|other| other != *path && path.starts_with(other))
+            })
+            .cloned()
+            .collect();
+        hidden.retain(|path| !covered.contains(path));
+        let (own_writable, own_readable) = match access {
+            Access::Run => (&[][..], [&self.readable[..], &self.writable[..]].concat()),
+            _ => (&self.writable[..], self.readable.clone()),
+        };
+        let writable = resolve(
+            own_writable
+                .iter()
+                .map(PathBuf::as_path)
+                .chain(writable.iter().copied())
+                .collect(),
+        );
+        let readable = resolve(
+            own_readable
+                .iter()
+                .map(PathBuf::as_path)
+                .chain(readable.iter().copied())
+                .collect(),
+        );
+
+        let mut binds: Vec<Bind> = writable
+            .iter()
+            .map(|path| Bind::new(path, &hidden, false))
+            .chain(readable.iter().map(|path| Bind::new(path, &hidden, true)))
+            .collect();
+        // Parents first, so a writable directory inside a read-only one stays writable
+        binds.sort_by_key(|bind| bind.depth);
+
+        let plan = Plan {
+            flags,
+            hidden: hidden.iter().map(|path| c_path(path)).collect(),
+            binds,
+            cwd: command
+                .get_current_dir()
+                .and_then(|dir| dir.canonicalize().ok())
+                .map(|dir| c_path(&dir)),
+            relay,
+            idmap: self.idmap.as_ref().map_or(-1, AsRawFd::as_raw_fd),
+        };
+        // Filled in the child, which must not allocate
+        let mut fds = Vec::with_capacity(plan.binds.len());
+
+        // SAFETY: `enter` only makes system calls on data prepared above and doesn't
+        // allocate, as required between fork and exec
+        unsafe {
+            command.pre_exec(move || enter(&plan, &mut fds));
+        }
+    }
+}
+
+// Whether commands are being sandboxed
+pub fn is_installed() -> bool {
+    SANDBOX.get().is_some()
+}
+
+// Run `command` in the sandbox, if sandboxing is enabled, with `writable` and
+// `readable` directories made available in addition to the sandbox's own
+pub fn confine<'a>(
+    command: &'a mut Command,
+    writable: &[&Path],
+    readable: &[&Path],
+) -> &'a mut Command {
+    if let Some(sandbox) = SANDBOX.get() {
+        sandbox.apply(command, writable, readable, Access::Build);
+    }
+    command
+}
+
+// Like `confine`, for running a user's program: the sandbox's own writable
+// directories such as CARGO_HOME are only readable, so that the program can't change
+// what later builds use
+pub fn confine_run<'a>(
+    command: &'a mut Command,
+    writable: &[&Path],
+    readable: &[&Path],
+) -> &'a mut Command {
+    if let Some(sandbox) = SANDBOX.get() {
+        sandbox.apply(command, writable, readable, Access::Run);
+    }
+    command
+}
+
+// Like `confine`, but on the server's network whatever SANDBOX_NETWORK says, for
+// dependency installs that have to reach a package registry
+pub fn confine_sharing_network<'a>(
+    command: &'a mut Command,
+    writable: &[&Path],
+    readable: &[&Path],
+) -> &'a mut Command {
+    if let Some(sandbox) = SANDBOX.get() {
+        sandbox.apply(command, writable, readable, Access::SharedNetwork);
+    }
+    command
+}
+
+// What a sandboxed command may do beyond its own directories
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Access {
+    // Write the sandbox's writable directories
+    Build,
+    // Also use the server's network
+    SharedNetwork,
+    // Only read the sandbox's writable directories
+    Run,
+}
+
+// A user namespace that maps the server's user and group to SANDBOX_ID, to idmap the
+// bind mounts with. The namespace lives as long as the returned file descriptor.
+fn idmap_namespace() -> io::Result<OwnedFd> {
+    // A process has to create the namespace; it waits for its stdin to close
+    let mut holder = Command::new("cat");
+    holder.stdin(Stdio::piped()).stdout(Stdio::null());
+    // SAFETY: unshare is async-signal-safe and doesn't allocate
+    unsafe {
+        holder.pre_exec(|| check(libc::unshare(libc::CLONE_NEWUSER)).map(drop));
+    }
+    let mut holder = holder.spawn()?;
+    let proc = PathBuf::from(format!("/proc/{}", holder.id()));
+    // SAFETY: geteuid and getegid can't fail
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let namespace = fs::write(proc.join("uid_map"), format!("{} {} 1\n", uid, SANDBOX_ID))
+        .and_then(|()| fs::write(proc.join("gid_map"), format!("{} {} 1\n", gid, SANDBOX_ID)))
+        .and_then(|()| fs::File::open(proc.join("ns/user")))
+        .map(OwnedFd::from);
+    drop(holder.stdin.take());
+    holder.wait()?;
+    namespace
+}
+
+// Everything the child needs to set up the sandbox, prepared before forking
+struct Plan {
+    flags: libc::c_int,
+    hidden: Vec<CString>,
+    binds: Vec<Bind>,
+    cwd: Option<CString>,
+    relay: Option<RelayPlan>,
+    idmap: libc::c_int,
+}
+
+// A directory to mount back into the sandbox
+struct Bind {
+    path: CString,
+    // Directories to create first when the path lies in a hidden directory, whose
+    // tmpfs starts out empty
+    mountpoint_dirs: Vec<CString>,
+    read_only: bool,
+    depth: usize,
+}
+
+impl Bind {
+    fn new(path: &Path, hidden: &[PathBuf], read_only: bool) -> Self {
+        let mountpoint_dirs = hidden
+            .iter()
+            .find(|hidden| path.starts_with(hidden))
+            .map(|hidden| {
+                let mut dirs: Vec<CString> = path
+                    .ancestors()
+                    .take_while(|ancestor| ancestor != hidden)
+                    .map(c_path)
+                    .collect();
+                dirs.reverse();
+                dirs
+            })
+            .unwrap_or_default();
+        Bind {
+            path: c_path(path),
+            mountpoint_dirs,
+            read_only,
+            depth: path.components().count(),
+        }
+    }
+}
+
+fn c_path(path: &Path) -> CString {
+    CString::new(path.as_os_str().as_bytes()).expect("paths can't contain NUL bytes")
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+// Set up the sandbox in the freshly forked child. On success the caller goes on to
+// exec the command, as PID 1 of the new PID namespace.
+unsafe fn enter(plan: &Plan, fds: &mut Vec<libc::c_int>) -> io::Result<()> {
+    let root = c"/";
+    let tmpfs = c"tmpfs";
+    let tmpfs_options = c"mode=1777";
+
+    // The relay forwards from the server's network namespace, so keep a way back to it
+    let server_network = match &plan.relay {
+        Some(_) => check(libc::open(
+            c"/proc/self/ns/net".as_ptr(),
+            libc::O_RDONLY | libc::O_CLOEXEC,
+        ))?,
+        None => -1,
+    };
+    check(libc::unshare(plan.flags))?;
+    let listeners = match &plan.relay {
+        Some(relay) => Some(relay::listen(relay)?),
+        None => None,
+    };
+    // Keep the mounts below from propagating back to the host
+    check(libc::mount(
+        ptr::null(),
+        root.as_ptr(),
+        ptr::null(),
+        libc::MS_REC | libc::MS_PRIVATE,
+        ptr::null(),
+    ))?;
+
+    // Hold on to the directories to mount back before anything covers them up
+    fds.clear();
+    for bind in &plan.binds {
+        fds.push(check(libc::open(
+            bind.path.as_ptr(),
+            libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        ))?);
+    }
+    let hosts = match plan.relay.as_ref().and_then(|relay| relay.hosts.as_ref()) {
+        Some(hosts) => check(libc::open(hosts.as_ptr(), libc::O_PATH | libc::O_CLOEXEC))?,
+        None => -1,
+    };
+
+    check(libc::mount(
+        root.as_ptr(),
+        root.as_ptr(),
+        ptr::null(),
+        libc::MS_BIND | libc::MS_REC,
+        ptr::null(),
+    ))?;
+    check(libc::mount(
+        ptr::null(),
+        root.as_ptr(),
+        ptr::null(),
+        libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY,
+        ptr::null(),
+    ))?;
+
+    for hidden in &plan.hidden {
+        check(libc::mount(
+            tmpfs.as_ptr(),
+            hidden.as_ptr(),
+            tmpfs.as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV,
+            tmpfs_options.as_ptr().cast(),
+        ))?;
+    }
+
+    for (bind, &fd) in plan.binds.iter().zip(fds.iter()) {
+        for dir in &bind.mountpoint_dirs {
+            // Fails harmlessly when the directory is already there
+            libc::mkdir(dir.as_ptr(), 0o755);
+        }
+        // A detached copy of the directory's mounts, idmapped and given the access
+        // this directory should have before it's attached
+        let tree = check(libc::syscall(
+            libc::SYS_open_tree,
+            fd,
+            c"".as_ptr(),
+            OPEN_TREE_CLONE
+                | libc::O_CLOEXEC as libc::c_uint
+                | libc::AT_EMPTY_PATH as libc::c_uint
+                | AT_RECURSIVE,
+        ) as libc::c_int)?;
+        let attr = MountAttr {
+            attr_set: MOUNT_ATTR_IDMAP | if bind.read_only { MOUNT_ATTR_RDONLY } else { 0 },
+            // Binds come from the root, which is read-only by now
+            attr_clr: if bind.read_only { 0 } else { MOUNT_ATTR_RDONLY },
+            propagation: 0,
+            userns_fd: plan.idmap as u64,
+        };
+        check(libc::syscall(
+            libc::SYS_mount_setattr,
+            tree,
+            c"".as_ptr(),
+            libc::AT_EMPTY_PATH as libc::c_uint | AT_RECURSIVE,
+            &attr as *const MountAttr,
+            mem::size_of::<MountAttr>(),
+        ) as libc::c_int)?;
+        check(libc::syscall(
+            libc::SYS_move_mount,
+            tree,
+            c"".as_ptr(),
+            libc::AT_FDCWD,
+            bind.path.as_ptr(),
+            MOVE_MOUNT_F_EMPTY_PATH,
+        ) as libc::c_int)?;
+        libc::close(tree);
+        libc::close(fd);
+    }
+
+    // Point the validator's host name at the relay on loopback
+    if hosts != -1 {
+        let etc_hosts = c"/etc/hosts";
+        let mut source = [0u8; 32];
+        check(libc::mount(
+            fd_path(hosts, &mut source),
+            etc_hosts.as_ptr(),
+            ptr::null(),
+            libc::MS_BIND,
+            ptr::null(),
+        ))?;
+        check(libc::mount(
+            ptr::null(),
+            etc_hosts.as_ptr(),
+            ptr::null(),
+            libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY,
+            ptr::null(),
+        ))?;
+        libc::close(hosts);
+    }
+
+    // The working directory was entered before the mounts changed, so enter it again
+    // to get the sandboxed view of it
+    if let Some(cwd) = &plan.cwd {
+        check(libc::chdir(cwd.as_ptr()))?;
+    }
+
+    // Only children join the new PID namespace, so fork once more. This process stays
+    // behind, waits for the command and exits with its status.
+    let pid = check(libc::fork())?;
+    if pid == 0 {
+        check(libc::mount(
+            c"proc".as_ptr(),
+            c"/proc".as_ptr(),
+            c"proc".as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+            ptr::null(),
+        ))?;
+        // Give up root before the command starts, so it can't undo the mounts above.
+        // Changing the user drops every capability, and no_new_privs keeps setuid
+        // binaries from bringing any back.
+        check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+        check(libc::setgroups(0, ptr::null()))?;
+        check(libc::setresgid(SANDBOX_ID, SANDBOX_ID, SANDBOX_ID))?;
+        check(libc::setresuid(SANDBOX_ID, SANDBOX_ID, SANDBOX_ID))?;
+        // Changing the user also cleared this
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+        return Ok(());
+    }
+    let relay = plan.relay.as_ref().zip(listeners.as_ref());
+    if relay.is_some() && libc::setns(server_network, libc::CLONE_NEWNET) != 0 {
+        libc::kill(pid, libc::SIGKILL);
+    }
+    wait_and_exit(pid, relay)
+}
+
+// Wait for the sandboxed command, relaying its validator connections if it has a relay,
+// and end this process the same way the command ended
+unsafe fn wait_and_exit(pid: libc::pid_t, relay: Option<(&RelayPlan, &Listeners)>) -> ! {
+    // Close everything but stdio and the relay's sockets, in particular the pipe std uses
+    // to learn whether the exec succeeded, which would otherwise stay open until the
+    // command finishes
+    let mut keep = [u32::MAX; 8];
+    let listeners = relay.map(|(_, listeners)| listeners.fds()).unwrap_or(&[]);
+    for (slot, &fd) in keep.iter_mut().zip(listeners) {
+        *slot = fd as u32;
+    }
+    keep.sort_unstable();
+    let mut first = 3;
+    for &fd in &keep {
+        if fd == u32::MAX {
+            close_range(first, u32::MAX);
+            break;
+        }
+        if fd > first {
+            close_range(first, fd - 1);
+        }
+        first = first.max(fd + 1);
+    }
+
+    if let Some((plan, listeners)) = relay {
+        relay::serve(plan, listeners, pid);
+    }
+
+    let mut status = 0;
+    while libc::waitpid(pid, &mut status, 0) == -1 {
+        if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+            libc::_exit(1);
+        }
+    }
+    if libc::WIFSIGNALED(status) {
+        let signal = libc::WTERMSIG(status);
+        libc::signal(signal, libc::SIG_DFL);
+        libc::kill(libc::getpid(), signal);
+    }
+    libc::_exit(libc::WEXITSTATUS(status))
+}
+
+// Close the file descriptors from `first` to `last`, both included
+unsafe fn close_range(first: u32, last: u32) {
+    if libc::syscall(libc::SYS_close_range, first, last, 0u32) != 0 {
+        for fd in first..=last.min(1023) {
+            libc::close(fd as libc::c_int);
+        }
+    }
+}
+
+// Write "/proc/self/fd/<fd>" into `buffer` without allocating
+fn fd_path(fd: libc::c_int, buffer: &mut [u8; 32]) -> *const libc::c_char {
+    let prefix = b"/proc/self/fd/";
+    buffer[..prefix.len()].copy_from_slice(prefix);
+    let mut digits = [0u8; 10];
+    let mut count = 0;
+    let mut rest = fd as u32;
+    loop {
+        digits[count] = b'0' + (rest % 10) as u8;
+        count += 1;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+    for (index, digit) in digits[..count].iter().rev().enumerate() {
+        buffer[prefix.len() + index] = *digit;
+    }
+    buffer[prefix.len() + count] = 0;
+    buffer.as_ptr().cast()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, io::Read, net::TcpListener, process::Output};
+
+    // Run `script` with bash in `sandbox`, in a workspace of its own, or None when this
+    // machine can't set up the sandbox (e.g. no CAP_SYS_ADMIN)
+    fn run_in(sandbox: &Sandbox, workspace: &Path, script: &str) -> Option<Output> {
+        run_with(sandbox, Access::Build, workspace, script)
+    }
+
+    fn run_with(
+        sandbox: &Sandbox,
+        access: Access,
+        workspace: &Path,
+        script: &str,
+    ) -> Option<Output> {
+        let mut probe = Command::new("true");
+        probe.current_dir(workspace);
+        sandbox.apply(&mut probe, &[workspace], &[], access);
+        if !probe.status().is_ok_and(|status| status.success()) {
+            println!("Skipping: the sandbox can't be set up here");
+            return None;
+        }
+        let mut command = Command::new("bash");
+        command.current_dir(workspace).args(["-c", script]);
+        sandbox.apply(&mut command, &[workspace], &[], access);
+        Some(command.output().unwrap())
+    }
+
+    #[test]
+    fn commands_run_without_privileges() {
+        let workspace = tempfile::tempdir().unwrap();
+        let sandbox = Sandbox::new(&[], &[], SandboxNetwork::Shared);
+        let script = "id -u; id -g; grep CapEff /proc/self/status; echo own > own.txt; \
+             umount /tmp && echo unmounted; \
+             mount -o remount,rw / && echo remounted; true";
+        let Some(output) = run_in(&sandbox, workspace.path(), script) else {
+            return;
+        };
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "65534\n65534\nCapEff:\t0000000000000000\n",
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        // What the command writes belongs to the server outside the sandbox
+        let metadata = std::os::unix::fs::MetadataExt::uid(
+            &fs::metadata(workspace.path().join("own.txt")).unwrap(),
+        );
+        // SAFETY: geteuid can't fail
+        assert_eq!(metadata, unsafe { libc::geteuid() });
+    }
+
+    #[test]
+    fn programs_cant_change_the_toolchain() {
+        let workspace = tempfile::tempdir().unwrap();
+        let toolchain = tempfile::tempdir().unwrap();
+        let sandbox = Sandbox {
+            writable: vec![toolchain.path().to_path_buf()],
+            ..Sandbox::new(&[], &[], SandboxNetwork::Shared)
+        };
+        let script = format!("echo changed > {}/file", toolchain.path().display());
+
+        let Some(output) = run_with(&sandbox, Access::Run, workspace.path(), &script) else {
+            return;
+        };
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Read-only file system"));
+        assert!(!toolchain.path().join("file").exists());
+
+        let output = run_with(&sandbox, Access::Build, workspace.path(), &script).unwrap();
+        assert!(output.status.success());
+        assert!(toolchain.path().join("file").exists());
+    }
+
+    #[test]
+    fn files_outside_the_workspace_cant_be_read() {
+        let workspace = tempfile::tempdir().unwrap();
+        let other = tempfile::tempdir().unwrap();
+        fs::write(workspace.path().join("own.txt"), "own").unwrap();
+        fs::write(other.path().join("secret.txt"), "secret").unwrap();
+
+        let sandbox = Sandbox::new(&[], &[], SandboxNetwork::Shared);
+        let script = format!(
+            "cat own.txt; echo; cat {}/secret.txt",
+            other.path().display()
+        );
+        let Some(output) = run_in(&sandbox, workspace.path(), &script) else {
+            return;
+        };
+        assert!(!output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "own\n");
+        assert!(String::from_utf8_lossy(&output.stderr).contains("No such file or directory"));
+    }
+
+    #[test]
+    fn the_validator_is_the_only_host_reachable() {
+        let validator = TcpListener::bind("127.0.0.1:0").unwrap();
+        let elsewhere = TcpListener::bind("127.0.0.1:0").unwrap();
+        let validator_port = validator.local_addr().unwrap().port();
+        let elsewhere_port = elsewhere.local_addr().unwrap().port();
+        let relay =
+            ValidatorRelay::new(&[&format!("http://127.0.0.1:{}", validator_port)]).unwrap();
+        let received = std::thread::spawn(move || {
+            let (mut connection, _) = validator.accept().unwrap();
+            let mut received = String::new();
+            connection.read_to_string(&mut received).unwrap();
+            received
+        });
+
+        let workspace = tempfile::tempdir().unwrap();
+        let sandbox = Sandbox::new(&[], &[], SandboxNetwork::Validator(relay));
+        let script = format!(
+            "echo hello > /dev/tcp/127.0.0.1/{} && ! echo hello > /dev/tcp/127.0.0.1/{}",
+            validator_port, elsewhere_port
+        );
+        let Some(output) = run_in(&sandbox, workspace.path(), &script) else {
+            return;
+        };
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(received.join().unwrap(), "hello\n");
+    }
+}
@@ -0,0 +1,238 @@
+use crate::{
+    elapsed_ms,
+    extract::JobJson,
+    process::{self, CancelOnDrop, Cancellation, RunLimits},
+    workspace::{ActiveWorkspaces, Workspace},
+    AppState,
+};
+use axum::{extract::State, http::StatusCode, Json};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde::{Deserialize, Serialize};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+// Building against the SBF target is much slower than a normal build, especially the
+// first time, when solana-program itself has to be compiled
+const SBF_BUILD_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Deserialize)]
+pub struct SbfBuildRequest {
+    // Source of the program's src/lib.rs
+    code: String,
+}
+
+#[derive(Serialize)]
+pub struct SbfBuildResponse {
+    success: bool,
+    // The deployable `.so`, base64 encoded
+    program_bytes: Option<String>,
+    // Size of the `.so` in bytes
+    size: Option<u64>,
+    // Address of the program keypair the program would be deployed to
+    program_id: Option<String>,
+    error: Option<String>,
+    duration_ms: u64,
+}
+
+// A built program, as read back from the target/deploy directory
+struct SbfArtifact {
+    bytes: Vec<u8>,
+    program_id: String,
+}
+
+type SbfReply = (StatusCode, Json<SbfBuildResponse>);
+
+/// Build an on-chain program
+///
+/// Writes the code to src/lib.rs in a copy of the SBF template, builds it with
+/// `cargo build-sbf` and returns the resulting program.
+pub async fn build_sbf_handler(
+    State(app_state): State<AppState>,
+    JobJson(request): JobJson<SbfBuildRequest>,
+) -> SbfReply {
+    println!("Received SBF build request");
+    let started = Instant::now();
+    if let Some(reason) = app_state.sbf_unavailable {
+        let message = reason.to_string();
+        return failure(StatusCode::SERVICE_UNAVAILABLE, message, started);
+    }
+
+    // Builds share the template's target directory, where every copy's program has the
+    // same name, so they run one at a time. The build task holds the lock, so a build
+    // that is still being cancelled keeps the next one waiting.
+    let build_lock = app_state.sbf_build_lock.clone().lock_owned().await;
+    let _slot = app_state.job_queue.acquire().await;
+
+    // Kill the build if the client disconnects, as for Rust jobs
+    let cancel = Arc::new(Cancellation::default());
+    let guard = CancelOnDrop::new(cancel.clone());
+
+    let template_sbf = app_state.template_sbf.clone();
+    let active_workspaces = app_state.active_workspaces.clone();
+    let build = tokio::task::spawn_blocking(move || {
+        let _build = build_lock;
+        build_program(&template_sbf, &active_workspaces, &request.code, cancel)
+    });
+    let build = build.await;
+    guard.disarm();
+    match build {
+        Ok(Ok(artifact)) => {
+            let size = artifact.bytes.len() as u64;
+            let response = SbfBuildResponse {
+                success: true,
+                program_bytes: Some(BASE64_STANDARD.encode(&artifact.bytes)),
+                size: Some(size),
+                program_id: Some(artifact.program_id),
+                error: None,
+                duration_ms: elapsed_ms(started),
+            };
+            (StatusCode::OK, Json(response))
+        }
+        Ok(Err((status, message))) => failure(status, message, started),
+        Err(err) => failure(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Task panic: {}", err),
+            started,
+        ),
+    }
+}
+
+fn build_program(
+    template_sbf: &str,
+    active_workspaces: &ActiveWorkspaces,
+    code: &str,
+    cancel: Arc<Cancellation>,
+) -> Result<SbfArtifact, (StatusCode, String)> {
+    let internal = |err: std::io::Error| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
+
+    // Without the Solana toolchain there is nothing to build with
+    let toolchain = Command::new("cargo-build-sbf").arg("--version").output();
+    if !toolchain.is_ok_and(|output| output.status.success()) {
+        return Err((
+            StatusCode::NOT_IMPLEMENTED,
+            "cargo-build-sbf is not installed on this server; install the Solana CLI to build programs"
+                .to_string(),
+        ));
+    }
+
+    let workspace = Workspace::sbf_copy(template_sbf, active_workspaces).map_err(internal)?;
+    fs::write(workspace.dir.join("src/lib.rs"), code).map_err(internal)?;
+    let (writable, readable) = toolchain_dirs();
+    let writable: Vec<&Path> = writable.iter().map(PathBuf::as_path).collect();
+    let readable: Vec<&Path> = readable.iter().map(PathBuf::as_path).collect();
+    let output = process::run(
+        workspace.confine_with(
+            Command::new("cargo")
+                .current_dir(&workspace.dir)
+                .envs(workspace.envs.iter().cloned())
+                .arg("build-sbf"),
+            &writable,
+            &readable,
+        ),
+        RunLimits {
+            deadline: Some(Instant::now() + SBF_BUILD_TIMEOUT),
+            idle_timeout: None,
+            cancel: Some(cancel),
+        },
+        &mut |_, _| {},
+    )
+    .map_err(internal)?;
+
+    if output.timed_out {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Build timed out after {} seconds",
+                SBF_BUILD_TIMEOUT.as_secs()
+            ),
+        ));
+    }
+    if !output.status.success() {
+        // cargo-build-sbf reports toolchain problems (e.g. a platform-tools version that
+        // doesn't support the requested Solana version) on both streams
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "cargo build-sbf failed:\n{}{}",
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            ),
+        ));
+    }
+
+    // The artifacts are named after the library, i.e. the package name with `_`s
+    let name = package_name(&workspace.dir)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?
+        .replace('-', "_");
+    let deploy = Path::new(template_sbf).join("target/deploy");
+    let bytes = fs::read(deploy.join(format!("{}.so", name))).map_err(internal)?;
+    let keypair =
+        fs::read_to_string(deploy.join(format!("{}-keypair.json", name))).map_err(internal)?;
+
+    let program_id =
+        program_id(&keypair).map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err))?;
+
+    Ok(SbfArtifact { bytes, program_id })
+}
+
+// The Solana toolchain is usually installed in the home directory, which the sandbox
+// hides: the CLI's release directory, found through cargo-build-sbf on PATH, and the
+// platform tools it downloads into ~/.cache/solana. Returns (writable, readable).
+fn toolchain_dirs() -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let writable = env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join(".cache/solana"))
+        .into_iter()
+        .collect();
+    let readable = env::var_os("PATH")
+        .iter()
+        .flat_map(env::split_paths)
+        .map(|dir| dir.join("cargo-build-sbf"))
+        .find(|path| path.is_file())
+        .and_then(|path| path.canonicalize().ok())
+        // <release>/bin/cargo-build-sbf
+        .and_then(|path| path.ancestors().nth(2).map(Path::to_path_buf))
+        .into_iter()
+        .collect();
+    (writable, readable)
+}
+
+fn package_name(template: &Path) -> Result<String, String> {
+    let manifest =
+        fs::read_to_string(template.join("Cargo.toml")).map_err(|err| err.to_string())?;
+    let manifest = manifest
+        .parse::<toml::Table>()
+        .map_err(|err| err.to_string())?;
+    manifest
+        .get("package")
+        .and_then(|package| package.get("name"))
+        .and_then(|name| name.as_str())
+        .map(|name| name.to_string())
+        .ok_or_else(|| "SBF template has no package name".to_string())
+}
+
+// A keypair file is a JSON array of the 32 secret key bytes followed by the 32 public
+// key bytes; the program id is the public half in base58
+fn program_id(keypair: &str) -> Result<String, String> {
+    let bytes: Vec<u8> = serde_json::from_str(keypair).map_err(|err| err.to_string())?;
+    if bytes.len() != 64 {
+        return Err(format!("Unexpected program keypair length {}", bytes.len()));
+    }
+    Ok(bs58::encode(&bytes[32..]).into_string())
+}
+
+fn failure(status: StatusCode, message: String, started: Instant) -> SbfReply {
+    let response = SbfBuildResponse {
+        success: false,
+        program_bytes: None,
+        size: None,
+        program_id: None,
+        error: Some(message),
+        duration_ms: elapsed_ms(started),
+    };
+    (status, Json(response))
+}
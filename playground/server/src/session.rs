@@ -0,0 +1,315 @@
+use crate::{
+    finish_job, job_response,
+    process::{CancelOnDrop, Cancellation, RunLimits, Stream},
+    rewrite_validator_urls, run_rust, run_typescript, validate_args,
+    workspace::Workspace,
+    AppState, CargoProfile, CompileResponse, JobEvent, JobReport, Language, OutputEncoding, ProgramInput,
+    RustEntrypoint, TS_ENTRYPOINT,
+    EXECUTION_TIMEOUT,
+};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    response::Response,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, sync::Arc, time::Instant};
+use tokio::sync::mpsc;
+
+// Query parameters accepted when opening a session, e.g. `/ws?language=typescript`
+#[derive(Deserialize)]
+pub struct SessionParams {
+    language: Option<Language>,
+}
+
+// Messages a client can send over the socket
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum ClientMessage {
+    Run {
+        code: String,
+        args: Option<Vec<String>>,
+        #[serde(default)]
+        verbose: bool,
+    },
+}
+
+// Frames the server sends back over the socket
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    // The workspace is set up and the session is ready for `run` messages
+    Ready { language: Language },
+    Stdout { data: String },
+    Stderr { data: String },
+    // Another unit of a Rust build finished. `total` and `percent` are null while the
+    // number of units in the build isn't known yet.
+    Progress {
+        compiled: u64,
+        total: Option<u64>,
+        percent: Option<u64>,
+    },
+    // A run finished; same shape as the /rust and /typescript responses
+    Result(CompileResponse),
+    // The message couldn't be handled, e.g. it wasn't valid JSON, or the session had to
+    // end, e.g. because output was lost
+    Error { message: String },
+}
+
+/// Interactive session over a WebSocket
+///
+/// Each connection gets its own copy of the language template, kept for the lifetime
+/// of the connection so builds stay incremental between runs. Clients send
+/// `{ "action": "run", "code": "..." }` and receive the program's output as it is
+/// produced, followed by a `result` frame.
+pub async fn session_handler(
+    ws: WebSocketUpgrade,
+    State(app_state): State<AppState>,
+    Query(params): Query<SessionParams>,
+) -> Response {
+    let language = params.language.unwrap_or(Language::Rust);
+    ws.on_upgrade(move |socket| run_session(socket, app_state, language))
+}
+
+async fn run_session(mut socket: WebSocket, app_state: AppState, language: Language) {
+    println!("WebSocket session opened ({:?})", language);
+    let enabled = match language {
+        Language::Rust => app_state.rust_enabled,
+        Language::TypeScript => app_state.typescript_enabled,
+    };
+    if !enabled {
+        let message = format!("{:?} is disabled on this server", language);
+        let _ = send_frame(&mut socket, &ServerFrame::Error { message }).await;
+        return;
+    }
+    let unavailable = match language {
+        Language::Rust => app_state.rust_unavailable,
+        Language::TypeScript => app_state.typescript_unavailable,
+    };
+    if let Some(reason) = unavailable {
+        let message = reason.to_string();
+        let _ = send_frame(&mut socket, &ServerFrame::Error { message }).await;
+        return;
+    }
+
+    let template_rs = app_state.template_rs.clone();
+    let template_ts = app_state.template_ts.clone();
+    let active_workspaces = app_state.active_workspaces.clone();
+    let workspace = tokio::task::spawn_blocking(move || match language {
+        Language::Rust => Workspace::rust_copy(&template_rs, &active_workspaces),
+        Language::TypeScript => Workspace::typescript_copy(&template_ts, &active_workspaces),
+    })
+    .await;
+    let workspace = match workspace.map_err(|err| err.to_string()) {
+        Ok(Ok(workspace)) => Arc::new(workspace),
+        Ok(Err(err)) => return fail_session(&mut socket, err.to_string()).await,
+        Err(err) => return fail_session(&mut socket, err).await,
+    };
+
+    if send_frame(&mut socket, &ServerFrame::Ready { language })
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    // Messages are handled one at a time; a run must finish before the next starts
+    while let Some(Ok(message)) = socket.recv().await {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+
+        let sent = match serde_json::from_str::<ClientMessage>(&text) {
+            Ok(ClientMessage::Run {
+                code,
+                args,
+                verbose,
+            }) => {
+                let args = args.unwrap_or_default();
+                run_in_session(
+                    &mut socket,
+                    &workspace,
+                    language,
+                    code,
+                    args,
+                    verbose,
+                    &app_state,
+                )
+                .await
+            }
+            Err(err) => {
+                let message = format!("Invalid message: {}", err);
+                send_frame(&mut socket, &ServerFrame::Error { message }).await
+            }
+        };
+        if sent.is_err() {
+            break;
+        }
+    }
+
+    // The workspace is deleted once the last reference to it (this one, or a run
+    // still finishing in the background) is dropped
+    println!("WebSocket session closed ({:?})", language);
+}
+
+// Run one snippet in the session's workspace, streaming its output to the socket
+async fn run_in_session(
+    socket: &mut WebSocket,
+    workspace: &Arc<Workspace>,
+    language: Language,
+    code: String,
+    args: Vec<String>,
+    verbose: bool,
+    app_state: &AppState,
+) -> Result<(), axum::Error> {
+    let started = Instant::now();
+    let idle_timeout = app_state.idle_timeout;
+    let active_workspaces = app_state.active_workspaces.clone();
+    if let Err(error) = validate_args(&args) {
+        let (_, Json(response)) = job_response(
+            Err(error),
+            JobReport::default(),
+            OutputEncoding::Utf8,
+            started,
+        );
+        return send_frame(socket, &ServerFrame::Result(response)).await;
+    }
+
+    // Session runs share the server-wide job slots with the compile endpoints
+    let _slot = app_state.job_queue.acquire().await;
+
+    // Bounded, so that a client that reads slowly slows the program down rather than
+    // its output piling up here
+    let (sender, mut receiver) = mpsc::channel(app_state.stream_buffer);
+    let runtime = tokio::runtime::Handle::current();
+    let workspace = workspace.clone();
+    let build_total = app_state.build_total.clone();
+    let archived_source = app_state
+        .archive
+        .is_some()
+        .then(|| (code.clone(), args.clone()));
+    // Stop the run if the socket fails while its output is being streamed
+    let cancel = Arc::new(Cancellation::default());
+    let guard = CancelOnDrop::new(cancel.clone());
+    let deadline = Instant::now() + EXECUTION_TIMEOUT;
+    let limits = RunLimits {
+        deadline: Some(deadline),
+        idle_timeout,
+        cancel: Some(cancel),
+    };
+    let job = tokio::task::spawn_blocking(move || {
+        let code = rewrite_validator_urls(&code);
+        let mut dropped_frames = 0;
+        let mut on_event = |event: JobEvent| {
+            let frame = match event {
+                JobEvent::Output(stream, bytes) => {
+                    let data = String::from_utf8_lossy(bytes).to_string();
+                    match stream {
+                        Stream::Stdout => ServerFrame::Stdout { data },
+                        Stream::Stderr => ServerFrame::Stderr { data },
+                    }
+                }
+                JobEvent::BuildProgress(compiled) => {
+                    let total = build_total.get();
+                    // An estimated total can be off, so never report more than 100%
+                    let percent = total.map(|total| (compiled * 100 / total).min(100));
+                    ServerFrame::Progress {
+                        compiled,
+                        total,
+                        percent,
+                    }
+                }
+            };
+            // Waiting for room holds up reading the program's output, and with it the
+            // program. Past the deadline frames are dropped instead, so the run can
+            // still be killed, and the client is told its output is incomplete.
+            let send = tokio::time::timeout_at(deadline.into(), sender.send(frame));
+            if runtime.block_on(send).is_err() {
+                dropped_frames += 1;
+            }
+        };
+
+        let mut report = JobReport::default();
+        let result = match language {
+            Language::Rust => run_rust(
+                &workspace,
+                &code,
+                &ProgramInput {
+                    entrypoint: &RustEntrypoint::Main,
+                    profile: CargoProfile::Dev,
+                    update_deps: false,
+                    active_workspaces: &active_workspaces,
+                    args: &args,
+                    env: &BTreeMap::new(),
+                },
+                verbose,
+                &mut report,
+                limits,
+                &mut on_event,
+            ),
+            Language::TypeScript => run_typescript(
+                &workspace,
+                &code,
+                TS_ENTRYPOINT,
+                &BTreeMap::new(),
+                &mut report,
+                limits,
+                &mut |stream, bytes| on_event(JobEvent::Output(stream, bytes)),
+            ),
+        };
+        // The first finished build replaces the startup estimate with the exact count
+        if let Some(units) = report.build_units {
+            build_total.set(units);
+        }
+        (result, report, dropped_frames)
+    });
+
+    // The channel closes once the job finishes and drops its sender
+    while let Some(frame) = receiver.recv().await {
+        send_frame(socket, &frame).await?;
+    }
+
+    // Output was already streamed as text frames, so the result uses text as well
+    let job = job.await;
+    let dropped_frames = job.as_ref().map_or(0, |(_, _, dropped)| *dropped);
+    let job = job.map(|(result, report, _)| (result, report));
+    let (_, Json(mut response)) = finish_job(Ok(job), EXECUTION_TIMEOUT, OutputEncoding::Utf8, started);
+    guard.disarm();
+    if let (Some(archive), Some((code, args))) = (&app_state.archive, &archived_source) {
+        archive.record(language, code, args, &BTreeMap::new(), &mut response);
+    }
+
+    // The client never saw part of the output, so it can't trust what it has; end the
+    // session rather than go on as if nothing was missing
+    if dropped_frames > 0 {
+        let message = format!(
+            "Output truncated: {} frames were dropped because the client didn't read them before the {} second timeout",
+            dropped_frames,
+            EXECUTION_TIMEOUT.as_secs()
+        );
+        println!("WebSocket session ended: {}", message);
+        let frame = ServerFrame::Error {
+            message: message.clone(),
+        };
+        send_frame(socket, &frame).await?;
+        let _ = socket.send(Message::Close(None)).await;
+        return Err(axum::Error::new(message));
+    }
+    send_frame(socket, &ServerFrame::Result(response)).await
+}
+
+async fn fail_session(socket: &mut WebSocket, reason: String) {
+    println!("WebSocket session failed: {}", reason);
+    let message = format!("Failed to create session workspace: {}", reason);
+    let _ = send_frame(socket, &ServerFrame::Error { message }).await;
+}
+
+async fn send_frame(socket: &mut WebSocket, frame: &ServerFrame) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(frame).unwrap_or_default();
+    socket.send(Message::Text(text)).await
+}
@@ -0,0 +1,221 @@
+use futures::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+// Coalesces identical concurrent jobs: the first caller for a key runs the job and
+// everyone who asks for the same key while it is still running shares its result.
+//
+// Only safe for jobs whose result doesn't depend on who asked, which is why callers
+// have to opt in per request.
+pub struct SingleFlight<T: Clone> {
+    in_flight: Arc<Mutex<HashMap<String, Flight<T>>>>,
+    // Tells flights for the same key apart, so a caller never removes a later one
+    next_id: AtomicU64,
+}
+
+// A running job and how many callers are waiting for it. The job only lives as long as
+// someone is waiting: when the last caller goes away the entry is dropped, and with it
+// the job, which cancels whatever it is running and gives back its job slot.
+struct Flight<T: Clone> {
+    id: u64,
+    shared: Shared<BoxFuture<'static, T>>,
+    waiters: usize,
+}
+
+impl<T: Clone + Send + Sync + 'static> SingleFlight<T> {
+    pub fn new() -> Self {
+        SingleFlight {
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    pub async fn run<F>(&self, key: String, job: F) -> T
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        let (shared, _waiter) = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            let flight = match in_flight.get_mut(&key) {
+                Some(existing) => {
+                    println!("Joining in-flight job {}", &key[..12]);
+                    existing
+                }
+                None => {
+                    // The job removes its own entry when it finishes, so a finished
+                    // result is never handed to a later request
+                    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                    let registry = self.in_flight.clone();
+                    let entry_key = key.clone();
+                    let shared = async move {
+                        let result = job.await;
+                        remove_flight(&registry, &entry_key, id);
+                        result
+                    }
+                    .boxed()
+                    .shared();
+                    in_flight.entry(key.clone()).or_insert(Flight {
+                        id,
+                        shared,
+                        waiters: 0,
+                    })
+                }
+            };
+            flight.waiters += 1;
+            let waiter = Waiter {
+                registry: self.in_flight.clone(),
+                key,
+                id: flight.id,
+            };
+            (flight.shared.clone(), waiter)
+        };
+
+        shared.await
+    }
+}
+
+// A caller waiting for a flight; leaving, whether with the result or because the
+// request was dropped, counts it out
+struct Waiter<T: Clone> {
+    registry: Arc<Mutex<HashMap<String, Flight<T>>>>,
+    key: String,
+    id: u64,
+}
+
+impl<T: Clone> Drop for Waiter<T> {
+    fn drop(&mut self) {
+        // Dropped after the lock is released, as dropping the job cancels it
+        let _abandoned;
+        let mut in_flight = self.registry.lock().unwrap();
+        if let Some(flight) = in_flight.get_mut(&self.key) {
+            if flight.id == self.id {
+                flight.waiters -= 1;
+                if flight.waiters == 0 {
+                    _abandoned = in_flight.remove(&self.key);
+                }
+            }
+        }
+    }
+}
+
+fn remove_flight<T: Clone>(registry: &Mutex<HashMap<String, Flight<T>>>, key: &str, id: u64) {
+    let mut in_flight = registry.lock().unwrap();
+    if in_flight.get(key).is_some_and(|flight| flight.id == id) {
+        in_flight.remove(key);
+    }
+}
+
+// Key identifying a job by everything that affects its result. Each part is length
+// prefixed so that e.g. ("ab", "c") and ("a", "bc") can't collide.
+pub fn job_key(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part.as_bytes());
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::{oneshot, Semaphore};
+
+    // A job that holds a permit, standing in for a job slot, until told to finish
+    fn job(
+        slots: &Arc<Semaphore>,
+        finish: oneshot::Receiver<()>,
+    ) -> impl Future<Output = u32> + Send + 'static {
+        let slots = slots.clone();
+        async move {
+            let _slot = slots.acquire_owned().await.unwrap();
+            let _ = finish.await;
+            7
+        }
+    }
+
+    fn key() -> String {
+        job_key(&["fn main() {}"])
+    }
+
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    #[tokio::test]
+    async fn job_is_dropped_when_every_waiter_leaves() {
+        let flights = Arc::new(SingleFlight::<u32>::new());
+        let slots = Arc::new(Semaphore::new(1));
+        let (_finish, finished) = oneshot::channel();
+        let job = job(&slots, finished);
+
+        let first = tokio::spawn({
+            let flights = flights.clone();
+            async move { flights.run(key(), job).await }
+        });
+        settle().await;
+        let second = tokio::spawn({
+            let flights = flights.clone();
+            async move { flights.run(key(), async { 0 }).await }
+        });
+        settle().await;
+        assert_eq!(slots.available_permits(), 0);
+
+        first.abort();
+        second.abort();
+        settle().await;
+        assert_eq!(slots.available_permits(), 1);
+        assert!(flights.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn job_outlives_a_waiter_that_leaves() {
+        let flights = Arc::new(SingleFlight::<u32>::new());
+        let slots = Arc::new(Semaphore::new(1));
+        let (finish, finished) = oneshot::channel();
+        let job = job(&slots, finished);
+
+        let first = tokio::spawn({
+            let flights = flights.clone();
+            async move { flights.run(key(), job).await }
+        });
+        settle().await;
+        let second = tokio::spawn({
+            let flights = flights.clone();
+            async move { flights.run(key(), async { 0 }).await }
+        });
+        settle().await;
+
+        first.abort();
+        settle().await;
+        assert_eq!(slots.available_permits(), 0);
+
+        finish.send(()).unwrap();
+        assert_eq!(second.await.unwrap(), 7);
+        assert_eq!(slots.available_permits(), 1);
+        assert!(flights.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn finished_jobs_are_not_shared() {
+        let flights = SingleFlight::<u32>::new();
+        assert_eq!(flights.run(key(), async { 1 }).await, 1);
+        assert_eq!(flights.run(key(), async { 2 }).await, 2);
+        assert!(flights.in_flight.lock().unwrap().is_empty());
+    }
+}
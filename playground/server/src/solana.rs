@@ -0,0 +1,173 @@
+use crate::AppState;
+use axum::{extract::State, http::StatusCode, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+
+// How long to wait for an airdrop to be confirmed, and how often to check on it
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(30);
+const CONFIRM_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// JSON-RPC client for the playground's validator
+pub struct Validator {
+    client: reqwest::Client,
+    url: String,
+    // Largest airdrop a single request may ask for
+    pub max_airdrop_lamports: u64,
+}
+
+impl Validator {
+    pub fn new(url: String, max_airdrop_lamports: u64) -> Self {
+        Validator {
+            client: reqwest::Client::new(),
+            url,
+            max_airdrop_lamports,
+        }
+    }
+
+    // Call an RPC method and return its `result`, turning RPC errors into messages
+    async fn call(&self, method: &str, params: Value) -> Result<Value, String> {
+        let body = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+        let mut response: Value = self
+            .client
+            .post(&self.url)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| format!("Validator request failed: {}", err))?
+            .json()
+            .await
+            .map_err(|err| format!("Invalid validator response: {}", err))?;
+
+        if let Some(error) = response.get("error") {
+            let message = error["message"].as_str().unwrap_or("unknown error");
+            return Err(format!("{} failed: {}", method, message));
+        }
+        Ok(response["result"].take())
+    }
+
+    // Airdrop `lamports` to `pubkey` and wait for it to be confirmed. Returns the
+    // airdrop's signature and the account's balance afterwards.
+    pub async fn airdrop(&self, pubkey: &str, lamports: u64) -> Result<(String, u64), String> {
+        let signature = self
+            .call(
+                "requestAirdrop",
+                json!([pubkey, lamports, { "commitment": "confirmed" }]),
+            )
+            .await?;
+        let signature = signature
+            .as_str()
+            .ok_or_else(|| "requestAirdrop returned no signature".to_string())?
+            .to_string();
+
+        self.confirm(&signature).await?;
+
+        let balance = self
+            .call("getBalance", json!([pubkey, { "commitment": "confirmed" }]))
+            .await?;
+        let balance = balance["value"]
+            .as_u64()
+            .ok_or_else(|| "getBalance returned no balance".to_string())?;
+        Ok((signature, balance))
+    }
+
+    // Poll the signature until it is confirmed, fails, or CONFIRM_TIMEOUT passes
+    async fn confirm(&self, signature: &str) -> Result<(), String> {
+        let started = Instant::now();
+        loop {
+            let result = self
+                .call("getSignatureStatuses", json!([[signature]]))
+                .await?;
+            let status = &result["value"][0];
+            if !status.is_null() {
+                if !status["err"].is_null() {
+                    return Err(format!("Airdrop transaction failed: {}", status["err"]));
+                }
+                if matches!(
+                    status["confirmationStatus"].as_str(),
+                    Some("confirmed" | "finalized")
+                ) {
+                    return Ok(());
+                }
+            }
+
+            if started.elapsed() >= CONFIRM_TIMEOUT {
+                return Err(format!(
+                    "Airdrop {} was not confirmed within {} seconds",
+                    signature,
+                    CONFIRM_TIMEOUT.as_secs()
+                ));
+            }
+            tokio::time::sleep(CONFIRM_POLL_INTERVAL).await;
+        }
+    }
+}
+
+// Request model for the airdrop endpoint
+#[derive(Deserialize)]
+pub struct AirdropRequest {
+    pubkey: String,
+    lamports: u64,
+}
+
+#[derive(Serialize)]
+pub struct AirdropResponse {
+    signature: String,
+    lamports: u64,
+    // Balance of `pubkey` after the airdrop was confirmed
+    balance: u64,
+}
+
+/// Airdrop SOL to an account on the playground's validator
+///
+/// Requests the airdrop, waits for it to be confirmed and returns the account's new
+/// balance, so snippets don't each have to reimplement airdrop-and-confirm.
+pub async fn airdrop_handler(
+    State(app_state): State<AppState>,
+    Json(request): Json<AirdropRequest>,
+) -> Result<Json<AirdropResponse>, (StatusCode, String)> {
+    println!("Received request to /solana/airdrop");
+    let validator = &app_state.validator;
+
+    validate_pubkey(&request.pubkey).map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    if request.lamports == 0 || request.lamports > validator.max_airdrop_lamports {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "lamports must be between 1 and {}",
+                validator.max_airdrop_lamports
+            ),
+        ));
+    }
+
+    // Anything that goes wrong past validation is the validator's fault, not the client's
+    let (signature, balance) = validator
+        .airdrop(&request.pubkey, request.lamports)
+        .await
+        .map_err(|err| (StatusCode::BAD_GATEWAY, err))?;
+
+    println!(
+        "Airdropped {} lamports to {} ({})",
+        request.lamports, request.pubkey, signature
+    );
+    Ok(Json(AirdropResponse {
+        signature,
+        lamports: request.lamports,
+        balance,
+    }))
+}
+
+// A public key is 32 bytes, written in base58
+fn validate_pubkey(pubkey: &str) -> Result<(), String> {
+    let bytes = bs58::decode(pubkey)
+        .into_vec()
+        .map_err(|err| format!("Invalid pubkey: {}", err))?;
+    if bytes.len() != 32 {
+        return Err(format!(
+            "Invalid pubkey: expected 32 bytes, got {}",
+            bytes.len()
+        ));
+    }
+    Ok(())
+}
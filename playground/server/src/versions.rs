@@ -0,0 +1,87 @@
+use serde::Serialize;
+use std::{collections::BTreeMap, fs, path::Path, process::Command};
+
+// Versions of the tools and template dependencies this server runs with, gathered
+// once at startup so bug reports can say exactly what the playground used
+#[derive(Serialize, Clone)]
+pub struct ToolVersions {
+    pub rustc: Option<String>,
+    pub cargo: Option<String>,
+    pub solana: Option<String>,
+    pub anchor: Option<String>,
+    pub node: Option<String>,
+    pub pnpm: Option<String>,
+    // Dependencies pinned in the Rust template's Cargo.toml (crate -> version)
+    pub template_dependencies: BTreeMap<String, String>,
+}
+
+impl ToolVersions {
+    pub fn detect(template_rs: &str) -> Self {
+        ToolVersions {
+            rustc: tool_version("rustc"),
+            cargo: tool_version("cargo"),
+            solana: tool_version("solana"),
+            anchor: tool_version("anchor"),
+            node: tool_version("node"),
+            pnpm: tool_version("pnpm"),
+            template_dependencies: template_dependencies(template_rs),
+        }
+    }
+}
+
+// First line of `<tool> --version`, or None when the tool isn't installed or fails
+fn tool_version(tool: &str) -> Option<String> {
+    let output = Command::new(tool).arg("--version").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+}
+
+// Read the `[dependencies]` table of the template's Cargo.toml. Both `name = "1.0"` and
+// `name = { version = "1.0", ... }` forms are understood; path/git dependencies without
+// a version are skipped.
+fn template_dependencies(template_rs: &str) -> BTreeMap<String, String> {
+    let manifest_path = Path::new(template_rs).join("Cargo.toml");
+    let manifest = match fs::read_to_string(&manifest_path)
+        .map_err(|err| err.to_string())
+        .and_then(|manifest| {
+            manifest
+                .parse::<toml::Table>()
+                .map_err(|err| err.to_string())
+        }) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            println!(
+                "WARNING: Could not read template dependencies from {}: {}",
+                manifest_path.display(),
+                err
+            );
+            return BTreeMap::new();
+        }
+    };
+
+    let Some(dependencies) = manifest
+        .get("dependencies")
+        .and_then(|deps| deps.as_table())
+    else {
+        return BTreeMap::new();
+    };
+
+    dependencies
+        .iter()
+        .filter_map(|(name, spec)| {
+            let version = match spec {
+                toml::Value::String(version) => Some(version.as_str()),
+                toml::Value::Table(table) => table.get("version").and_then(|v| v.as_str()),
+                _ => None,
+            }?;
+            Some((name.clone(), version.to_string()))
+        })
+        .collect()
+}
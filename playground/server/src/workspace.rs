@@ -0,0 +1,265 @@
+use crate::sandbox;
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, Mutex},
+};
+use tempfile::TempDir;
+
+// Name prefixes of the private workspace directories created in the temp root
+pub const RUST_PREFIX: &str = "playground-rs-";
+pub const TYPESCRIPT_PREFIX: &str = "playground-ts-";
+
+// Directories of the private workspaces that are currently in use
+#[derive(Clone, Default)]
+pub struct ActiveWorkspaces(Arc<Mutex<HashSet<PathBuf>>>);
+
+impl ActiveWorkspaces {
+    pub fn contains(&self, dir: &Path) -> bool {
+        self.0.lock().unwrap().contains(dir)
+    }
+}
+
+// A directory that code is written into and built/run from.
+//
+// The compile endpoints use the template directories in place when only one job may run
+// at a time. Otherwise, and for WebSocket sessions, they get a private temporary copy
+// instead, which is deleted when the workspace is dropped.
+pub struct Workspace {
+    pub dir: PathBuf,
+    // Extra environment variables for every command run in this workspace
+    pub envs: Vec<(String, String)>,
+    // Files outside `dir` that belong to this workspace and are removed on drop
+    owned_files: Vec<PathBuf>,
+    // Where this workspace is registered as in use, until it is dropped
+    active: Option<ActiveWorkspaces>,
+    _temp_dir: Option<TempDir>,
+}
+
+impl Workspace {
+    // Use an existing directory as is
+    pub fn in_place(dir: impl Into<PathBuf>) -> Self {
+        Workspace {
+            dir: dir.into(),
+            envs: Vec::new(),
+            owned_files: Vec::new(),
+            active: None,
+            _temp_dir: None,
+        }
+    }
+
+    // A private copy of the Rust template that shares the template's target directory,
+    // so dependencies that are already built don't have to be compiled again.
+    //
+    // Every workspace builds its binary under its own name; otherwise workspaces would
+    // overwrite each other's `target/debug/<package>` executable in the shared target.
+    pub fn rust_copy(template_rs: &str, active: &ActiveWorkspaces) -> io::Result<Self> {
+        Self::rust_copy_with_target(template_rs, Path::new(template_rs).join("target"), active)
+    }
+
+    // A private copy of the Rust template that builds into `target_dir` instead of the
+    // template's own target directory
+    pub fn rust_copy_with_target(
+        template_rs: &str,
+        target_dir: PathBuf,
+        active: &ActiveWorkspaces,
+    ) -> io::Result<Self> {
+        let temp_dir = tempfile::Builder::new().prefix(RUST_PREFIX).tempdir()?;
+        let dir = temp_dir.path().to_path_buf();
+        let bin_name = format!("session-{}", uuid::Uuid::new_v4().simple());
+
+        // The template declares no [[bin]] targets of its own, so this explicit one
+        // replaces the auto-discovered `src/main.rs` binary
+        let manifest = fs::read_to_string(Path::new(template_rs).join("Cargo.toml"))?;
+        fs::write(
+            dir.join("Cargo.toml"),
+            format!(
+                "{}\n[[bin]]\nname = \"{}\"\npath = \"src/main.rs\"\n",
+                manifest, bin_name
+            ),
+        )?;
+        copy_if_exists(
+            &Path::new(template_rs).join("Cargo.lock"),
+            &dir.join("Cargo.lock"),
+        )?;
+        fs::create_dir_all(dir.join("src"))?;
+
+        // The binary is built under the dev or release profile's directory, depending on
+        // the request
+        let owned_files = ["debug", "release"]
+            .iter()
+            .flat_map(|profile| {
+                [
+                    target_dir.join(profile).join(&bin_name),
+                    target_dir.join(profile).join(format!("{}.d", bin_name)),
+                ]
+            })
+            .collect();
+
+        Ok(Workspace {
+            dir,
+            envs: vec![(
+                "CARGO_TARGET_DIR".to_string(),
+                target_dir.to_string_lossy().to_string(),
+            )],
+            owned_files,
+            active: None,
+            _temp_dir: Some(temp_dir),
+        }
+        .register(active))
+    }
+
+    // A private copy of the TypeScript template whose node_modules links back to the
+    // template's installed packages
+    pub fn typescript_copy(
+        template_ts: impl AsRef<Path>,
+        active: &ActiveWorkspaces,
+    ) -> io::Result<Self> {
+        let template_ts = template_ts.as_ref();
+        let temp_dir = tempfile::Builder::new()
+            .prefix(TYPESCRIPT_PREFIX)
+            .tempdir()?;
+        let dir = temp_dir.path().to_path_buf();
+
+        for entry in fs::read_dir(template_ts)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                fs::copy(entry.path(), dir.join(entry.file_name()))?;
+            }
+        }
+        fs::create_dir_all(dir.join("src"))?;
+        std::os::unix::fs::symlink(template_ts.join("node_modules"), dir.join("node_modules"))?;
+
+        Ok(Workspace {
+            dir,
+            envs: Vec::new(),
+            owned_files: Vec::new(),
+            active: None,
+            _temp_dir: Some(temp_dir),
+        }
+        .register(active))
+    }
+
+    // A private copy of the SBF template that builds into the template's target
+    // directory, so solana-program is only compiled for the SBF target once
+    pub fn sbf_copy(template_sbf: &str, active: &ActiveWorkspaces) -> io::Result<Self> {
+        let template_sbf = Path::new(template_sbf);
+        let temp_dir = tempfile::Builder::new().prefix(RUST_PREFIX).tempdir()?;
+        let dir = temp_dir.path().to_path_buf();
+
+        for entry in fs::read_dir(template_sbf)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                fs::copy(entry.path(), dir.join(entry.file_name()))?;
+            }
+        }
+        fs::create_dir_all(dir.join("src"))?;
+        // It must exist to be made writable in the sandbox
+        let target_dir = template_sbf.join("target");
+        fs::create_dir_all(&target_dir)?;
+
+        Ok(Workspace {
+            dir,
+            envs: vec![(
+                "CARGO_TARGET_DIR".to_string(),
+                target_dir.to_string_lossy().to_string(),
+            )],
+            owned_files: Vec::new(),
+            active: None,
+            _temp_dir: Some(temp_dir),
+        }
+        .register(active))
+    }
+
+    // Whether this is a private copy, rather than a directory other jobs use as well
+    pub fn is_private(&self) -> bool {
+        self._temp_dir.is_some()
+    }
+
+    // Remove `file` along with this workspace, e.g. an extra entrypoint written into
+    // a template that is used in place
+    pub fn owning(mut self, file: PathBuf) -> Self {
+        self.owned_files.push(file);
+        self
+    }
+
+    // Run `command` in the sandbox with access to this workspace: its directory and
+    // target directory are writable, and a linked node_modules is readable
+    pub fn confine<'a>(&self, command: &'a mut Command) -> &'a mut Command {
+        self.confine_with(command, &[], &[])
+    }
+
+    // Like `confine`, with access to the given directories as well, e.g. a toolchain
+    // that is installed in the home directory
+    pub fn confine_with<'a>(
+        &self,
+        command: &'a mut Command,
+        extra_writable: &[&Path],
+        extra_readable: &[&Path],
+    ) -> &'a mut Command {
+        let mut writable = vec![self.dir.as_path()];
+        writable.extend(
+            self.envs
+                .iter()
+                .filter(|(name, _)| name == "CARGO_TARGET_DIR")
+                .map(|(_, dir)| Path::new(dir)),
+        );
+        writable.extend_from_slice(extra_writable);
+        let node_modules = self.dir.join("node_modules");
+        let mut readable = extra_readable.to_vec();
+        if node_modules.is_symlink() {
+            readable.push(&node_modules);
+        }
+        sandbox::confine(command, &writable, &readable)
+    }
+
+    // Run a user's program in the sandbox. Unlike the build it can't write the target
+    // directory or CARGO_HOME, which later builds go on to use; the workspace
+    // directory stays writable when `writable_dir` is set.
+    pub fn confine_run<'a>(&self, command: &'a mut Command, writable_dir: bool) -> &'a mut Command {
+        let mut readable: Vec<&Path> = self
+            .envs
+            .iter()
+            .filter(|(name, _)| name == "CARGO_TARGET_DIR")
+            .map(|(_, dir)| Path::new(dir))
+            .collect();
+        let node_modules = self.dir.join("node_modules");
+        if node_modules.is_symlink() {
+            readable.push(&node_modules);
+        }
+        let mut writable = Vec::new();
+        if writable_dir {
+            writable.push(self.dir.as_path());
+        } else {
+            readable.push(&self.dir);
+        }
+        sandbox::confine_run(command, &writable, &readable)
+    }
+
+    fn register(mut self, active: &ActiveWorkspaces) -> Self {
+        active.0.lock().unwrap().insert(self.dir.clone());
+        self.active = Some(active.clone());
+        self
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        for file in &self.owned_files {
+            let _ = fs::remove_file(file);
+        }
+        if let Some(active) = &self.active {
+            active.0.lock().unwrap().remove(&self.dir);
+        }
+    }
+}
+
+fn copy_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::copy(from, to) {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}